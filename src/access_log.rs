@@ -0,0 +1,309 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+
+use crate::logging::{self, iso_time, time_parts, LogSink, Span};
+use crate::request::HTTPContext;
+use crate::request_id::X_REQUEST_ID;
+use crate::response::HttpResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    Common,
+    /// Common plus the quoted `Referer` and `User-Agent`.
+    Combined,
+    /// One JSON object per line, with the latency in milliseconds as well.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!("unknown access log format: {}", s)),
+        };
+    }
+}
+
+/// What gets logged about one request, taken before the context goes to the
+/// handler and completed once the response is known.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    pub peer_addr: Option<SocketAddr>,
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub time: SystemTime,
+    pub status: u16,
+    /// The bytes written to the connection for the response, as counted
+    /// while writing: the whole HTTP/1 message, or the HTTP/2 DATA payload.
+    pub bytes: u64,
+    /// The id `RequestIdMiddleware` echoed on the response.
+    pub request_id: Option<String>,
+    started: Instant,
+}
+
+impl AccessLogEntry {
+    /// `started` is when the request began to arrive, which latency is measured from.
+    pub fn new(context: &HTTPContext, started: Instant) -> Self {
+        return AccessLogEntry {
+            peer_addr: context.peer_addr,
+            method: context.method.to_string(),
+            target: context.target(),
+            version: context.http_version.clone(),
+            referer: context.get_header("referer").map(|referer| referer.to_string()),
+            user_agent: context.get_header("user-agent").map(|user_agent| user_agent.to_string()),
+            time: SystemTime::now(),
+            status: 0,
+            bytes: 0,
+            request_id: None,
+            started,
+        };
+    }
+
+    pub fn set_response(&mut self, response: &HttpResponse) {
+        self.status = response.status().as_u16();
+        self.request_id = response.get_header(&X_REQUEST_ID).map(|id| id.to_string());
+    }
+
+    pub fn set_bytes_sent(&mut self, bytes: u64) {
+        self.bytes = bytes;
+    }
+}
+
+/// Writes a line per request to `sink`, except for excluded paths such as health checks.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Box<dyn LogSink + Send + Sync>,
+    excluded: Vec<String>,
+}
+
+impl AccessLog {
+    pub fn new<T>(format: AccessLogFormat, sink: T) -> Self
+    where
+        T: LogSink + 'static + Send + Sync,
+    {
+        return AccessLog {
+            format,
+            sink: Box::new(sink),
+            excluded: vec![],
+        };
+    }
+
+    /// Skips requests for `path`, or every path under it when it ends in `*`.
+    pub fn exclude(&mut self, path: &str) -> &mut Self {
+        self.excluded.push(path.to_string());
+        return self;
+    }
+
+    pub fn is_excluded(&self, path: &str) -> bool {
+        return self.excluded.iter().any(|excluded| match excluded.strip_suffix("*") {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == excluded,
+        });
+    }
+
+    pub fn format(&self, entry: &AccessLogEntry) -> String {
+        let host = match entry.peer_addr {
+            Some(peer_addr) => peer_addr.ip().to_string(),
+            None => "-".to_string(),
+        };
+        let bytes = match entry.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let request_line = format!("{} {} {}", entry.method, entry.target, entry.version);
+        let common = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            host,
+            common_log_time(entry.time),
+            escape_quoted(&request_line),
+            entry.status,
+            bytes,
+        );
+        return match self.format {
+            AccessLogFormat::Common => common,
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                common,
+                escape_quoted(entry.referer.as_deref().unwrap_or("-")),
+                escape_quoted(entry.user_agent.as_deref().unwrap_or("-")),
+            ),
+            AccessLogFormat::Json => format!(
+                "{{\"time\":{},\"remote_addr\":{},\"method\":{},\"path\":{},\"version\":{},\"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\"referer\":{},\"user_agent\":{},\"request_id\":{}}}",
+                json_string(Some(&iso_time(entry.time))),
+                json_string(entry.peer_addr.map(|peer_addr| peer_addr.ip().to_string()).as_deref()),
+                json_string(Some(&entry.method)),
+                json_string(Some(&entry.target)),
+                json_string(Some(&entry.version)),
+                entry.status,
+                entry.bytes,
+                entry.started.elapsed().as_secs_f64() * 1000.0,
+                json_string(entry.referer.as_deref()),
+                json_string(entry.user_agent.as_deref()),
+                json_string(entry.request_id.as_deref()),
+            ),
+        };
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry) {
+        if let Err(e) = self.sink.write_line(&self.format(entry)) {
+            logging::error(&Span::default(), &format!("access log error: {}", e));
+        }
+    }
+}
+
+fn common_log_time(time: SystemTime) -> String {
+    let (day, month, year, clock) = time_parts(time);
+    return format!("{}/{}/{}:{} +0000", day, month, year, clock);
+}
+
+/// Keeps client supplied text from breaking out of its quotes or the line.
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+pub fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "null".to_string(),
+    };
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::logging::StdoutSink;
+
+    fn entry() -> AccessLogEntry {
+        return AccessLogEntry {
+            peer_addr: Some("127.0.0.1:50312".parse().unwrap()),
+            method: "GET".to_string(),
+            target: "/apache_pb.gif?a=1".to_string(),
+            version: "HTTP/1.1".to_string(),
+            referer: Some("http://www.example.com/start.html".to_string()),
+            user_agent: Some("Mozilla/4.08".to_string()),
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            status: 200,
+            bytes: 2326,
+            request_id: Some("req-1".to_string()),
+            started: Instant::now(),
+        };
+    }
+
+    fn log(format: AccessLogFormat) -> AccessLog {
+        return AccessLog::new(format, StdoutSink);
+    }
+
+    #[test]
+    fn formats_common_and_combined_lines() {
+        let common = "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.1\" 200 2326";
+        assert_eq!(log(AccessLogFormat::Common).format(&entry()), common);
+        assert_eq!(
+            log(AccessLogFormat::Combined).format(&entry()),
+            format!("{} \"http://www.example.com/start.html\" \"Mozilla/4.08\"", common)
+        );
+
+        let mut entry = entry();
+        entry.peer_addr = None;
+        entry.bytes = 0;
+        entry.referer = None;
+        entry.user_agent = None;
+        assert_eq!(
+            log(AccessLogFormat::Combined).format(&entry),
+            "- - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.1\" 200 - \"-\" \"-\""
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = log(AccessLogFormat::Json).format(&entry());
+        let (start, rest) = line.split_once(",\"latency_ms\":").unwrap();
+        assert_eq!(
+            start,
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"remote_addr\":\"127.0.0.1\",\"method\":\"GET\",\"path\":\"/apache_pb.gif?a=1\",\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2326"
+        );
+        let (latency, end) = rest.split_once(",").unwrap();
+        assert!(latency.parse::<f64>().unwrap() >= 0.0);
+        assert_eq!(
+            end,
+            "\"referer\":\"http://www.example.com/start.html\",\"user_agent\":\"Mozilla/4.08\",\"request_id\":\"req-1\"}"
+        );
+
+        let mut entry = entry();
+        entry.bytes = 0;
+        entry.peer_addr = None;
+        entry.request_id = None;
+        let line = log(AccessLogFormat::Json).format(&entry);
+        assert!(line.contains("\"remote_addr\":null,"));
+        assert!(line.contains("\"bytes\":0,"));
+        assert!(line.ends_with("\"request_id\":null}"));
+    }
+
+    #[test]
+    fn escapes_client_supplied_text() {
+        let mut entry = entry();
+        entry.target = "/\"quoted\"\\path".to_string();
+        entry.user_agent = Some("evil\"\n127.0.0.1 - - [forged]\x1b[31m\t".to_string());
+        let line = log(AccessLogFormat::Combined).format(&entry);
+        assert!(line.contains("\"GET /\\\"quoted\\\"\\\\path HTTP/1.1\""));
+        assert!(line.ends_with("\"evil\\\"\\x0a127.0.0.1 - - [forged]\\x1b[31m\\x09\""));
+        assert!(!line.contains('\n'));
+
+        assert_eq!(json_string(None), "null");
+        assert_eq!(json_string(Some("a\"b\\c\nd\re\tf\u{1}")), "\"a\\\"b\\\\c\\nd\\re\\tf\\u0001\"");
+        assert_eq!(json_string(Some("zoë ✓")), "\"zoë ✓\"");
+    }
+
+    #[test]
+    fn excludes_exact_paths_and_prefixes() {
+        let mut access_log = log(AccessLogFormat::Common);
+        access_log.exclude("/health").exclude("/static/*");
+        assert!(access_log.is_excluded("/health"));
+        assert!(!access_log.is_excluded("/health/deep"));
+        assert!(!access_log.is_excluded("/healthz"));
+        assert!(access_log.is_excluded("/static/"));
+        assert!(access_log.is_excluded("/static/app.js"));
+        assert!(!access_log.is_excluded("/static"));
+        assert!(!access_log.is_excluded("/"));
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert_eq!("COMBINED".parse(), Ok(AccessLogFormat::Combined));
+        assert_eq!("json".parse(), Ok(AccessLogFormat::Json));
+        assert!("apache".parse::<AccessLogFormat>().is_err());
+    }
+}
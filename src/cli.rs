@@ -0,0 +1,221 @@
+use thiserror::Error;
+
+use crate::config::{Config, StaticMount};
+
+pub const USAGE: &str = "\
+Usage: http-server-starter-rust [OPTIONS]
+
+Serves the demo routes (GET /, GET /app, POST /echo) and optionally a directory of files.
+
+Options:
+  -b, --bind <ADDRESS>       Address to listen on, may be repeated [default: 127.0.0.1:4221]
+  -d, --directory <DIR>      Serve the files in DIR under /files/
+  -w, --workers <COUNT>      Worker threads in sync mode [default: 8]
+  -m, --mode <MODE>          sync (thread pool) or async (tokio) [default: async]
+  -c, --config <FILE>        Read settings from a TOML file [env: HTTP_SERVER_CONFIG]
+  -l, --log-format <FORMAT>  Write an access log to stdout: common, combined or json
+  -h, --help                 Print this help
+
+Options override HTTP_SERVER_* environment variables, which override the config file.
+";
+
+/// Route the `--directory` files are served under.
+pub const DIRECTORY_ROUTE: &str = "/files/*";
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("{0} needs a value")]
+    MissingValue(String),
+    #[error("invalid value {value:?} for {flag}: expected {expected}")]
+    InvalidValue { flag: String, value: String, expected: String },
+    #[error("unknown argument {0}")]
+    UnknownArgument(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// `Server::run` on a thread pool.
+    Sync,
+    /// `Server::run_async` on tokio, needed for TLS.
+    Async,
+}
+
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub bind: Vec<String>,
+    pub directory: Option<String>,
+    pub workers: Option<usize>,
+    pub mode: Mode,
+    pub config: Option<String>,
+    pub log_format: Option<String>,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        return Args {
+            bind: vec![],
+            directory: None,
+            workers: None,
+            mode: Mode::Async,
+            config: None,
+            log_format: None,
+            help: false,
+        };
+    }
+}
+
+impl Args {
+    /// Parses arguments without the program name. Values are given as
+    /// `--flag value` or `--flag=value`.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, CliError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once("=") {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            if flag == "-h" || flag == "--help" {
+                parsed.help = true;
+                continue;
+            }
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => match flag.as_str() {
+                    "-b" | "--bind" | "-d" | "--directory" | "-w" | "--workers" | "-m" | "--mode" | "-c"
+                    | "--config" | "-l" | "--log-format" => return Err(CliError::MissingValue(flag)),
+                    _ => return Err(CliError::UnknownArgument(arg)),
+                },
+            };
+            match flag.as_str() {
+                "-b" | "--bind" => parsed.bind.push(value),
+                "-d" | "--directory" => parsed.directory = Some(value),
+                "-w" | "--workers" => match value.parse() {
+                    Ok(workers) => parsed.workers = Some(workers),
+                    Err(_) => return Err(invalid_value(&flag, &value, "a whole number")),
+                },
+                "-m" | "--mode" => {
+                    parsed.mode = match value.as_str() {
+                        "sync" => Mode::Sync,
+                        "async" => Mode::Async,
+                        _ => return Err(invalid_value(&flag, &value, "sync or async")),
+                    }
+                }
+                "-c" | "--config" => parsed.config = Some(value),
+                "-l" | "--log-format" => parsed.log_format = Some(value),
+                _ => return Err(CliError::UnknownArgument(arg)),
+            }
+        }
+        return Ok(parsed);
+    }
+
+    /// Overrides the settings given on the command line. The result still
+    /// needs `Config::validate`.
+    pub fn apply(&self, config: &mut Config) {
+        if !self.bind.is_empty() {
+            config.bind = self.bind.clone();
+        }
+        if let Some(directory) = &self.directory {
+            config.static_mounts.retain(|mount| mount.path != DIRECTORY_ROUTE);
+            config.static_mounts.push(StaticMount {
+                path: DIRECTORY_ROUTE.to_string(),
+                directory: directory.clone(),
+            });
+        }
+        if self.workers.is_some() {
+            config.workers = self.workers;
+        }
+        if self.log_format.is_some() {
+            config.log_format = self.log_format.clone();
+        }
+    }
+}
+
+fn invalid_value(flag: &str, value: &str, expected: &str) -> CliError {
+    return CliError::InvalidValue {
+        flag: flag.to_string(),
+        value: value.to_string(),
+        expected: expected.to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, CliError> {
+        return Args::parse(args.iter().map(|arg| arg.to_string()));
+    }
+
+    #[test]
+    fn parses_flags_and_values() {
+        let args = parse(&[
+            "-b",
+            "127.0.0.1:8080",
+            "--bind=[::1]:8080",
+            "--directory",
+            "public",
+            "-w",
+            "4",
+            "--mode=sync",
+            "-c",
+            "server.toml",
+            "--log-format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(args.bind, vec!["127.0.0.1:8080", "[::1]:8080"]);
+        assert_eq!(args.directory.as_deref(), Some("public"));
+        assert_eq!(args.workers, Some(4));
+        assert_eq!(args.mode, Mode::Sync);
+        assert_eq!(args.config.as_deref(), Some("server.toml"));
+        assert_eq!(args.log_format.as_deref(), Some("json"));
+        assert!(!args.help);
+
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.mode, Mode::Async);
+        assert!(args.bind.is_empty());
+        assert!(parse(&["--help"]).unwrap().help);
+        assert!(parse(&["-w", "2", "-h"]).unwrap().help);
+    }
+
+    #[test]
+    fn refuses_bad_arguments() {
+        assert!(matches!(parse(&["--bind"]), Err(CliError::MissingValue(flag)) if flag == "--bind"));
+        assert!(matches!(parse(&["-w"]), Err(CliError::MissingValue(flag)) if flag == "-w"));
+        assert!(matches!(
+            parse(&["--workers", "many"]),
+            Err(CliError::InvalidValue { flag, value, .. }) if flag == "--workers" && value == "many"
+        ));
+        assert!(matches!(
+            parse(&["-m=sync"]),
+            Err(CliError::UnknownArgument(arg)) if arg == "-m=sync"
+        ));
+        assert!(matches!(
+            parse(&["--mode", "threads"]),
+            Err(CliError::InvalidValue { expected, .. }) if expected == "sync or async"
+        ));
+        assert!(matches!(parse(&["--port", "80"]), Err(CliError::UnknownArgument(arg)) if arg == "--port"));
+        assert!(matches!(parse(&["serve"]), Err(CliError::UnknownArgument(arg)) if arg == "serve"));
+    }
+
+    #[test]
+    fn overrides_the_config() {
+        let mut config = Config::default();
+        config.static_mounts.push(StaticMount {
+            path: DIRECTORY_ROUTE.to_string(),
+            directory: "old".to_string(),
+        });
+        parse(&["-b", "0.0.0.0:80", "-d", "new", "-w", "3"]).unwrap().apply(&mut config);
+        assert_eq!(config.bind, vec!["0.0.0.0:80"]);
+        assert_eq!(config.workers, Some(3));
+        assert_eq!(config.static_mounts.len(), 1);
+        assert_eq!(config.static_mounts[0].directory, "new");
+
+        let mut config = Config::default();
+        parse(&[]).unwrap().apply(&mut config);
+        assert_eq!(config.bind, Config::default().bind);
+        assert_eq!(config.workers, None);
+    }
+}
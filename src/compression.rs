@@ -0,0 +1,480 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use http::{header, StatusCode};
+
+use crate::logging::{self, Span};
+use crate::request::parse_quality_values;
+use crate::response::{HttpResponse, ResponseBody};
+use crate::route::{HTTPRequest, PostRequestMiddlewareResult, ResponseMiddleware};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        };
+    }
+}
+
+/// Encodings in the order we prefer them when the client rates them equally.
+const SUPPORTED_ENCODINGS: [ContentEncoding; 3] = [
+    ContentEncoding::Brotli,
+    ContentEncoding::Gzip,
+    ContentEncoding::Deflate,
+];
+
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+
+enum EncoderKind {
+    Gzip(GzEncoder<Vec<u8>>),
+    // HTTP's "deflate" is the zlib format, not a raw deflate stream.
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+/// Compresses a body piece by piece, so streamed bodies never sit in memory whole.
+pub struct Encoder {
+    inner: EncoderKind,
+}
+
+impl Encoder {
+    pub fn new(encoding: ContentEncoding) -> Self {
+        let inner = match encoding {
+            ContentEncoding::Gzip => EncoderKind::Gzip(GzEncoder::new(vec![], Compression::default())),
+            ContentEncoding::Deflate => {
+                EncoderKind::Deflate(ZlibEncoder::new(vec![], Compression::default()))
+            }
+            ContentEncoding::Brotli => EncoderKind::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        };
+        return Encoder { inner };
+    }
+
+    /// Feeds `chunk` in and returns whatever compressed output is ready so far.
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match &mut self.inner {
+            EncoderKind::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            EncoderKind::Deflate(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            EncoderKind::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        return Ok(std::mem::take(output));
+    }
+
+    /// Flushes the remaining output and the stream trailer.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        return match self.inner {
+            EncoderKind::Gzip(encoder) => encoder.finish(),
+            EncoderKind::Deflate(encoder) => encoder.finish(),
+            EncoderKind::Brotli(encoder) => Ok(encoder.into_inner()),
+        };
+    }
+}
+
+pub fn compress(encoding: ContentEncoding, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding);
+    let mut compressed = encoder.compress(bytes)?;
+    compressed.extend(encoder.finish()?);
+    return Ok(compressed);
+}
+
+fn encoding_name_matches(name: &str, encoding: ContentEncoding) -> bool {
+    if encoding == ContentEncoding::Gzip && name.eq_ignore_ascii_case("x-gzip") {
+        return true;
+    }
+    return name.eq_ignore_ascii_case(encoding.as_str());
+}
+
+/// Picks the encoding the client rates highest, or `None` for identity.
+pub fn negotiate_encoding(accept_encoding: &str) -> Option<ContentEncoding> {
+    let ratings = parse_quality_values(accept_encoding);
+    let wildcard = ratings
+        .iter()
+        .find(|(name, _)| name == "*")
+        .map(|(_, quality)| *quality);
+
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for encoding in SUPPORTED_ENCODINGS.iter() {
+        let quality = ratings
+            .iter()
+            .find(|(name, _)| encoding_name_matches(name, *encoding))
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        let is_better = match best {
+            Some((_, best_quality)) => quality > best_quality,
+            None => quality > 0.0,
+        };
+        if is_better {
+            best = Some((*encoding, quality));
+        }
+    }
+    return best.map(|(encoding, _)| encoding);
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type.to_lowercase();
+    let media_type = content_type.split(";").next().unwrap_or("").trim();
+    return media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type,
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        );
+}
+
+/// A strong validator has to differ between encodings of the same file;
+/// weak ones may be shared.
+fn encoded_etag(etag: &str, encoding: ContentEncoding) -> String {
+    let etag = etag.trim();
+    if etag.starts_with("W/") || !etag.ends_with('"') {
+        return etag.to_string();
+    }
+    return format!("{}-{}\"", &etag[..etag.len() - 1], encoding.as_str());
+}
+
+/// Adds `Accept-Encoding` to whatever `Vary` the handler set, folding
+/// several `Vary` headers into one.
+fn vary_on_accept_encoding(response: &mut HttpResponse) {
+    let mut names: Vec<String> = vec![];
+    for value in response.get_header_values(&header::VARY) {
+        for name in value.split(",").map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            if name == "*" {
+                return;
+            }
+            if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    if !names.iter().any(|name| name.eq_ignore_ascii_case("accept-encoding")) {
+        names.push("Accept-Encoding".to_string());
+    }
+    response.remove_header(&header::VARY).set_header(header::VARY, &names.join(", "));
+}
+
+/// Compresses response bodies for clients that accept it.
+///
+/// Buffered bodies are compressed up front and keep their `Content-Length`;
+/// streamed bodies are compressed while written and sent chunked.
+pub struct CompressionMiddleware {
+    threshold: u64,
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> Self {
+        return CompressionMiddleware::new();
+    }
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> Self {
+        return CompressionMiddleware { threshold: 1024 };
+    }
+
+    /// Bodies smaller than this many bytes are sent as they are.
+    pub fn threshold(&mut self, threshold: u64) -> &mut Self {
+        self.threshold = threshold;
+        return self;
+    }
+
+    fn should_compress(&self, response: &HttpResponse) -> bool {
+        if !response.status().is_success()
+            || response.status() == StatusCode::NO_CONTENT
+            || response.status() == StatusCode::PARTIAL_CONTENT
+            || response.get_header(&header::CONTENT_ENCODING).is_some()
+            || response.is_streaming()
+        {
+            return false;
+        }
+        return match response.get_header(&header::CONTENT_TYPE) {
+            Some(content_type) => is_compressible(content_type),
+            None => false,
+        };
+    }
+
+    fn encode(&self, request: &HTTPRequest, response: &mut HttpResponse) -> io::Result<()> {
+        let encoding = match request.context.get_header("accept-encoding") {
+            Some(accept_encoding) => match negotiate_encoding(accept_encoding) {
+                Some(encoding) => encoding,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let body = match response.take_body() {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+        if body.len() < self.threshold {
+            response.set_response_body(body);
+            return Ok(());
+        }
+
+        let body = match body {
+            ResponseBody::Bytes(bytes) => {
+                let compressed = match compress(encoding, &bytes) {
+                    Ok(compressed) => compressed,
+                    Err(e) => {
+                        response.set_body_bytes(bytes);
+                        return Err(e);
+                    }
+                };
+                if compressed.len() >= bytes.len() {
+                    response.set_body_bytes(bytes);
+                    return Ok(());
+                }
+                ResponseBody::Bytes(compressed)
+            }
+            // Ranges address the file as stored, so leave those to the range handling.
+            body if request.context.get_header("range").is_some() => {
+                response.set_response_body(body);
+                return Ok(());
+            }
+            body => ResponseBody::Encoded {
+                body: Box::new(body),
+                encoding,
+            },
+        };
+        if let Some(etag) = response.get_header(&header::ETAG) {
+            let etag = encoded_etag(etag, encoding);
+            response.remove_header(&header::ETAG).set_header(header::ETAG, &etag);
+        }
+        response
+            .set_header(header::CONTENT_ENCODING, encoding.as_str())
+            .set_response_body(body);
+        return Ok(());
+    }
+}
+
+impl ResponseMiddleware for CompressionMiddleware {
+    fn handle(&self, request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+        let mut response = response;
+        if !self.should_compress(&response) {
+            return PostRequestMiddlewareResult::Next(response);
+        }
+        vary_on_accept_encoding(&mut response);
+        if let Err(e) = self.encode(request, &mut response) {
+            logging::error(&Span::request(&request.context), &format!("compression failed: {}", e));
+        }
+        return PostRequestMiddlewareResult::Next(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::request::{context_from_parts, HTTPHeader, RequestLimits};
+
+    fn decompress(encoding: ContentEncoding, bytes: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        match encoding {
+            ContentEncoding::Gzip => flate2::read::GzDecoder::new(bytes).read_to_end(&mut decoded),
+            ContentEncoding::Deflate => flate2::read::ZlibDecoder::new(bytes).read_to_end(&mut decoded),
+            ContentEncoding::Brotli => brotli::Decompressor::new(bytes, BROTLI_BUFFER).read_to_end(&mut decoded),
+        }
+        .unwrap();
+        return decoded;
+    }
+
+    fn request(accept_encoding: Option<&str>) -> HTTPRequest {
+        let headers = match accept_encoding {
+            Some(value) => vec![HTTPHeader("Accept-Encoding".to_string(), value.to_string())],
+            None => vec![],
+        };
+        let context = context_from_parts("GET", "HTTP/1.1", "/", headers, vec![], &RequestLimits::default());
+        return HTTPRequest::new(context.unwrap());
+    }
+
+    fn text_response(content_type: &str, body: &str) -> HttpResponse {
+        let mut response = HttpResponse::new();
+        response.set_header(header::CONTENT_TYPE, content_type).set_body(body);
+        return response;
+    }
+
+    fn run(middleware: &CompressionMiddleware, accept_encoding: Option<&str>, response: HttpResponse) -> HttpResponse {
+        return match ResponseMiddleware::handle(middleware, &request(accept_encoding), response) {
+            PostRequestMiddlewareResult::Next(response) => response,
+            PostRequestMiddlewareResult::End(_) => panic!("compression never ends the chain"),
+        };
+    }
+
+    fn body_bytes(response: &HttpResponse) -> &[u8] {
+        return match response.body() {
+            Some(ResponseBody::Bytes(bytes)) => bytes,
+            _ => panic!("expected a buffered body"),
+        };
+    }
+
+    #[test]
+    fn negotiates_encodings_by_quality() {
+        let cases = [
+            ("", None),
+            ("gzip", Some(ContentEncoding::Gzip)),
+            ("x-gzip", Some(ContentEncoding::Gzip)),
+            ("deflate, gzip", Some(ContentEncoding::Gzip)),
+            ("gzip;q=0.5, deflate;q=0.8", Some(ContentEncoding::Deflate)),
+            ("br;q=0.1, GZIP", Some(ContentEncoding::Gzip)),
+            ("gzip;q=0", None),
+            ("*", Some(ContentEncoding::Brotli)),
+            ("br;q=0, *", Some(ContentEncoding::Gzip)),
+            ("*;q=0.5, deflate", Some(ContentEncoding::Deflate)),
+            ("*;q=0", None),
+            ("identity", None),
+            ("identity;q=0", None),
+            ("gzip, identity;q=0", Some(ContentEncoding::Gzip)),
+            ("compress, zstd", None),
+        ];
+        for (accept_encoding, expected) in cases {
+            assert_eq!(negotiate_encoding(accept_encoding), expected, "{:?}", accept_encoding);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        let text = "All work and no play makes Jack a dull boy. ".repeat(100);
+        for encoding in SUPPORTED_ENCODINGS {
+            let compressed = compress(encoding, text.as_bytes()).unwrap();
+            assert!(compressed.len() < text.len());
+            assert_eq!(decompress(encoding, &compressed), text.as_bytes());
+
+            let mut encoder = Encoder::new(encoding);
+            let mut streamed = vec![];
+            for chunk in text.as_bytes().chunks(100) {
+                streamed.extend(encoder.compress(chunk).unwrap());
+            }
+            streamed.extend(encoder.finish().unwrap());
+            assert_eq!(decompress(encoding, &streamed), text.as_bytes(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn compresses_large_compressible_bodies() {
+        let middleware = CompressionMiddleware::new();
+        let text = "{\"key\": \"value\"} ".repeat(100);
+        let response = run(&middleware, Some("gzip"), text_response("application/json", &text));
+        assert_eq!(response.get_header(&header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(response.get_header(&header::VARY), Some("Accept-Encoding"));
+        assert_eq!(decompress(ContentEncoding::Gzip, body_bytes(&response)), text.as_bytes());
+
+        // Without an Accept-Encoding the body stays as it is, but caches still need the Vary.
+        let response = run(&middleware, None, text_response("application/json", &text));
+        assert_eq!(response.get_header(&header::CONTENT_ENCODING), None);
+        assert_eq!(response.get_header(&header::VARY), Some("Accept-Encoding"));
+        assert_eq!(body_bytes(&response), text.as_bytes());
+    }
+
+    #[test]
+    fn leaves_small_and_incompressible_bodies_alone() {
+        let mut middleware = CompressionMiddleware::new();
+        middleware.threshold(64);
+        let at_threshold = "a".repeat(64);
+        let below_threshold = "a".repeat(63);
+
+        let response = run(&middleware, Some("gzip"), text_response("text/plain", &below_threshold));
+        assert_eq!(response.get_header(&header::CONTENT_ENCODING), None);
+        assert_eq!(body_bytes(&response), below_threshold.as_bytes());
+        let response = run(&middleware, Some("gzip"), text_response("text/plain", &at_threshold));
+        assert_eq!(response.get_header(&header::CONTENT_ENCODING), Some("gzip"));
+
+        for content_type in ["image/png", "application/octet-stream", "video/mp4"] {
+            let response = run(&middleware, Some("gzip"), text_response(content_type, &at_threshold));
+            assert_eq!(response.get_header(&header::CONTENT_ENCODING), None, "{}", content_type);
+            assert_eq!(response.get_header(&header::VARY), None, "{}", content_type);
+        }
+        for content_type in ["TEXT/HTML; charset=utf-8", "application/ld+json", "image/svg+xml", "application/javascript"] {
+            assert!(is_compressible(content_type), "{}", content_type);
+        }
+
+        let mut response = text_response("text/plain", &at_threshold);
+        response.set_status(StatusCode::NOT_FOUND);
+        assert_eq!(run(&middleware, Some("gzip"), response).get_header(&header::CONTENT_ENCODING), None);
+        let mut response = text_response("text/plain", &at_threshold);
+        response.set_header(header::CONTENT_ENCODING, "br");
+        assert_eq!(run(&middleware, Some("gzip"), response).get_header(&header::CONTENT_ENCODING), Some("br"));
+    }
+
+    #[test]
+    fn merges_vary_headers() {
+        let middleware = CompressionMiddleware::new();
+        let cases: [(&[&str], &str); 4] = [
+            (&["Accept"], "Accept, Accept-Encoding"),
+            (&["Accept", "Cookie, Accept-Language"], "Accept, Cookie, Accept-Language, Accept-Encoding"),
+            (&["accept-encoding, Accept"], "accept-encoding, Accept"),
+            (&["*"], "*"),
+        ];
+        for (vary, expected) in cases {
+            let mut response = text_response("text/plain", "short");
+            for value in vary.iter() {
+                response.set_header(header::VARY, value);
+            }
+            let response = run(&middleware, Some("gzip"), response);
+            assert_eq!(response.get_header_values(&header::VARY), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn marks_strong_etags_with_the_encoding() {
+        assert_eq!(encoded_etag("\"abc\"", ContentEncoding::Gzip), "\"abc-gzip\"");
+        assert_eq!(encoded_etag(" \"abc\" ", ContentEncoding::Brotli), "\"abc-br\"");
+        assert_eq!(encoded_etag("W/\"abc\"", ContentEncoding::Gzip), "W/\"abc\"");
+
+        let middleware = CompressionMiddleware::new();
+        let mut response = text_response("text/css", &"body { color: red; }\n".repeat(100));
+        response.set_header(header::ETAG, "\"v1\"");
+        let response = run(&middleware, Some("deflate"), response);
+        assert_eq!(response.get_header_values(&header::ETAG), vec!["\"v1-deflate\""]);
+    }
+
+    #[tokio::test]
+    async fn compresses_streamed_bodies_while_written() {
+        let path = std::env::temp_dir().join(format!("compression-{}.css", std::process::id()));
+        let text = "body { color: red; }\n".repeat(500);
+        std::fs::write(&path, &text).unwrap();
+
+        let middleware = CompressionMiddleware::new();
+        let mut response = HttpResponse::new();
+        response
+            .set_header(header::CONTENT_TYPE, "text/css")
+            .set_file(std::fs::File::open(&path).unwrap(), text.len() as u64);
+        let mut response = run(&middleware, Some("br"), response);
+        assert_eq!(response.get_header(&header::CONTENT_ENCODING), Some("br"));
+        let body = response.take_body().unwrap();
+        assert!(matches!(body, ResponseBody::Encoded { encoding: ContentEncoding::Brotli, .. }));
+
+        let mut written = vec![];
+        body.write_async(&mut written).await.unwrap();
+        assert_eq!(decompress(ContentEncoding::Brotli, &written), text.as_bytes());
+        std::fs::remove_file(path).unwrap();
+    }
+}
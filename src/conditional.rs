@@ -0,0 +1,319 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{header, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::request::HTTPMethod;
+use crate::response::{HttpResponse, ResponseBody};
+use crate::route::{HTTPRequest, PostRequestMiddlewareResult, ResponseMiddleware};
+
+/// The validators of the representation a request targets, known without
+/// running its handler. See `Router::validators`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Adds a weak `ETag` to successful responses with a buffered body.
+///
+/// Opt-in with `router.after_all(ETagMiddleware)`. Responses that already carry
+/// an `ETag`, such as static files, are left alone.
+pub struct ETagMiddleware;
+
+impl ResponseMiddleware for ETagMiddleware {
+    fn handle(&self, _request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+        let mut response = response;
+        if !response.status().is_success() || response.get_header(&header::ETAG).is_some() {
+            return PostRequestMiddlewareResult::Next(response);
+        }
+        if let Some(ResponseBody::Bytes(bytes)) = response.body() {
+            let etag = weak_etag(bytes);
+            response.set_header(header::ETAG, &etag);
+        }
+        return PostRequestMiddlewareResult::Next(response);
+    }
+}
+
+pub fn weak_etag(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    return format!("W/\"{}\"", URL_SAFE_NO_PAD.encode(&digest[..16]));
+}
+
+/// An `ETag` derived from file metadata, so the file does not have to be read.
+///
+/// It is strong so that `If-Range` can be used to resume downloads.
+pub fn file_etag(length: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    return format!("\"{:x}-{:x}\"", length, modified.as_nanos());
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    return etag.trim().trim_start_matches("W/");
+}
+
+pub fn is_weak(etag: &str) -> bool {
+    return etag.trim().starts_with("W/");
+}
+
+/// `If-None-Match` compares weakly: `W/"a"` matches `"a"`.
+fn matches_weak(header: &str, etag: &str) -> bool {
+    return header.split(",").any(|candidate| {
+        candidate.trim() == "*" || opaque_tag(candidate) == opaque_tag(etag)
+    });
+}
+
+/// `If-Match` compares strongly: weak tags never match.
+fn matches_strong(header: &str, etag: Option<&str>) -> bool {
+    return header.split(",").any(|candidate| {
+        let candidate = candidate.trim();
+        if candidate == "*" {
+            return etag.is_some();
+        }
+        return match etag {
+            Some(etag) => !is_weak(candidate) && !is_weak(etag) && candidate == etag.trim(),
+            None => false,
+        };
+    });
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    return time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+}
+
+/// HTTP dates only have second precision, so compare whole seconds.
+fn modified_after(last_modified: Option<&str>, date: &str) -> Option<bool> {
+    let last_modified = httpdate::parse_http_date(last_modified?).ok()?;
+    let date = httpdate::parse_http_date(date).ok()?;
+    return Some(unix_seconds(last_modified) > unix_seconds(date));
+}
+
+/// Evaluates the request's preconditions against the validators of the
+/// selected representation, in the order RFC 9110 section 13.2.2 prescribes.
+///
+/// Returns the status to answer with instead of the representation, if any.
+/// The router applies it before the handler for paths with registered
+/// validators and to GET responses; other handlers that change state should
+/// call it before doing so.
+pub fn evaluate_preconditions(
+    request: &HTTPRequest,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+    is_safe: bool,
+) -> Option<StatusCode> {
+    let context = &request.context;
+    if let Some(if_match) = context.get_header("if-match") {
+        if !matches_strong(if_match, etag) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(if_unmodified_since) = context.get_header("if-unmodified-since") {
+        if modified_after(last_modified, if_unmodified_since) == Some(true) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = context.get_header("if-none-match") {
+        let matched = match etag {
+            Some(etag) => matches_weak(if_none_match, etag),
+            None => if_none_match.trim() == "*",
+        };
+        if matched {
+            if is_safe {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(if_modified_since) = context.get_header("if-modified-since") {
+        if is_safe && modified_after(last_modified, if_modified_since) == Some(false) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+    return None;
+}
+
+/// The 304 or 412 to answer with instead of running the handler, if any.
+///
+/// `None` for `validators` means the target has no current representation,
+/// in which case only `If-Match` can fail.
+pub fn check_preconditions(request: &HTTPRequest, validators: Option<&Validators>) -> Option<HttpResponse> {
+    let mut response = HttpResponse::new();
+    let validators = match validators {
+        Some(validators) => validators,
+        None => {
+            request.context.get_header("if-match")?;
+            response.set_status(StatusCode::PRECONDITION_FAILED);
+            return Some(response);
+        }
+    };
+    let status_code = evaluate_preconditions(
+        request,
+        validators.etag.as_deref(),
+        validators.last_modified.as_deref(),
+        request.context.method == HTTPMethod::GET,
+    )?;
+    response.set_status(status_code);
+    if status_code == StatusCode::NOT_MODIFIED {
+        if let Some(etag) = &validators.etag {
+            response.set_header(header::ETAG, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            response.set_header(header::LAST_MODIFIED, last_modified);
+        }
+    }
+    return Some(response);
+}
+
+/// Replaces a successful response with a 304 or 412 when the request's
+/// preconditions call for it.
+pub fn apply_preconditions(request: &HTTPRequest, response: HttpResponse) -> HttpResponse {
+    let mut response = response;
+    if !response.status().is_success() {
+        return response;
+    }
+    let status_code = evaluate_preconditions(
+        request,
+        response.get_header(&header::ETAG),
+        response.get_header(&header::LAST_MODIFIED),
+        true,
+    );
+    return match status_code {
+        Some(StatusCode::NOT_MODIFIED) => {
+            // A 304 keeps the validators and caching headers but drops the content.
+            response
+                .set_status(StatusCode::NOT_MODIFIED)
+                .remove_header(&header::CONTENT_TYPE);
+            response.take_body();
+            response
+        }
+        Some(status_code) => {
+            let mut failed = HttpResponse::new();
+            failed.set_status(status_code);
+            failed
+        }
+        None => response,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::request::{context_from_parts, HTTPHeader, RequestLimits};
+    use crate::route::{PreRequestMiddleware, PreRequestMiddlewareResult, Router};
+
+    const ETAG: &str = "\"v1\"";
+    const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> HTTPRequest {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| HTTPHeader(name.to_string(), value.to_string()))
+            .collect();
+        let context = context_from_parts(method, "HTTP/1.1", "/doc", headers, vec![], &RequestLimits::default());
+        return HTTPRequest::new(context.unwrap());
+    }
+
+    fn evaluate(method: &str, headers: &[(&str, &str)]) -> Option<StatusCode> {
+        let is_safe = method == "GET";
+        return evaluate_preconditions(&request(method, headers), Some(ETAG), Some(LAST_MODIFIED), is_safe);
+    }
+
+    #[test]
+    fn evaluates_preconditions_in_rfc_order() {
+        assert_eq!(evaluate("GET", &[]), None);
+        assert_eq!(evaluate("GET", &[("If-None-Match", "\"v0\", W/\"v1\"")]), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(evaluate("GET", &[("If-None-Match", "\"v0\"")]), None);
+        assert_eq!(evaluate("PUT", &[("If-None-Match", "*")]), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(evaluate("PUT", &[("If-Match", ETAG)]), None);
+        assert_eq!(evaluate("PUT", &[("If-Match", "W/\"v1\"")]), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(
+            evaluate("PUT", &[("If-Unmodified-Since", "Tue, 20 Oct 2015 07:28:00 GMT")]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(evaluate("GET", &[("If-Modified-Since", LAST_MODIFIED)]), Some(StatusCode::NOT_MODIFIED));
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert_eq!(
+            evaluate("GET", &[("If-None-Match", "\"v0\""), ("If-Modified-Since", LAST_MODIFIED)]),
+            None
+        );
+        // If-Match is evaluated before If-None-Match.
+        assert_eq!(
+            evaluate("GET", &[("If-Match", "\"v0\""), ("If-None-Match", ETAG)]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn checks_missing_representations_with_if_match_only() {
+        let failed = check_preconditions(&request("PUT", &[("If-Match", "*")]), None);
+        assert_eq!(failed.map(|response| response.status()), Some(StatusCode::PRECONDITION_FAILED));
+        assert!(check_preconditions(&request("PUT", &[("If-None-Match", "*")]), None).is_none());
+    }
+
+    fn router(calls: Arc<AtomicUsize>) -> Router {
+        let mut router = Router::new();
+        let put_calls = calls.clone();
+        router
+            .validators("/doc", |_| {
+                Some(Validators {
+                    etag: Some(ETAG.to_string()),
+                    last_modified: Some(LAST_MODIFIED.to_string()),
+                })
+            })
+            .route(HTTPMethod::GET, "/doc", move |_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let mut response = HttpResponse::new();
+                response.set_header(header::ETAG, ETAG).set_body("document");
+                response
+            })
+            .route(HTTPMethod::PUT, "/doc", move |_| {
+                put_calls.fetch_add(1, Ordering::SeqCst);
+                HttpResponse::new()
+            });
+        return router;
+    }
+
+    #[test]
+    fn answers_preconditions_before_running_the_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = router(calls.clone());
+
+        let response = router.handle(request("GET", &[("If-None-Match", ETAG)]).context);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.get_header(&header::ETAG), Some(ETAG));
+        assert_eq!(response.get_header(&header::LAST_MODIFIED), Some(LAST_MODIFIED));
+        assert!(response.body().is_none());
+
+        let response = router.handle(request("PUT", &[("If-Match", "\"v0\"")]).context);
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let response = router.handle(request("PUT", &[("If-Match", ETAG)]).context);
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router.handle(request("GET", &[("If-None-Match", "\"v0\"")]).context);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    struct Unauthorized;
+
+    impl PreRequestMiddleware for Unauthorized {
+        fn handle(&self, _request: &mut HTTPRequest) -> PreRequestMiddlewareResult {
+            let mut response = HttpResponse::new();
+            response.set_status(StatusCode::UNAUTHORIZED);
+            return PreRequestMiddlewareResult::End(response);
+        }
+    }
+
+    #[test]
+    fn runs_route_middleware_before_preconditions() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut router = router(calls);
+        router.before("/doc", Unauthorized);
+        let response = router.handle(request("GET", &[("If-None-Match", ETAG)]).context);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,524 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::access_log::{AccessLog, AccessLogFormat};
+use crate::error::Error;
+use crate::logging::StdoutSink;
+use crate::request::RequestLimits;
+use crate::route::Router;
+use crate::server::{KeepAlive, Server, ServerBuilder, Timeouts};
+use crate::static_files::StaticFiles;
+use crate::tls::TlsConfig;
+
+const ENV_PREFIX: &str = "HTTP_SERVER_";
+
+/// Names the config file to load when none is passed to `Config::load`.
+pub const CONFIG_ENV: &str = "HTTP_SERVER_CONFIG";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {path}: {source}")]
+    Read { path: String, source: std::io::Error },
+    #[error("invalid config file {path}: {message}")]
+    Parse { path: String, message: String },
+    #[error("invalid {name}={value:?}: expected {expected}")]
+    Env { name: String, value: String, expected: String },
+    #[error("unknown setting {0}")]
+    UnknownEnv(String),
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM certificate chain, leaf first.
+    pub cert: String,
+    /// PEM private key.
+    pub key: String,
+}
+
+/// Overrides for `RequestLimits`, in bytes or headers.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_request_line: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub max_header_count: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+    pub max_decoded_body_bytes: Option<usize>,
+}
+
+/// Overrides for `Timeouts`, in seconds.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSettings {
+    pub idle_secs: Option<u64>,
+    pub header_read_secs: Option<u64>,
+    pub body_read_secs: Option<u64>,
+    pub handler_secs: Option<u64>,
+    pub write_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAliveSettings {
+    pub enabled: Option<bool>,
+    pub max_requests: Option<usize>,
+}
+
+/// A directory served below a wildcard route.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticMount {
+    /// Such as `/static/*`.
+    pub path: String,
+    pub directory: String,
+}
+
+/// Server settings from a TOML file, overridden by `HTTP_SERVER_*` variables.
+///
+/// ```toml
+/// bind = ["127.0.0.1:4221", "[::1]:4221"]
+/// workers = 8
+/// handler_threads = 16
+/// log_format = "combined"
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+///
+/// [limits]
+/// max_body_bytes = 1048576
+///
+/// [timeouts]
+/// idle_secs = 5
+///
+/// [keep_alive]
+/// max_requests = 100
+///
+/// [[static]]
+/// path = "/static/*"
+/// directory = "public"
+/// ```
+///
+/// Every setting has a variable named after its place in the file, such as
+/// `HTTP_SERVER_WORKERS` or `HTTP_SERVER_TIMEOUTS_IDLE_SECS`. `HTTP_SERVER_BIND`
+/// takes a comma separated list and `HTTP_SERVER_STATIC` a comma separated
+/// list of `path=directory` pairs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub workers: Option<usize>,
+    /// Threads running handlers for every connection, and how many requests may queue for them.
+    pub handler_threads: Option<usize>,
+    pub max_queued_handlers: Option<usize>,
+    /// Access log format written to stdout: `common`, `combined` or `json`.
+    pub log_format: Option<String>,
+    pub tls: Option<TlsSettings>,
+    pub limits: LimitSettings,
+    pub timeouts: TimeoutSettings,
+    pub keep_alive: KeepAliveSettings,
+    #[serde(rename = "static")]
+    pub static_mounts: Vec<StaticMount>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Config {
+            bind: vec!["127.0.0.1:4221".to_string()],
+            workers: None,
+            handler_threads: None,
+            max_queued_handlers: None,
+            log_format: None,
+            tls: None,
+            limits: LimitSettings::default(),
+            timeouts: TimeoutSettings::default(),
+            keep_alive: KeepAliveSettings::default(),
+            static_mounts: vec![],
+        };
+    }
+}
+
+impl Config {
+    /// Reads `path`, or the file named by `HTTP_SERVER_CONFIG` when there is
+    /// none, and applies the environment on top. The result is not validated
+    /// yet, so command line options can still override it; call `validate` last.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_string()),
+            None => env::var(CONFIG_ENV).ok(),
+        };
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        return Ok(config);
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(source) => {
+                return Err(ConfigError::Read {
+                    path: path.to_string(),
+                    source,
+                })
+            }
+        };
+        return toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_string(),
+            message: e.to_string().trim().to_string(),
+        });
+    }
+
+    /// Applies every `HTTP_SERVER_*` variable, refusing ones it does not know
+    /// so a misspelt name does not go unnoticed.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        let mut vars: Vec<(String, String)> = env::vars().filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect();
+        vars.sort();
+        for (name, value) in vars.iter() {
+            self.apply_env_var(name, value)?;
+        }
+        return Ok(());
+    }
+
+    fn apply_env_var(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let setting = &name[ENV_PREFIX.len()..];
+        match setting {
+            "CONFIG" => (),
+            "BIND" => self.bind = split_list(value),
+            "WORKERS" => self.workers = Some(parse_env(name, value, "a whole number")?),
+            "HANDLER_THREADS" => self.handler_threads = Some(parse_env(name, value, "a whole number")?),
+            "MAX_QUEUED_HANDLERS" => self.max_queued_handlers = Some(parse_env(name, value, "a whole number")?),
+            "LOG_FORMAT" => self.log_format = Some(value.to_string()),
+            "TLS_CERT" | "TLS_KEY" => {
+                let tls = self.tls.get_or_insert(TlsSettings {
+                    cert: String::new(),
+                    key: String::new(),
+                });
+                match setting {
+                    "TLS_CERT" => tls.cert = value.to_string(),
+                    _ => tls.key = value.to_string(),
+                }
+            }
+            "STATIC" => {
+                let mut mounts = vec![];
+                for mount in split_list(value) {
+                    match mount.split_once("=") {
+                        Some((path, directory)) => mounts.push(StaticMount {
+                            path: path.trim().to_string(),
+                            directory: directory.trim().to_string(),
+                        }),
+                        None => return Err(env_error(name, value, "path=directory pairs")),
+                    }
+                }
+                self.static_mounts = mounts;
+            }
+            "LIMITS_MAX_REQUEST_LINE" => self.limits.max_request_line = Some(parse_env(name, value, "a size in bytes")?),
+            "LIMITS_MAX_HEADER_BYTES" => self.limits.max_header_bytes = Some(parse_env(name, value, "a size in bytes")?),
+            "LIMITS_MAX_HEADER_COUNT" => self.limits.max_header_count = Some(parse_env(name, value, "a whole number")?),
+            "LIMITS_MAX_BODY_BYTES" => self.limits.max_body_bytes = Some(parse_env(name, value, "a size in bytes")?),
+            "LIMITS_MAX_DECODED_BODY_BYTES" => {
+                self.limits.max_decoded_body_bytes = Some(parse_env(name, value, "a size in bytes")?)
+            }
+            "TIMEOUTS_IDLE_SECS" => self.timeouts.idle_secs = Some(parse_env(name, value, "seconds")?),
+            "TIMEOUTS_HEADER_READ_SECS" => self.timeouts.header_read_secs = Some(parse_env(name, value, "seconds")?),
+            "TIMEOUTS_BODY_READ_SECS" => self.timeouts.body_read_secs = Some(parse_env(name, value, "seconds")?),
+            "TIMEOUTS_HANDLER_SECS" => self.timeouts.handler_secs = Some(parse_env(name, value, "seconds")?),
+            "TIMEOUTS_WRITE_SECS" => self.timeouts.write_secs = Some(parse_env(name, value, "seconds")?),
+            "KEEP_ALIVE_ENABLED" => self.keep_alive.enabled = Some(parse_env(name, value, "true or false")?),
+            "KEEP_ALIVE_MAX_REQUESTS" => {
+                self.keep_alive.max_requests = Some(parse_env(name, value, "a whole number")?)
+            }
+            _ => return Err(ConfigError::UnknownEnv(name.to_string())),
+        }
+        return Ok(());
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind.is_empty() {
+            return Err(invalid("bind must list at least one address"));
+        }
+        for address in self.bind.iter() {
+            let port = address.rsplit_once(":").map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                return Err(invalid(&format!(
+                    "bind address {:?} is not host:port, e.g. 127.0.0.1:4221 or [::1]:4221",
+                    address
+                )));
+            }
+        }
+        if self.workers == Some(0) {
+            return Err(invalid("workers must be at least 1"));
+        }
+        if self.handler_threads == Some(0) {
+            return Err(invalid("handler_threads must be at least 1"));
+        }
+        self.access_log_format()?;
+        if let Some(tls) = &self.tls {
+            if tls.cert.is_empty() || tls.key.is_empty() {
+                return Err(invalid("tls needs both cert and key"));
+            }
+            for file in [&tls.cert, &tls.key] {
+                if !Path::new(file).is_file() {
+                    return Err(invalid(&format!("tls file {} does not exist", file)));
+                }
+            }
+        }
+        let limits = [
+            ("limits.max_request_line", self.limits.max_request_line),
+            ("limits.max_header_bytes", self.limits.max_header_bytes),
+            ("limits.max_header_count", self.limits.max_header_count),
+            ("limits.max_body_bytes", self.limits.max_body_bytes),
+            ("limits.max_decoded_body_bytes", self.limits.max_decoded_body_bytes),
+            ("keep_alive.max_requests", self.keep_alive.max_requests),
+        ];
+        for (name, limit) in limits.iter() {
+            if *limit == Some(0) {
+                return Err(invalid(&format!("{} must be more than 0", name)));
+            }
+        }
+        let timeouts = [
+            ("timeouts.idle_secs", self.timeouts.idle_secs),
+            ("timeouts.header_read_secs", self.timeouts.header_read_secs),
+            ("timeouts.body_read_secs", self.timeouts.body_read_secs),
+            ("timeouts.handler_secs", self.timeouts.handler_secs),
+            ("timeouts.write_secs", self.timeouts.write_secs),
+        ];
+        for (name, timeout) in timeouts.iter() {
+            if *timeout == Some(0) {
+                return Err(invalid(&format!("{} must be more than 0", name)));
+            }
+        }
+        for mount in self.static_mounts.iter() {
+            if !mount.path.starts_with("/") || !mount.path.ends_with("/*") {
+                return Err(invalid(&format!(
+                    "static path {:?} must start with / and end in /*, e.g. /static/*",
+                    mount.path
+                )));
+            }
+            if !Path::new(&mount.directory).is_dir() {
+                return Err(invalid(&format!("static directory {} does not exist", mount.directory)));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn access_log_format(&self) -> Result<Option<AccessLogFormat>, ConfigError> {
+        return match &self.log_format {
+            Some(format) => match format.parse() {
+                Ok(format) => Ok(Some(format)),
+                Err(_) => Err(invalid(&format!(
+                    "log_format {:?} is not one of common, combined or json",
+                    format
+                ))),
+            },
+            None => Ok(None),
+        };
+    }
+
+    pub fn request_limits(&self) -> RequestLimits {
+        let mut limits = RequestLimits::default();
+        let settings = &self.limits;
+        limits.max_request_line = settings.max_request_line.unwrap_or(limits.max_request_line);
+        limits.max_header_bytes = settings.max_header_bytes.unwrap_or(limits.max_header_bytes);
+        limits.max_header_count = settings.max_header_count.unwrap_or(limits.max_header_count);
+        limits.max_body_bytes = settings.max_body_bytes.unwrap_or(limits.max_body_bytes);
+        limits.max_decoded_body_bytes = settings.max_decoded_body_bytes.unwrap_or(limits.max_decoded_body_bytes);
+        return limits;
+    }
+
+    pub fn server_timeouts(&self) -> Timeouts {
+        let mut timeouts = Timeouts::default();
+        let settings = &self.timeouts;
+        let seconds = |value: Option<u64>, default: Duration| value.map(Duration::from_secs).unwrap_or(default);
+        timeouts.idle = seconds(settings.idle_secs, timeouts.idle);
+        timeouts.header_read = seconds(settings.header_read_secs, timeouts.header_read);
+        timeouts.body_read = seconds(settings.body_read_secs, timeouts.body_read);
+        timeouts.handler = seconds(settings.handler_secs, timeouts.handler);
+        timeouts.write = seconds(settings.write_secs, timeouts.write);
+        return timeouts;
+    }
+
+    pub fn server_keep_alive(&self) -> KeepAlive {
+        let mut keep_alive = KeepAlive::default();
+        keep_alive.enabled = self.keep_alive.enabled.unwrap_or(keep_alive.enabled);
+        keep_alive.max_requests = self.keep_alive.max_requests.or(keep_alive.max_requests);
+        return keep_alive;
+    }
+
+    /// Binds a server as configured, serving `router` with the static
+    /// directories and access log added to it.
+    pub fn server(&self, router: Router) -> Result<Server, Error> {
+        let mut router = router;
+        for mount in self.static_mounts.iter() {
+            router.serve_static(&mount.path, StaticFiles::new(&mount.directory)?);
+        }
+        if let Some(format) = self.access_log_format()? {
+            router.access_log(AccessLog::new(format, StdoutSink));
+        }
+
+        let mut builder = ServerBuilder::new();
+        for address in self.bind.iter() {
+            builder.bind(address);
+        }
+        if let Some(workers) = self.workers {
+            builder.workers(workers);
+        }
+        if let Some(threads) = self.handler_threads {
+            builder.handler_threads(threads);
+        }
+        if let Some(max_queued) = self.max_queued_handlers {
+            builder.max_queued_handlers(max_queued);
+        }
+        builder
+            .limits(self.request_limits())
+            .timeouts(self.server_timeouts())
+            .keep_alive(self.server_keep_alive());
+        if let Some(tls) = &self.tls {
+            builder.tls(TlsConfig::new(&tls.cert, &tls.key));
+        }
+        let mut server = builder.build()?;
+        server.use_router(router);
+        return Ok(server);
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    return value
+        .split(",")
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect();
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str, expected: &str) -> Result<T, ConfigError> {
+    return value.trim().parse().map_err(|_| env_error(name, value, expected));
+}
+
+fn env_error(name: &str, value: &str, expected: &str) -> ConfigError {
+    return ConfigError::Env {
+        name: name.to_string(),
+        value: value.to_string(),
+        expected: expected.to_string(),
+    };
+}
+
+fn invalid(message: &str) -> ConfigError {
+    return ConfigError::Invalid(message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_env(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        for (name, value) in vars.iter() {
+            config.apply_env_var(name, value)?;
+        }
+        return Ok(config);
+    }
+
+    #[test]
+    fn applies_env_overrides() {
+        let config = with_env(&[
+            ("HTTP_SERVER_BIND", "127.0.0.1:8080, [::1]:8080"),
+            ("HTTP_SERVER_WORKERS", " 4 "),
+            ("HTTP_SERVER_LOG_FORMAT", "json"),
+            ("HTTP_SERVER_TLS_CERT", "cert.pem"),
+            ("HTTP_SERVER_TLS_KEY", "key.pem"),
+            ("HTTP_SERVER_STATIC", "/assets/*=public, /docs/* = docs"),
+            ("HTTP_SERVER_LIMITS_MAX_BODY_BYTES", "1024"),
+            ("HTTP_SERVER_TIMEOUTS_IDLE_SECS", "30"),
+            ("HTTP_SERVER_KEEP_ALIVE_ENABLED", "false"),
+        ])
+        .unwrap();
+        assert_eq!(config.bind, vec!["127.0.0.1:8080", "[::1]:8080"]);
+        assert_eq!(config.workers, Some(4));
+        assert_eq!(config.log_format.as_deref(), Some("json"));
+        let tls = config.tls.unwrap();
+        assert_eq!((tls.cert.as_str(), tls.key.as_str()), ("cert.pem", "key.pem"));
+        let mounts: Vec<(&str, &str)> = config
+            .static_mounts
+            .iter()
+            .map(|mount| (mount.path.as_str(), mount.directory.as_str()))
+            .collect();
+        assert_eq!(mounts, vec![("/assets/*", "public"), ("/docs/*", "docs")]);
+        assert_eq!(config.limits.max_body_bytes, Some(1024));
+        assert_eq!(config.timeouts.idle_secs, Some(30));
+        assert_eq!(config.keep_alive.enabled, Some(false));
+    }
+
+    #[test]
+    fn refuses_invalid_env_values() {
+        let cases = [
+            ("HTTP_SERVER_WORKERS", "many", "a whole number"),
+            ("HTTP_SERVER_LIMITS_MAX_BODY_BYTES", "-1", "a size in bytes"),
+            ("HTTP_SERVER_TIMEOUTS_IDLE_SECS", "1.5", "seconds"),
+            ("HTTP_SERVER_KEEP_ALIVE_ENABLED", "yes", "true or false"),
+            ("HTTP_SERVER_STATIC", "/assets/*", "path=directory pairs"),
+        ];
+        for (name, value, expected) in cases {
+            match with_env(&[(name, value)]) {
+                Err(ConfigError::Env {
+                    name: error_name,
+                    value: error_value,
+                    expected: error_expected,
+                }) => {
+                    assert_eq!((error_name.as_str(), error_value.as_str()), (name, value));
+                    assert_eq!(error_expected, expected);
+                }
+                other => panic!("{}={:?} gave {:?}", name, value, other),
+            }
+        }
+        assert!(matches!(
+            with_env(&[("HTTP_SERVER_WORKES", "4")]),
+            Err(ConfigError::UnknownEnv(name)) if name == "HTTP_SERVER_WORKES"
+        ));
+    }
+
+    #[test]
+    fn applies_the_process_environment() {
+        std::env::set_var("HTTP_SERVER_MAX_QUEUED_HANDLERS", "7");
+        let mut config = Config::default();
+        let result = config.apply_env();
+        std::env::remove_var("HTTP_SERVER_MAX_QUEUED_HANDLERS");
+        result.unwrap();
+        assert_eq!(config.max_queued_handlers, Some(7));
+    }
+
+    #[test]
+    fn leaves_validation_until_every_override_is_applied() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        fs::write(&path, "workers = 0\n").unwrap();
+        let config = Config::load(path.to_str());
+        fs::remove_file(&path).unwrap();
+
+        let mut config = config.unwrap();
+        assert!(config.validate().is_err());
+        config.workers = Some(4);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn validates_the_result() {
+        let config = with_env(&[("HTTP_SERVER_WORKERS", "0")]).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = with_env(&[("HTTP_SERVER_BIND", "localhost")]).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = with_env(&[("HTTP_SERVER_TLS_CERT", "cert.pem")]).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        assert!(Config::default().validate().is_ok());
+    }
+}
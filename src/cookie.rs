@@ -0,0 +1,303 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A cookie part that would change the meaning of the `Set-Cookie` header,
+/// such as a `;` starting a new attribute or a line break starting a new header.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CookieError {
+    #[error("invalid cookie name {0:?}")]
+    InvalidName(String),
+    #[error("invalid value for cookie {name}: {value:?}")]
+    InvalidValue { name: String, value: String },
+    #[error("invalid cookie {attribute} {value:?}")]
+    InvalidAttribute { attribute: &'static str, value: String },
+}
+
+/// RFC 6265 cookie names are tokens.
+pub fn is_valid_name(name: &str) -> bool {
+    return !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+}
+
+/// RFC 6265 cookie values are cookie-octets, optionally in double quotes:
+/// visible ASCII without space, `"`, `,`, `;` and `\`.
+pub fn is_valid_value(value: &str) -> bool {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    return value
+        .bytes()
+        .all(|byte| (0x21..=0x7e).contains(&byte) && !b"\",;\\".contains(&byte));
+}
+
+/// Attribute values such as `Path` may hold anything but controls and `;`.
+pub fn is_valid_attribute(value: &str) -> bool {
+    return !value.chars().any(|c| c.is_control() || c == ';');
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        return f.write_str(name);
+    }
+}
+
+/// A cookie to be sent to the client through a `Set-Cookie` header.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Fails when `name` is not a token or `value` is not made of cookie-octets.
+    pub fn new(name: &str, value: &str) -> Result<Self, CookieError> {
+        if !is_valid_name(name) {
+            return Err(CookieError::InvalidName(name.to_string()));
+        }
+        if !is_valid_value(value) {
+            return Err(CookieError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+        return Ok(Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        });
+    }
+
+    /// A cookie that tells the client to drop `name` straight away.
+    pub fn removal(name: &str) -> Result<Self, CookieError> {
+        let mut cookie = Cookie::new(name, "")?;
+        cookie
+            .max_age(Duration::ZERO)
+            .expires(SystemTime::UNIX_EPOCH);
+        return Ok(cookie);
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    pub fn value(&self) -> &str {
+        return &self.value;
+    }
+
+    pub fn path(&mut self, path: &str) -> Result<&mut Self, CookieError> {
+        if !is_valid_attribute(path) {
+            return Err(CookieError::InvalidAttribute {
+                attribute: "Path",
+                value: path.to_string(),
+            });
+        }
+        self.path = Some(path.to_string());
+        return Ok(self);
+    }
+
+    pub fn domain(&mut self, domain: &str) -> Result<&mut Self, CookieError> {
+        if !is_valid_attribute(domain) {
+            return Err(CookieError::InvalidAttribute {
+                attribute: "Domain",
+                value: domain.to_string(),
+            });
+        }
+        self.domain = Some(domain.to_string());
+        return Ok(self);
+    }
+
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_age = Some(max_age);
+        return self;
+    }
+
+    pub fn expires(&mut self, expires: SystemTime) -> &mut Self {
+        self.expires = Some(expires);
+        return self;
+    }
+
+    pub fn secure(&mut self, secure: bool) -> &mut Self {
+        self.secure = secure;
+        return self;
+    }
+
+    pub fn http_only(&mut self, http_only: bool) -> &mut Self {
+        self.http_only = http_only;
+        return self;
+    }
+
+    pub fn same_site(&mut self, same_site: SameSite) -> &mut Self {
+        self.same_site = Some(same_site);
+        return self;
+    }
+
+    /// Replaces the value with `value.signature`, see [`verify_value`].
+    pub fn sign(&mut self, key: &[u8]) -> &mut Self {
+        self.value = sign_value(key, &self.name, &self.value);
+        return self;
+    }
+
+    /// Renders the value of the `Set-Cookie` header for this cookie.
+    pub fn to_header_value(&self) -> String {
+        let mut parts = vec![format!("{}={}", self.name, self.value)];
+        if let Some(path) = &self.path {
+            parts.push(format!("Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            parts.push(format!("Domain={}", domain));
+        }
+        if let Some(max_age) = &self.max_age {
+            parts.push(format!("Max-Age={}", max_age.as_secs()));
+        }
+        if let Some(expires) = &self.expires {
+            parts.push(format!("Expires={}", httpdate::fmt_http_date(*expires)));
+        }
+        if self.secure {
+            parts.push("Secure".to_string());
+        }
+        if self.http_only {
+            parts.push("HttpOnly".to_string());
+        }
+        if let Some(same_site) = &self.same_site {
+            parts.push(format!("SameSite={}", same_site));
+        }
+        return parts.join("; ");
+    }
+}
+
+/// Parses the value of a `Cookie` request header into name/value pairs.
+pub fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(";")
+        .filter_map(|pair| pair.split_once("="))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn signature(key: &[u8], name: &str, value: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    // The name is part of the signed message so a value cannot be replayed under another cookie.
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(value.as_bytes());
+    return mac;
+}
+
+pub fn sign_value(key: &[u8], name: &str, value: &str) -> String {
+    let tag = signature(key, name, value).finalize().into_bytes();
+    return format!("{}.{}", value, URL_SAFE_NO_PAD.encode(tag));
+}
+
+/// Returns the original value if `signed` was produced by [`sign_value`] with the same key and name.
+pub fn verify_value(key: &[u8], name: &str, signed: &str) -> Option<String> {
+    let (value, tag) = signed.rsplit_once(".")?;
+    let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+    return match signature(key, name, value).verify_slice(&tag) {
+        Ok(_) => Some(value.to_string()),
+        Err(_) => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cookie_attributes() {
+        let mut cookie = Cookie::new("id", "abc").unwrap();
+        cookie
+            .path("/app")
+            .unwrap()
+            .max_age(Duration::from_secs(60))
+            .http_only(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(cookie.to_header_value(), "id=abc; Path=/app; Max-Age=60; HttpOnly; SameSite=Strict");
+    }
+
+    #[test]
+    fn rejects_parts_that_would_inject_attributes_or_headers() {
+        assert!(matches!(Cookie::new("", "a"), Err(CookieError::InvalidName(_))));
+        assert!(matches!(Cookie::new("a b", "a"), Err(CookieError::InvalidName(_))));
+        assert!(matches!(Cookie::new("a=b", "a"), Err(CookieError::InvalidName(_))));
+        assert!(matches!(Cookie::new("id", "a; Domain=evil"), Err(CookieError::InvalidValue { .. })));
+        assert!(matches!(Cookie::new("id", "a\r\nLocation: /"), Err(CookieError::InvalidValue { .. })));
+        assert!(matches!(Cookie::new("id", "a b"), Err(CookieError::InvalidValue { .. })));
+        assert!(matches!(Cookie::removal("a\n"), Err(CookieError::InvalidName(_))));
+
+        let mut cookie = Cookie::new("id", "\"quoted\"").unwrap();
+        assert!(matches!(cookie.path("/; Secure"), Err(CookieError::InvalidAttribute { .. })));
+        assert!(matches!(cookie.domain("a.test\r\n"), Err(CookieError::InvalidAttribute { .. })));
+        assert_eq!(cookie.to_header_value(), "id=\"quoted\"");
+    }
+
+    #[test]
+    fn verifies_signed_values() {
+        let key = b"secret key";
+        let mut cookie = Cookie::new("user", "alice").unwrap();
+        cookie.sign(key);
+        assert!(is_valid_value(&cookie.value));
+        assert_eq!(verify_value(key, "user", &cookie.value), Some("alice".to_string()));
+
+        let tampered = cookie.value.replacen("alice", "admin", 1);
+        assert_eq!(verify_value(key, "user", &tampered), None);
+        assert_eq!(verify_value(key, "other", &cookie.value), None);
+        assert_eq!(verify_value(b"other key", "user", &cookie.value), None);
+        assert_eq!(verify_value(key, "user", "alice"), None);
+        assert_eq!(verify_value(key, "user", "alice.!!"), None);
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        assert_eq!(
+            parse_cookie_header("a=1; b=\"2\";c=; =x; junk"),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), String::new()),
+            ]
+        );
+    }
+}
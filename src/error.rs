@@ -0,0 +1,75 @@
+use std::io;
+
+use http::StatusCode;
+use thiserror::Error;
+
+use crate::config::ConfigError;
+use crate::request::ParseError;
+
+/// A `RequestLimits` bound a request went over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Limit {
+    #[error("Request line too long")]
+    RequestLine,
+    #[error("Request header fields too large")]
+    Headers,
+    #[error("Request body too large")]
+    Body,
+}
+
+/// What the server was doing when a timeout ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Phase {
+    #[error("Timed out reading request")]
+    ReadingRequest,
+    #[error("Timed out handling the request")]
+    Handling,
+    #[error("Timed out writing the response")]
+    WritingResponse,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The request was not valid HTTP, or asked for something unsupported.
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    LimitExceeded(Limit),
+    #[error(transparent)]
+    Timeout(Phase),
+    /// The connection failed, or the client went away.
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The handler stopped without producing a response.
+    #[error("Handler failed: {0}")]
+    Handler(String),
+    /// Every handler thread is busy and the queue in front of them is full.
+    #[error("Too many requests waiting for a handler")]
+    Overloaded,
+    /// The server settings could not be loaded.
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+impl Error {
+    /// The status to answer with, or `None` when the client is past answering.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        return match self {
+            Error::Parse(ParseError::Malformed) => Some(StatusCode::BAD_REQUEST),
+            Error::Parse(ParseError::UnsupportedEncoding) => Some(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            Error::Parse(ParseError::UnsupportedVersion) => Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+            Error::Parse(ParseError::ExpectationFailed) => Some(StatusCode::EXPECTATION_FAILED),
+            Error::Parse(ParseError::UnsupportedTransferEncoding) => Some(StatusCode::NOT_IMPLEMENTED),
+            Error::LimitExceeded(Limit::RequestLine) => Some(StatusCode::URI_TOO_LONG),
+            Error::LimitExceeded(Limit::Headers) => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Error::LimitExceeded(Limit::Body) => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Error::Timeout(Phase::ReadingRequest) => Some(StatusCode::REQUEST_TIMEOUT),
+            Error::Timeout(Phase::Handling) => Some(StatusCode::GATEWAY_TIMEOUT),
+            Error::Timeout(Phase::WritingResponse) => None,
+            Error::Io(_) => None,
+            Error::Handler(_) => Some(StatusCode::INTERNAL_SERVER_ERROR),
+            Error::Overloaded => Some(StatusCode::SERVICE_UNAVAILABLE),
+            Error::Config(_) => None,
+        };
+    }
+}
@@ -0,0 +1,164 @@
+use http::{header, StatusCode};
+
+use crate::access_log::json_string;
+use crate::negotiation::negotiate_media_type;
+use crate::request::HTTPContext;
+use crate::response::HttpResponse;
+
+/// An error the router or server answers on its own, such as a 404 for an
+/// unmatched path or a 413 for a body over the limit.
+#[derive(Debug, Clone)]
+pub struct HttpError {
+    pub status: StatusCode,
+    /// What went wrong, in words fit to show the client.
+    pub message: String,
+    /// The request's `Accept` header, if it got far enough to be read.
+    pub accept: Option<String>,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        return HttpError {
+            status,
+            message: message.to_string(),
+            accept: None,
+        };
+    }
+
+    pub fn for_request(status: StatusCode, message: &str, context: &HTTPContext) -> Self {
+        return HttpError {
+            status,
+            message: message.to_string(),
+            accept: context.get_header("accept").map(|accept| accept.to_string()),
+        };
+    }
+
+    /// Whether the client would rather have JSON than HTML. Clients that
+    /// accept either, or say nothing, get HTML.
+    pub fn prefers_json(&self) -> bool {
+        let media_type = negotiate_media_type(self.accept.as_deref(), &["text/html", "application/json"]);
+        return media_type == Some("application/json");
+    }
+}
+
+/// A small HTML page for `error`, or `{"status":404,"error":"Not Found","message":"..."}`
+/// for clients that prefer JSON. The router's default error handler.
+pub fn error_page(error: &HttpError) -> HttpResponse {
+    let reason = error.status.canonical_reason().unwrap_or("Error");
+    let mut response = HttpResponse::new();
+    response.set_status(error.status).set_header(header::VARY, "Accept");
+    if error.prefers_json() {
+        response
+            .set_header(header::CONTENT_TYPE, "application/json")
+            .set_body(&format!(
+                "{{\"status\":{},\"error\":{},\"message\":{}}}",
+                error.status.as_u16(),
+                json_string(Some(reason)),
+                json_string(Some(&error.message)),
+            ));
+    } else {
+        let title = format!("{} {}", error.status.as_u16(), reason);
+        response
+            .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .set_body(&format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<p>{}</p>\n</body>\n</html>\n",
+                title,
+                title,
+                escape_html(&error.message),
+            ));
+    }
+    return response;
+}
+
+/// A bodiless response with just the status, for `Router::default_error_handler`,
+/// and what errors get when their handler panics.
+pub fn empty_error_response(error: &HttpError) -> HttpResponse {
+    let mut response = HttpResponse::new();
+    response.set_status(error.status);
+    return response;
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{context_from_parts, HTTPHeader, HTTPMethod, RequestLimits};
+    use crate::response::ResponseBody;
+    use crate::route::Router;
+
+    fn body(response: &HttpResponse) -> String {
+        return match response.body() {
+            Some(ResponseBody::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            None => String::new(),
+            _ => panic!("expected a buffered body"),
+        };
+    }
+
+    fn context(path: &str, accept: Option<&str>) -> HTTPContext {
+        let headers = accept
+            .map(|accept| vec![HTTPHeader("Accept".to_string(), accept.to_string())])
+            .unwrap_or_default();
+        return context_from_parts("GET", "HTTP/1.1", path, headers, vec![], &RequestLimits::default()).unwrap();
+    }
+
+    #[test]
+    fn renders_html_or_json_as_the_client_prefers() {
+        let mut error = HttpError::new(StatusCode::NOT_FOUND, "Nothing is served at /<x>");
+        let html = error_page(&error);
+        assert_eq!(html.get_header(&header::CONTENT_TYPE), Some("text/html; charset=utf-8"));
+        assert!(body(&html).contains("<h1>404 Not Found</h1>\n<p>Nothing is served at /&lt;x&gt;</p>"));
+
+        error.accept = Some("application/json, text/html;q=0.5".to_string());
+        let json = error_page(&error);
+        assert_eq!(json.get_header(&header::CONTENT_TYPE), Some("application/json"));
+        assert_eq!(body(&json), "{\"status\":404,\"error\":\"Not Found\",\"message\":\"Nothing is served at /<x>\"}");
+        assert_eq!(json.get_header(&header::VARY), Some("Accept"));
+    }
+
+    #[test]
+    fn routers_answer_errors_with_the_error_page_by_default() {
+        let mut router = Router::new();
+        router.route(HTTPMethod::POST, "/items", |_| HttpResponse::new());
+
+        let response = router.handle(context("/missing", Some("application/json")));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.get_header(&header::CONTENT_TYPE), Some("application/json"));
+
+        let response = router.handle(context("/items", None));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.get_header(&header::ALLOW), Some("POST"));
+        assert!(body(&response).contains("<h1>405 Method Not Allowed</h1>"));
+    }
+
+    #[test]
+    fn status_handlers_override_the_default() {
+        let mut router = Router::new();
+        router.error_handler(StatusCode::NOT_FOUND, |error| {
+            let mut response = HttpResponse::new();
+            response.set_status(error.status).set_body("custom");
+            response
+        });
+        assert_eq!(body(&router.handle(context("/missing", None))), "custom");
+
+        router.default_error_handler(empty_error_response);
+        router.route(HTTPMethod::POST, "/items", |_| HttpResponse::new());
+        assert_eq!(body(&router.handle(context("/missing", None))), "custom");
+        let response = router.handle(context("/items", None));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response.body().is_none());
+    }
+}
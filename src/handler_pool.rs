@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use threadpool::ThreadPool;
+
+use crate::error::Error;
+
+/// Runs handlers on a fixed number of threads shared by every connection.
+///
+/// At most `threads + max_queued` handlers are running or waiting at once;
+/// beyond that `execute` refuses the job, which the server answers with 503.
+/// A handler that outlives its timeout keeps its thread until it returns, so
+/// slow handlers fill the pool instead of growing the number of threads.
+#[derive(Clone)]
+pub struct HandlerPool {
+    pool: ThreadPool,
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+/// Counts a job as pending until it has run, even if it panicked.
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl HandlerPool {
+    pub fn new(threads: usize, max_queued: usize) -> Self {
+        return HandlerPool {
+            pool: ThreadPool::with_name("handler".to_string(), threads),
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: threads + max_queued,
+        };
+    }
+
+    /// Handlers running or waiting for a thread.
+    pub fn pending(&self) -> usize {
+        return self.pending.load(Ordering::SeqCst);
+    }
+
+    pub fn execute<F>(&self, job: F) -> Result<(), Error>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let pending = Pending(self.pending.clone());
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            return Err(Error::Overloaded);
+        }
+        self.pool.execute(move || {
+            let _pending = pending;
+            job();
+        });
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn refuses_jobs_beyond_its_capacity() {
+        let pool = HandlerPool::new(1, 1);
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(std::sync::Mutex::new(wait));
+        for _ in 0..2 {
+            let wait = wait.clone();
+            pool.execute(move || {
+                let _ = wait.lock().unwrap().recv();
+            })
+            .unwrap();
+        }
+        assert!(matches!(pool.execute(|| ()), Err(Error::Overloaded)));
+        assert_eq!(pool.pending(), 2);
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        for _ in 0..100 {
+            if pool.pending() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.pending(), 0);
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(1).unwrap()).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok(1));
+    }
+
+    #[test]
+    fn frees_the_slot_of_a_panicking_job() {
+        let pool = HandlerPool::new(1, 0);
+        pool.execute(|| panic!("handler failed")).unwrap();
+        for _ in 0..100 {
+            if pool.pending() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.pending(), 0);
+    }
+}
@@ -0,0 +1,739 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::{header, HeaderValue, Request, Response};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::error::{Error, Limit, Phase};
+use crate::logging::{self, Span};
+use crate::request::{context_from_parts, HTTPContext, HTTPHeader, RequestLimits};
+use crate::response::{HttpResponse, ResponseBody};
+use crate::server::{error_response, handle_async, log_error, ConnectionSettings, RequestRecord};
+use crate::stream::{CountingWriter, Rewind, WriteTimeout};
+use crate::tls::TlsInfo;
+
+/// What a client speaking HTTP/2 with prior knowledge sends before anything else.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// The smallest frame size every peer has to accept.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const FRAME_HEADER_SIZE: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+/// Each setting is a 16 bit identifier followed by a 32 bit value.
+const SETTING_SIZE: usize = 6;
+
+const SWITCHING_PROTOCOLS: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+/// Whether the start of a cleartext connection is the HTTP/2 preface
+/// rather than an HTTP/1 request line.
+pub fn is_preface(buffer: &[u8]) -> bool {
+    // No HTTP/1 method starts with "PRI ", so four bytes are enough to tell.
+    return buffer.len() >= 4 && PREFACE.starts_with(&buffer[..buffer.len().min(PREFACE.len())]);
+}
+
+/// Serves an HTTP/2 connection, running each stream through the router
+/// concurrently. Flow control, HPACK and framing are left to `h2`.
+pub async fn serve<S>(settings: ConnectionSettings, stream: S, tls: Option<TlsInfo>, peer_addr: Option<SocketAddr>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ConnectionSettings { limits, timeouts, .. } = settings;
+    let span = Span::connection(peer_addr);
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(limits.max_header_bytes as u32)
+        .handshake::<_, Bytes>(stream);
+    let mut connection = match timeout(timeouts.header_read, handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            logging::warn(&span, &format!("h2 handshake failed: {}", e));
+            return;
+        }
+        Err(_) => {
+            logging::warn(&span, "timed out during h2 handshake");
+            return;
+        }
+    };
+
+    let active_streams = Arc::new(AtomicUsize::new(0));
+    let mut closing = false;
+    loop {
+        // Accepting also drives the connection, so keep polling while streams are busy.
+        let accepted = match timeout(timeouts.idle, connection.accept()).await {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                if active_streams.load(Ordering::SeqCst) == 0 && !closing {
+                    connection.graceful_shutdown();
+                    closing = true;
+                }
+                continue;
+            }
+        };
+        match accepted {
+            Some(Ok((request, respond))) => {
+                let settings = settings.clone();
+                let tls = tls.clone();
+                let active_streams = active_streams.clone();
+                active_streams.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    handle_stream(settings, request, respond, tls, peer_addr).await;
+                    active_streams.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Some(Err(e)) => {
+                logging::warn(&span, &format!("h2 connection failed: {}", e));
+                break;
+            }
+            None => break,
+        }
+    }
+}
+
+/// Appends an HPACK integer with an `prefix_bits` wide prefix (RFC 7541, 5.1).
+fn encode_integer(output: &mut Vec<u8>, value: usize, prefix_bits: u8) {
+    let limit = (1usize << prefix_bits) - 1;
+    if value < limit {
+        output.push(value as u8);
+        return;
+    }
+    output.push(limit as u8);
+    let mut value = value - limit;
+    while value >= 128 {
+        output.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    output.push(value as u8);
+}
+
+/// Appends a literal header field without indexing, so no dynamic table
+/// state is shared with the decoder (RFC 7541, 6.2.2).
+fn encode_header(output: &mut Vec<u8>, name: &str, value: &str) {
+    output.push(0);
+    encode_integer(output, name.len(), 7);
+    output.extend_from_slice(name.to_lowercase().as_bytes());
+    encode_integer(output, value.len(), 7);
+    output.extend_from_slice(value.as_bytes());
+}
+
+/// Headers that HTTP/2 does not allow, or that only concern the upgrade itself.
+fn is_hop_by_hop(name: &str) -> bool {
+    return ["connection", "upgrade", "http2-settings", "keep-alive", "proxy-connection", "transfer-encoding", "te", "host"]
+        .iter()
+        .any(|hop_by_hop| name.eq_ignore_ascii_case(hop_by_hop));
+}
+
+fn frame_header(length: usize, kind: u8, flags: u8, stream_id: u32) -> Vec<u8> {
+    let mut header = (length as u32).to_be_bytes()[1..].to_vec();
+    header.push(kind);
+    header.push(flags);
+    header.extend_from_slice(&stream_id.to_be_bytes());
+    return header;
+}
+
+/// The frames a cleartext `Upgrade: h2c` request stands for once the
+/// connection has switched to HTTP/2.
+pub struct UpgradeFrames {
+    /// The client's `HTTP2-Settings` as a SETTINGS frame.
+    settings: Vec<u8>,
+    /// The request replayed as stream 1, which is how it has to be answered.
+    headers: Vec<u8>,
+}
+
+/// Decodes `HTTP2-Settings`: a base64url SETTINGS payload (RFC 7540, 3.2.1).
+fn decode_settings(context: &HTTPContext) -> Option<Vec<u8>> {
+    let mut values = context
+        .headers
+        .iter()
+        .filter(|header| header.0.eq_ignore_ascii_case("http2-settings"));
+    let value = values.next()?;
+    if values.next().is_some() {
+        return None;
+    }
+    let payload = URL_SAFE_NO_PAD.decode(value.1.trim().trim_end_matches('=')).ok()?;
+    if payload.len() % SETTING_SIZE != 0 || payload.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+    let mut frame = frame_header(payload.len(), FRAME_SETTINGS, 0, 0);
+    frame.extend(payload);
+    return Some(frame);
+}
+
+/// For a cleartext `Upgrade: h2c` request, the frames to feed the HTTP/2
+/// connection in its place (RFC 7540, 3.2). `None` means the upgrade is
+/// ignored and the request is answered over HTTP/1.1, which is also what
+/// happens to upgrades carrying a body or an invalid `HTTP2-Settings`.
+pub fn upgrade_frames(context: &HTTPContext) -> Option<UpgradeFrames> {
+    let wants_upgrade = context.header_has_token("upgrade", "h2c")
+        && context.header_has_token("connection", "upgrade")
+        && context.header_has_token("connection", "http2-settings");
+    if !wants_upgrade || context.tls.is_some() || context.body_bytes.is_some() {
+        return None;
+    }
+    let settings = decode_settings(context)?;
+
+    let mut block = vec![];
+    encode_header(&mut block, ":method", &context.method.to_string());
+    encode_header(&mut block, ":scheme", "http");
+    encode_header(&mut block, ":path", &context.target());
+    if let Some(host) = context.get_header("host") {
+        encode_header(&mut block, ":authority", host);
+    }
+    for header in context.headers.iter().filter(|header| !is_hop_by_hop(&header.0)) {
+        encode_header(&mut block, &header.0, &header.1);
+    }
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+
+    let mut headers = frame_header(block.len(), FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 1);
+    headers.extend(block);
+    return Some(UpgradeFrames { settings, headers });
+}
+
+/// Switches a cleartext connection to HTTP/2 after an `Upgrade: h2c` request,
+/// with `frames` from `upgrade_frames` standing in for that request.
+pub async fn serve_upgrade<S>(
+    settings: ConnectionSettings,
+    stream: S,
+    frames: UpgradeFrames,
+    tls: Option<TlsInfo>,
+    peer_addr: Option<SocketAddr>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let timeouts = settings.timeouts;
+    let mut stream = stream;
+    let switch = async {
+        stream.write_all(SWITCHING_PROTOCOLS).await?;
+        stream.flush().await?;
+        // The client answers with the preface and its SETTINGS frame, and only
+        // after those may the replayed request be fed to the connection.
+        let mut received = vec![0; PREFACE.len() + FRAME_HEADER_SIZE];
+        stream.read_exact(&mut received).await?;
+        if !received.starts_with(PREFACE) || received[PREFACE.len() + 3] != FRAME_SETTINGS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing HTTP/2 preface"));
+        }
+        let settings_length = u32::from_be_bytes([0, received[24], received[25], received[26]]) as usize;
+        if settings_length > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SETTINGS frame too large"));
+        }
+        let mut client_settings = vec![0; settings_length];
+        stream.read_exact(&mut client_settings).await?;
+        // `HTTP2-Settings` applies before anything the client sends after the
+        // preface, so it goes in as the first SETTINGS frame.
+        let mut prefix = PREFACE.to_vec();
+        prefix.extend(frames.settings);
+        prefix.extend_from_slice(&received[PREFACE.len()..]);
+        prefix.extend(client_settings);
+        prefix.extend(frames.headers);
+        return Ok(prefix);
+    };
+    let prefix = match timeout(timeouts.header_read, switch).await {
+        Ok(Ok(prefix)) => prefix,
+        Ok(Err(e)) => {
+            logging::warn(&Span::connection(peer_addr), &format!("h2c upgrade failed: {}", e));
+            return;
+        }
+        Err(_) => {
+            logging::warn(&Span::connection(peer_addr), "timed out during h2c upgrade");
+            return;
+        }
+    };
+    serve(settings, SkipSettingsAck::new(Rewind::new(prefix, stream)), tls, peer_addr).await;
+}
+
+/// Drops the first SETTINGS acknowledgement the connection writes. That one
+/// answers the frame made from `HTTP2-Settings`, which the 101 already
+/// acknowledged, and a client would reject an ACK for a frame it never sent.
+struct SkipSettingsAck<S> {
+    inner: S,
+    /// The frame header being read, until all of its bytes have been written.
+    header: Vec<u8>,
+    /// How much of the current frame's payload is still to come.
+    payload_left: usize,
+    skipped: bool,
+    /// Bytes accepted from the caller but not yet written to `inner`.
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncWrite + Unpin> SkipSettingsAck<S> {
+    fn new(inner: S) -> Self {
+        return SkipSettingsAck {
+            inner,
+            header: vec![],
+            payload_left: 0,
+            skipped: false,
+            pending: vec![],
+        };
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    self.pending.drain(..written);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        return Poll::Ready(Ok(()));
+    }
+
+    /// Walks `buffer` frame by frame, keeping everything but the ACK to skip.
+    fn filter(&mut self, buffer: &[u8]) {
+        let mut buffer = buffer;
+        while !buffer.is_empty() {
+            if self.skipped {
+                self.pending.extend_from_slice(buffer);
+                return;
+            }
+            if self.payload_left > 0 {
+                let length = self.payload_left.min(buffer.len());
+                self.pending.extend_from_slice(&buffer[..length]);
+                self.payload_left -= length;
+                buffer = &buffer[length..];
+                continue;
+            }
+            let length = (FRAME_HEADER_SIZE - self.header.len()).min(buffer.len());
+            self.header.extend_from_slice(&buffer[..length]);
+            buffer = &buffer[length..];
+            if self.header.len() < FRAME_HEADER_SIZE {
+                return;
+            }
+            let header = std::mem::take(&mut self.header);
+            let payload_length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            if header[3] == FRAME_SETTINGS && header[4] & FLAG_ACK != 0 && payload_length == 0 {
+                self.skipped = true;
+                continue;
+            }
+            self.pending.extend(header);
+            self.payload_left = payload_length;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SkipSettingsAck<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.get_mut().inner).poll_read(cx, buffer);
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SkipSettingsAck<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.skipped && this.pending.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buffer);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.pending.is_empty() {
+            return Poll::Pending;
+        }
+        // What is kept goes out on the next write or flush.
+        this.filter(buffer);
+        return Poll::Ready(Ok(buffer.len()));
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+        return Pin::new(&mut this.inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+        return Pin::new(&mut this.inner).poll_shutdown(cx);
+    }
+}
+
+async fn read_body(body: &mut RecvStream, limits: &RequestLimits) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![];
+    while let Some(data) = body.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => return Err(Error::Io(to_io_error(e))),
+        };
+        // Hand the window back straight away, the limit below bounds what we hold.
+        let _ = body.flow_control().release_capacity(data.len());
+        if bytes.len() + data.len() > limits.max_body_bytes {
+            return Err(Error::LimitExceeded(Limit::Body));
+        }
+        bytes.extend_from_slice(&data);
+    }
+    return Ok(bytes);
+}
+
+async fn handle_stream(
+    settings: ConnectionSettings,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    tls: Option<TlsInfo>,
+    peer_addr: Option<SocketAddr>,
+) {
+    let ConnectionSettings { router, limits, timeouts, handlers, .. } = settings;
+    let started = Instant::now();
+    let mut span = Span::connection(peer_addr);
+    let (parts, mut body) = request.into_parts();
+    let context = match timeout(timeouts.body_read, read_body(&mut body, &limits)).await {
+        Ok(Ok(bytes)) => {
+            let mut headers: Vec<HTTPHeader> = parts
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    HTTPHeader(name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string())
+                })
+                .collect();
+            // Handlers look for `Host`, which HTTP/2 carries as `:authority`.
+            if let Some(authority) = parts.uri.authority() {
+                if !parts.headers.contains_key(header::HOST) {
+                    headers.push(HTTPHeader("host".to_string(), authority.to_string()));
+                }
+            }
+            let raw_path = match parts.uri.path_and_query() {
+                Some(path_and_query) => path_and_query.as_str(),
+                None => "/",
+            };
+            context_from_parts(parts.method.as_str(), "HTTP/2.0", raw_path, headers, bytes, &limits)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(Error::Timeout(Phase::ReadingRequest)),
+    };
+
+    let mut record = None;
+    let response = match context {
+        Ok(mut context) => {
+            context.tls = tls;
+            context.peer_addr = peer_addr;
+            span = Span::request(&context);
+            record = Some(RequestRecord::start(&router, &context, started));
+            let accept = context.get_header("accept").map(|accept| accept.to_string());
+            match handle_async(&handlers, router.clone(), context, timeouts.handler).await {
+                Ok(response) => response,
+                Err(e) => {
+                    log_error(&span, &e);
+                    match error_response(&router, &e, accept.as_deref()) {
+                        Some(response) => response,
+                        None => {
+                            respond.send_reset(h2::Reason::INTERNAL_ERROR);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            log_error(&span, &e);
+            if let Some(metrics) = router.metrics() {
+                metrics.parse_error(&e);
+            }
+            let accept = parts.headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok());
+            match error_response(&router, &e, accept) {
+                Some(response) => response,
+                None => {
+                    respond.send_reset(h2::Reason::CANCEL);
+                    return;
+                }
+            }
+        }
+    };
+
+    // An event stream stays open for as long as it has events, so only its
+    // writes are bounded, each on its own.
+    let span = span.response(&response);
+    let streaming = response.is_streaming();
+    if let Some(record) = record.as_mut() {
+        record.set_response(&response);
+    }
+    let mut bytes_sent = 0;
+    let send = send_response(response, &mut respond, timeouts.write, &span, &mut bytes_sent);
+    let sent = match streaming {
+        true => Ok(send.await),
+        false => timeout(timeouts.write, send).await,
+    };
+    match sent {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => logging::warn(&span, &format!("writing response failed: {}", e)),
+        Err(_) => {
+            logging::warn(&span, "timed out writing response");
+            respond.send_reset(h2::Reason::CANCEL);
+        }
+    }
+    if let Some(record) = record {
+        record.finish(&router, bytes_sent);
+    }
+}
+
+/// Headers that only mean something to a single HTTP/1.1 connection and
+/// are forbidden in HTTP/2.
+fn is_connection_specific(name: &header::HeaderName) -> bool {
+    return name == header::CONNECTION
+        || name == header::TRANSFER_ENCODING
+        || name == header::UPGRADE
+        || name == "keep-alive"
+        || name == "proxy-connection";
+}
+
+/// Sends the response on its stream, adding the DATA payload written to
+/// `bytes_sent`. Headers are left out since HPACK compresses them across streams.
+async fn send_response(
+    response: HttpResponse,
+    respond: &mut SendResponse<Bytes>,
+    write_timeout: Duration,
+    span: &Span,
+    bytes_sent: &mut u64,
+) -> io::Result<()> {
+    let allows_body = response.allows_body();
+    let (status, headers, body) = response.into_parts();
+    let mut head = Response::new(());
+    *head.status_mut() = status;
+    for (name, value) in headers.into_iter() {
+        if is_connection_specific(&name) {
+            continue;
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => {
+                head.headers_mut().append(name, value);
+            }
+            Err(_) => logging::warn(span, &format!("dropped invalid value for header {}", name)),
+        }
+    }
+    let body = match body {
+        Some(body) if allows_body => body,
+        _ => {
+            respond.send_response(head, true).map_err(to_io_error)?;
+            return Ok(());
+        }
+    };
+    if !matches!(body, ResponseBody::Encoded { .. } | ResponseBody::Events(_)) {
+        head.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+
+    let send = respond.send_response(head, false).map_err(to_io_error)?;
+    let mut writer = WriteTimeout::new(CountingWriter::new(StreamWriter { send }, bytes_sent), write_timeout);
+    body.write_async(&mut writer).await?;
+    writer.get_mut().get_mut().send.send_data(Bytes::new(), true).map_err(to_io_error)?;
+    return Ok(());
+}
+
+fn to_io_error(error: h2::Error) -> io::Error {
+    return io::Error::other(error);
+}
+
+/// Lets the HTTP/1.1 body writers feed an HTTP/2 stream, waiting for flow
+/// control to grant capacity before each DATA frame.
+struct StreamWriter {
+    send: SendStream<Bytes>,
+}
+
+impl AsyncWrite for StreamWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        if buffer.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        this.send.reserve_capacity(buffer.len());
+        loop {
+            return match this.send.poll_capacity(cx) {
+                Poll::Ready(Some(Ok(0))) => continue,
+                Poll::Ready(Some(Ok(capacity))) => {
+                    let length = capacity.min(buffer.len());
+                    match this.send.send_data(Bytes::copy_from_slice(&buffer[..length]), false) {
+                        Ok(()) => Poll::Ready(Ok(length)),
+                        Err(e) => Poll::Ready(Err(to_io_error(e))),
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => Poll::Ready(Err(to_io_error(e))),
+                Poll::Ready(None) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Poll::Ready(Ok(()));
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Poll::Ready(Ok(()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::HTTPMethod;
+    use crate::route::Router;
+    use crate::server::Server;
+    use tokio::net::TcpStream;
+
+    const BODY: &str = "hello world";
+
+    async fn start_server() -> SocketAddr {
+        let mut server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+        let mut router = Router::new();
+        router.route(HTTPMethod::GET, "/hello", |_request| {
+            let mut response = HttpResponse::new();
+            response.set_body(BODY);
+            return response;
+        });
+        server.use_router(router);
+        let address = server.local_addrs()[0];
+        tokio::spawn(async move { server.run_async(|| ()).await });
+        return address;
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header).await.unwrap();
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).await.unwrap();
+        return (header[3], header[4], stream_id, payload);
+    }
+
+    async fn upgrade(stream: &mut TcpStream, http2_settings: &str) -> String {
+        let request = format!(
+            "GET /hello HTTP/1.1\r\nHost: test\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\nHTTP2-Settings: {}\r\n\r\n",
+            http2_settings
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        return String::from_utf8(head).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn applies_http2_settings_of_an_upgrade() {
+        let address = start_server().await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // SETTINGS_INITIAL_WINDOW_SIZE = 5, so only 5 bytes of the body may be sent.
+        let http2_settings = URL_SAFE_NO_PAD.encode([0, 4, 0, 0, 0, 5]);
+        let head = upgrade(&mut stream, &http2_settings).await;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+
+        stream.write_all(PREFACE).await.unwrap();
+        stream.write_all(&frame_header(0, FRAME_SETTINGS, 0, 0)).await.unwrap();
+
+        let mut acks = 0;
+        let mut status = None;
+        let mut body = vec![];
+        let mut end_stream = false;
+        let mut window_opened = false;
+        while !end_stream {
+            let next = timeout(Duration::from_millis(300), read_frame(&mut stream)).await;
+            let (kind, flags, stream_id, payload) = match next {
+                Ok(frame) => frame,
+                Err(_) => {
+                    assert_eq!(body, BODY.as_bytes()[..5], "sent more than the window allows");
+                    assert!(!window_opened, "stalled after the window was opened");
+                    let mut window_update = frame_header(4, 0x8, 0, 1);
+                    window_update.extend_from_slice(&100u32.to_be_bytes());
+                    stream.write_all(&window_update).await.unwrap();
+                    window_opened = true;
+                    continue;
+                }
+            };
+            match kind {
+                FRAME_SETTINGS if flags & FLAG_ACK != 0 => acks += 1,
+                FRAME_HEADERS => {
+                    assert_eq!(stream_id, 1);
+                    // 0x88 is the static table entry for `:status 200`.
+                    status = payload.first().copied();
+                }
+                0x0 => {
+                    assert_eq!(stream_id, 1);
+                    body.extend(payload);
+                    end_stream = flags & FLAG_END_STREAM != 0;
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(status, Some(0x88));
+        assert_eq!(body, BODY.as_bytes());
+        assert!(window_opened);
+        assert_eq!(acks, 1, "only the client's own SETTINGS frame is acknowledged");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_over_http1_when_http2_settings_is_invalid() {
+        let address = start_server().await;
+        for http2_settings in ["not base64!", "AAQAAA"] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let head = upgrade(&mut stream, http2_settings).await;
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_clients_with_prior_knowledge() {
+        let address = start_server().await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = Request::get("http://test/hello").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = vec![];
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            let _ = body.flow_control().release_capacity(data.len());
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, BODY.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn skips_only_the_first_settings_ack() {
+        let mut frames = frame_header(6, FRAME_SETTINGS, 0, 0);
+        frames.extend_from_slice(&[0, 3, 0, 0, 0, 100]);
+        frames.extend(frame_header(0, FRAME_SETTINGS, FLAG_ACK, 0));
+        frames.extend(frame_header(0, FRAME_SETTINGS, FLAG_ACK, 0));
+        frames.extend(frame_header(2, 0x0, 0, 1));
+        frames.extend_from_slice(b"ok");
+
+        let mut writer = SkipSettingsAck::new(vec![]);
+        for byte in frames.iter() {
+            writer.write_all(&[*byte]).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+
+        let mut expected = frames[..FRAME_HEADER_SIZE + 6].to_vec();
+        expected.extend_from_slice(&frames[FRAME_HEADER_SIZE * 2 + 6..]);
+        assert_eq!(writer.inner, expected);
+    }
+}
@@ -0,0 +1,29 @@
+//! HTTP/1.1 and HTTP/2 server with a `Router`, middleware and the pieces
+//! around it. `main.rs` builds the demo binary on top of it.
+#![allow(clippy::needless_return)]
+
+pub mod access_log;
+pub mod cli;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod cookie;
+pub mod error;
+pub mod error_page;
+pub mod handler_pool;
+pub mod http2;
+pub mod logging;
+pub mod metrics;
+pub mod negotiation;
+pub mod range;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod route;
+pub mod server;
+pub mod session;
+pub mod sse;
+pub mod static_files;
+pub mod stream;
+pub mod tls;
+pub mod websocket;
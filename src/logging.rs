@@ -0,0 +1,351 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::request::HTTPContext;
+use crate::request_id::X_REQUEST_ID;
+use crate::response::HttpResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+    }
+}
+
+/// What a message is about: the connection it happened on and, once one
+/// has been read, the request.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    pub peer_addr: Option<SocketAddr>,
+    pub request_id: Option<String>,
+}
+
+impl Span {
+    pub fn connection(peer_addr: Option<SocketAddr>) -> Self {
+        return Span {
+            peer_addr,
+            request_id: None,
+        };
+    }
+
+    pub fn request(context: &HTTPContext) -> Self {
+        return Span {
+            peer_addr: context.peer_addr,
+            request_id: context.request_id.clone(),
+        };
+    }
+
+    /// The server only learns the id `RequestIdMiddleware` picked from the
+    /// response it echoes it on, so messages after the handler take it from there.
+    pub fn response(&self, response: &HttpResponse) -> Self {
+        return Span {
+            peer_addr: self.peer_addr,
+            request_id: response
+                .get_header(&X_REQUEST_ID)
+                .map(|id| id.to_string())
+                .or(self.request_id.clone()),
+        };
+    }
+}
+
+/// Receives everything the server reports. Install one with `set_logger`
+/// to route messages to a log pipeline.
+pub trait Logger {
+    fn log(&self, level: Level, span: &Span, message: &str);
+}
+
+/// Where formatted log lines end up.
+pub trait LogSink {
+    fn write_line(&self, line: &str) -> io::Result<()>;
+}
+
+pub struct StdoutSink;
+
+impl LogSink for StdoutSink {
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(line.as_bytes())?;
+        return stdout.write_all(b"\n");
+    }
+}
+
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(line.as_bytes())?;
+        return stderr.write_all(b"\n");
+    }
+}
+
+/// Appends to a file and, once it would grow past `max_bytes`, renames it to
+/// `<path>.1`, shifting older files up to `<path>.<max_files>` and dropping the oldest.
+pub struct RotatingFileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFileSink {
+    pub fn new(path: &str, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let length = file.metadata()?.len();
+        return Ok(RotatingFileSink {
+            path,
+            max_bytes,
+            max_files,
+            file: Mutex::new((file, length)),
+        });
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        return PathBuf::from(path);
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        if self.max_files == 0 {
+            return OpenOptions::new().create(true).write(true).truncate(true).open(&self.path);
+        }
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        return OpenOptions::new().create(true).append(true).open(&self.path);
+    }
+}
+
+impl LogSink for RotatingFileSink {
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let length = line.len() as u64 + 1;
+        if file.1 > 0 && file.1 + length > self.max_bytes {
+            *file = (self.rotate()?, 0);
+        }
+        file.0.write_all(format!("{}\n", line).as_bytes())?;
+        file.1 += length;
+        return Ok(());
+    }
+}
+
+/// Writes one line per message at or above `level`:
+/// `2000-10-10T13:55:36Z WARN peer=127.0.0.1:50312 request_id=... message`.
+pub struct TextLogger {
+    level: Level,
+    sink: Box<dyn LogSink + Send + Sync>,
+}
+
+impl TextLogger {
+    pub fn new<T>(level: Level, sink: T) -> Self
+    where
+        T: LogSink + 'static + Send + Sync,
+    {
+        return TextLogger {
+            level,
+            sink: Box::new(sink),
+        };
+    }
+}
+
+impl Logger for TextLogger {
+    fn log(&self, level: Level, span: &Span, message: &str) {
+        if level > self.level {
+            return;
+        }
+        let mut line = format!("{} {}", iso_time(SystemTime::now()), level.as_str());
+        if let Some(peer_addr) = span.peer_addr {
+            line.push_str(&format!(" peer={}", peer_addr));
+        }
+        if let Some(request_id) = &span.request_id {
+            line.push_str(&format!(" request_id={}", request_id));
+        }
+        line.push(' ');
+        line.push_str(&message.replace(['\r', '\n'], " "));
+        // There is nowhere left to report a failing sink.
+        let _ = self.sink.write_line(&line);
+    }
+}
+
+static LOGGER: RwLock<Option<Box<dyn Logger + Send + Sync>>> = RwLock::new(None);
+
+/// Replaces the logger for the whole process. Until this is called,
+/// messages at `Info` and above go to stderr.
+pub fn set_logger<T>(logger: T)
+where
+    T: Logger + 'static + Send + Sync,
+{
+    *LOGGER.write().unwrap() = Some(Box::new(logger));
+}
+
+pub fn log(level: Level, span: &Span, message: &str) {
+    match LOGGER.read().unwrap().as_ref() {
+        Some(logger) => logger.log(level, span, message),
+        None => TextLogger::new(Level::Info, StderrSink).log(level, span, message),
+    }
+}
+
+pub fn error(span: &Span, message: &str) {
+    log(Level::Error, span, message);
+}
+
+pub fn warn(span: &Span, message: &str) {
+    log(Level::Warn, span, message);
+}
+
+pub fn info(span: &Span, message: &str) {
+    log(Level::Info, span, message);
+}
+
+pub fn debug(span: &Span, message: &str) {
+    log(Level::Debug, span, message);
+}
+
+/// `Tue, 10 Oct 2000 13:55:36 GMT` split into day, month name, year and time.
+pub fn time_parts(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let parts: Vec<&str> = date.split(' ').collect();
+    return (
+        parts[1].to_string(),
+        parts[2].to_string(),
+        parts[3].to_string(),
+        parts[4].to_string(),
+    );
+}
+
+/// `2000-10-10T13:55:36Z`
+pub fn iso_time(time: SystemTime) -> String {
+    let (day, month, year, clock) = time_parts(time);
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = months.iter().position(|name| *name == month).unwrap_or(0) + 1;
+    return format!("{}-{:02}-{}T{}Z", year, month, day, clock);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn scratch_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("logging-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        return directory;
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        return fs::read_to_string(path).ok();
+    }
+
+    #[test]
+    fn rotates_files_and_drops_the_oldest() {
+        let directory = scratch_directory("rotate");
+        let path = directory.join("access.log");
+        let sink = RotatingFileSink::new(path.to_str().unwrap(), 20, 2).unwrap();
+        // Every line takes 10 bytes with its newline, so two fit in a file.
+        for line in ["line    1", "line    2", "line    3", "line    4", "line    5", "line    6", "line    7"] {
+            sink.write_line(line).unwrap();
+        }
+        assert_eq!(read(path.clone()).as_deref(), Some("line    7\n"));
+        assert_eq!(read(sink.rotated_path(1)).as_deref(), Some("line    5\nline    6\n"));
+        assert_eq!(read(sink.rotated_path(2)).as_deref(), Some("line    3\nline    4\n"));
+        assert_eq!(read(sink.rotated_path(3)), None);
+        assert!(sink.rotated_path(1).ends_with("access.log.1"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn picks_up_the_size_of_an_existing_file() {
+        let directory = scratch_directory("reopen");
+        let path = directory.join("app.log");
+        fs::write(&path, "0123456789012345\n").unwrap();
+        let sink = RotatingFileSink::new(path.to_str().unwrap(), 20, 1).unwrap();
+        sink.write_line("next").unwrap();
+        assert_eq!(read(path.clone()).as_deref(), Some("next\n"));
+        assert_eq!(read(sink.rotated_path(1)).as_deref(), Some("0123456789012345\n"));
+
+        // A single line longer than the limit still gets written, on its own.
+        sink.write_line(&"x".repeat(30)).unwrap();
+        assert_eq!(read(path).map(|text| text.len()), Some(31));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn truncates_in_place_without_rotated_files() {
+        let directory = scratch_directory("truncate");
+        let path = directory.join("app.log");
+        let sink = RotatingFileSink::new(path.to_str().unwrap(), 20, 0).unwrap();
+        for line in ["line    1", "line    2", "line    3"] {
+            sink.write_line(line).unwrap();
+        }
+        assert_eq!(read(path).as_deref(), Some("line    3\n"));
+        assert_eq!(read(sink.rotated_path(1)), None);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>);
+
+    impl LogSink for Lines {
+        fn write_line(&self, line: &str) -> io::Result<()> {
+            self.0.lock().unwrap().push(line.to_string());
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn filters_by_level_and_keeps_messages_on_one_line() {
+        let lines = Lines::default();
+        let logger = TextLogger::new(Level::Warn, lines.clone());
+        let span = Span {
+            peer_addr: Some("127.0.0.1:50312".parse().unwrap()),
+            request_id: Some("req-1".to_string()),
+        };
+        logger.log(Level::Info, &span, "not logged");
+        logger.log(Level::Debug, &span, "not logged");
+        logger.log(Level::Error, &span, "failed\r\nWARN forged line");
+        logger.log(Level::Warn, &Span::default(), "slow");
+
+        let lines = lines.0.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        let (time, rest) = lines[0].split_once(" ").unwrap();
+        assert!(time.len() == "2000-10-10T13:55:36Z".len() && time.ends_with('Z'), "{}", time);
+        assert_eq!(rest, "ERROR peer=127.0.0.1:50312 request_id=req-1 failed  WARN forged line");
+        assert!(lines[1].ends_with(" WARN slow"));
+    }
+
+    #[test]
+    fn formats_times() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(971186136);
+        assert_eq!(iso_time(time), "2000-10-10T13:55:36Z");
+        assert_eq!(
+            time_parts(time),
+            ("10".to_string(), "Oct".to_string(), "2000".to_string(), "13:55:36".to_string())
+        );
+    }
+}
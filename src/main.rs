@@ -1,14 +1,12 @@
-use std::{thread::sleep, time};
+#![allow(clippy::needless_return)]
 
-use response::HttpResponse;
-use route::Router;
+use std::{env, process, thread::sleep, time};
 
-use crate::server::Server;
-
-mod request;
-mod response;
-mod route;
-mod server;
+use http_server_starter_rust::cli::{Args, Mode, USAGE};
+use http_server_starter_rust::config::Config;
+use http_server_starter_rust::response::HttpResponse;
+use http_server_starter_rust::route::{self, Router};
+use http_server_starter_rust::{error, request};
 
 fn say_jung(_request: &mut route::HTTPRequest) -> HttpResponse {
         let mut response = HttpResponse::new();
@@ -23,7 +21,7 @@ fn echo(request: &mut route::HTTPRequest) -> HttpResponse {
         let sleep_duration = time::Duration::from_millis(10);
         sleep(sleep_duration);
         match &request.context.body {
-            Some(value) => response.set_body(&value),
+            Some(value) => response.set_body(value),
             None => response.set_body("Unknown"),
         };
         return response;
@@ -46,10 +44,39 @@ fn get_router() -> Router {
 
 #[tokio::main]
 async fn main() {
-    let address = "127.0.0.1:4221";
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        print!("{}", USAGE);
+        return;
+    }
 
-    let mut  server = Server::from_address(address).unwrap();
-    server.use_router(get_router());
+    let config = Config::load(args.config.as_deref()).and_then(|mut config| {
+        args.apply(&mut config);
+        config.validate()?;
+        return Ok(config);
+    });
+    let server = config.map_err(error::Error::from).and_then(|config| config.server(get_router()));
+    let server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+    let addresses = server.local_addrs();
 
-    server.run_async(move || println!("Listening synchronously on address: {:?}", address)).await;
+    let result = match args.mode {
+        Mode::Sync => server.run(move || println!("Listening synchronously on {:?}", addresses)),
+        Mode::Async => server.run_async(move || println!("Listening on {:?}", addresses)).await,
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
 }
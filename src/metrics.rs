@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::request::HTTPContext;
+use crate::response::HttpResponse;
+
+/// Latency bucket bounds in seconds, the Prometheus client defaults.
+const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Requests to paths no route matched share one label, so scanners cannot
+/// grow the series without bound.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    buckets: Vec<f64>,
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    latencies: Mutex<BTreeMap<(String, String), Histogram>>,
+    parse_errors: Mutex<BTreeMap<String, u64>>,
+    in_flight: AtomicI64,
+    open_connections: AtomicI64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Counters shared by the server and the metrics route. Clones share the same counts.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        return Metrics::new();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        return Metrics::with_buckets(&DEFAULT_BUCKETS);
+    }
+
+    /// Uses `buckets`, upper bounds in seconds in ascending order, for the latency histogram.
+    pub fn with_buckets(buckets: &[f64]) -> Self {
+        return Metrics {
+            registry: Arc::new(Registry {
+                buckets: buckets.to_vec(),
+                ..Default::default()
+            }),
+        };
+    }
+
+    /// Counts the connection as open until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.registry.open_connections.fetch_add(1, Ordering::SeqCst);
+        return ConnectionGuard {
+            registry: self.registry.clone(),
+        };
+    }
+
+    /// Counts the request as in flight until the returned timer is dropped.
+    /// `route` is the pattern the request matched, such as `/users/:id`.
+    pub fn request_started(&self, route: Option<&str>, context: &HTTPContext, started: Instant) -> RequestTimer {
+        self.registry.in_flight.fetch_add(1, Ordering::SeqCst);
+        let bytes_in = match &context.body_bytes {
+            Some(body_bytes) => body_bytes.len() as u64,
+            None => 0,
+        };
+        self.registry.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        return RequestTimer {
+            registry: self.registry.clone(),
+            route: route.unwrap_or(UNMATCHED_ROUTE).to_string(),
+            method: context.method.to_string(),
+            started,
+            status: None,
+            bytes_out: 0,
+        };
+    }
+
+    pub fn parse_error(&self, error: &Error) {
+        let reason = match error.status_code() {
+            Some(status_code) => status_code.as_u16().to_string(),
+            None => "aborted".to_string(),
+        };
+        *self.registry.parse_errors.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    /// Everything counted so far in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let registry = &self.registry;
+        let mut output = String::new();
+
+        output.push_str("# HELP http_requests_total Requests answered, by route pattern, method and status.\n");
+        output.push_str("# TYPE http_requests_total counter\n");
+        for ((route, method, status), count) in registry.requests.lock().unwrap().iter() {
+            output.push_str(&format!(
+                "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}\n",
+                escape_label(route),
+                method,
+                status,
+                count
+            ));
+        }
+
+        output.push_str("# HELP http_request_duration_seconds Time from the first byte of a request to the last byte of its response.\n");
+        output.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for ((route, method), histogram) in registry.latencies.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape_label(route), method);
+            for (bound, count) in registry.buckets.iter().zip(histogram.counts.iter()) {
+                output.push_str(&format!(
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                    labels, bound, count
+                ));
+            }
+            output.push_str(&format!(
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n",
+                labels, histogram.count
+            ));
+            output.push_str(&format!("http_request_duration_seconds_sum{{{}}} {}\n", labels, histogram.sum));
+            output.push_str(&format!("http_request_duration_seconds_count{{{}}} {}\n", labels, histogram.count));
+        }
+
+        output.push_str("# HELP http_requests_in_flight Requests being handled or written right now.\n");
+        output.push_str("# TYPE http_requests_in_flight gauge\n");
+        output.push_str(&format!("http_requests_in_flight {}\n", registry.in_flight.load(Ordering::SeqCst)));
+
+        output.push_str("# HELP http_connections_open Client connections currently open.\n");
+        output.push_str("# TYPE http_connections_open gauge\n");
+        output.push_str(&format!("http_connections_open {}\n", registry.open_connections.load(Ordering::SeqCst)));
+
+        output.push_str("# HELP http_request_body_bytes_total Request body bytes received, after decoding.\n");
+        output.push_str("# TYPE http_request_body_bytes_total counter\n");
+        output.push_str(&format!("http_request_body_bytes_total {}\n", registry.bytes_in.load(Ordering::Relaxed)));
+
+        output.push_str("# HELP http_response_bytes_total Response bytes written to connections: whole HTTP/1 messages, HTTP/2 DATA payloads.\n");
+        output.push_str("# TYPE http_response_bytes_total counter\n");
+        output.push_str(&format!("http_response_bytes_total {}\n", registry.bytes_out.load(Ordering::Relaxed)));
+
+        output.push_str("# HELP http_parse_errors_total Requests that could not be read, by the status they were answered with.\n");
+        output.push_str("# TYPE http_parse_errors_total counter\n");
+        for (reason, count) in registry.parse_errors.lock().unwrap().iter() {
+            output.push_str(&format!("http_parse_errors_total{{reason=\"{}\"}} {}\n", reason, count));
+        }
+        return output;
+    }
+}
+
+pub struct ConnectionGuard {
+    registry: Arc<Registry>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tracks one request from when it began to arrive until it has been written.
+pub struct RequestTimer {
+    registry: Arc<Registry>,
+    route: String,
+    method: String,
+    started: Instant,
+    status: Option<u16>,
+    bytes_out: u64,
+}
+
+impl RequestTimer {
+    pub fn set_response(&mut self, response: &HttpResponse) {
+        self.status = Some(response.status().as_u16());
+    }
+
+    /// The bytes the connection took for the response, as counted while writing it.
+    pub fn set_bytes_sent(&mut self, bytes: u64) {
+        self.bytes_out = bytes;
+    }
+
+    /// Records the request once its response is out. Requests that never got
+    /// one, such as those whose handler failed, only leave the in-flight count.
+    pub fn finish(self) {
+        let status = match self.status {
+            Some(status) => status,
+            None => return,
+        };
+        let registry = &self.registry;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        *registry
+            .requests
+            .lock()
+            .unwrap()
+            .entry((self.route.clone(), self.method.clone(), status))
+            .or_insert(0) += 1;
+        let mut latencies = registry.latencies.lock().unwrap();
+        let histogram = latencies
+            .entry((self.route.clone(), self.method.clone()))
+            .or_insert_with(|| Histogram {
+                counts: vec![0; registry.buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        for (bound, count) in registry.buckets.iter().zip(histogram.counts.iter_mut()) {
+            if elapsed <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += elapsed;
+        histogram.count += 1;
+        registry.bytes_out.fetch_add(self.bytes_out, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        self.registry.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::error::Limit;
+    use crate::request::{context_from_parts, ParseError, RequestLimits};
+
+    fn context(method: &str, body: &[u8]) -> HTTPContext {
+        return context_from_parts(method, "HTTP/1.1", "/", vec![], body.to_vec(), &RequestLimits::default()).unwrap();
+    }
+
+    fn ago(millis: u64) -> Instant {
+        return Instant::now().checked_sub(Duration::from_millis(millis)).unwrap();
+    }
+
+    fn respond(timer: &mut RequestTimer, status: http::StatusCode) {
+        let mut response = HttpResponse::new();
+        response.set_status(status);
+        timer.set_response(&response);
+    }
+
+    fn lines_starting(output: &str, prefix: &str) -> Vec<String> {
+        return output
+            .lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(|line| line.to_string())
+            .collect();
+    }
+
+    #[test]
+    fn renders_cumulative_latency_buckets() {
+        let metrics = Metrics::with_buckets(&[0.1, 2.0, 10.0]);
+        for (millis, status) in [(500, http::StatusCode::OK), (5000, http::StatusCode::NOT_FOUND)] {
+            let mut timer = metrics.request_started(Some("/users/:id"), &context("GET", b""), ago(millis));
+            respond(&mut timer, status);
+            timer.finish();
+        }
+
+        let output = metrics.render();
+        assert_eq!(
+            lines_starting(&output, "http_request_duration_seconds_bucket"),
+            vec![
+                "http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"0.1\"} 0",
+                "http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"2\"} 1",
+                "http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"10\"} 2",
+                "http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"+Inf\"} 2",
+            ]
+        );
+        assert_eq!(
+            lines_starting(&output, "http_request_duration_seconds_count"),
+            vec!["http_request_duration_seconds_count{route=\"/users/:id\",method=\"GET\"} 2"]
+        );
+        let sum = lines_starting(&output, "http_request_duration_seconds_sum{route=\"/users/:id\",method=\"GET\"} ");
+        let sum: f64 = sum[0].rsplit_once(" ").unwrap().1.parse().unwrap();
+        assert!((5.5..7.0).contains(&sum), "{}", sum);
+        assert_eq!(
+            lines_starting(&output, "http_requests_total{"),
+            vec![
+                "http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"200\"} 1",
+                "http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"404\"} 1",
+            ]
+        );
+        assert!(output.contains("# TYPE http_request_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn labels_unmatched_routes_and_escapes_labels() {
+        let metrics = Metrics::new();
+        let mut timer = metrics.request_started(None, &context("GET", b""), Instant::now());
+        respond(&mut timer, http::StatusCode::NOT_FOUND);
+        timer.finish();
+        let mut timer = metrics.request_started(Some("/say/\"hi\"\\\n"), &context("GET", b""), Instant::now());
+        respond(&mut timer, http::StatusCode::OK);
+        timer.finish();
+
+        let output = metrics.render();
+        assert!(output.contains("http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1\n"));
+        assert!(output.contains("http_requests_total{route=\"/say/\\\"hi\\\"\\\\\\n\",method=\"GET\",status=\"200\"} 1\n"));
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn counts_in_flight_requests_and_open_connections() {
+        let metrics = Metrics::new();
+        let first = metrics.connection_opened();
+        let second = metrics.connection_opened();
+        let mut timer = metrics.request_started(Some("/echo"), &context("POST", b"hello"), Instant::now());
+        let unanswered = metrics.request_started(Some("/echo"), &context("POST", b""), Instant::now());
+        let output = metrics.render();
+        assert!(output.contains("\nhttp_requests_in_flight 2\n"));
+        assert!(output.contains("\nhttp_connections_open 2\n"));
+        assert!(output.contains("\nhttp_request_body_bytes_total 5\n"));
+
+        respond(&mut timer, http::StatusCode::OK);
+        timer.set_bytes_sent(120);
+        timer.finish();
+        // A request without a response leaves the in-flight count but is not recorded.
+        unanswered.finish();
+        drop(first);
+        let output = metrics.render();
+        assert!(output.contains("\nhttp_requests_in_flight 0\n"));
+        assert!(output.contains("\nhttp_connections_open 1\n"));
+        assert!(output.contains("\nhttp_response_bytes_total 120\n"));
+        assert_eq!(lines_starting(&output, "http_requests_total{").len(), 1);
+        drop(second);
+        assert!(metrics.clone().render().contains("\nhttp_connections_open 0\n"));
+    }
+
+    #[test]
+    fn counts_parse_errors_by_status() {
+        let metrics = Metrics::new();
+        metrics.parse_error(&Error::Parse(ParseError::Malformed));
+        metrics.parse_error(&Error::Parse(ParseError::Malformed));
+        metrics.parse_error(&Error::LimitExceeded(Limit::Body));
+        metrics.parse_error(&Error::Io(std::io::ErrorKind::ConnectionReset.into()));
+        assert_eq!(
+            lines_starting(&metrics.render(), "http_parse_errors_total{"),
+            vec![
+                "http_parse_errors_total{reason=\"400\"} 2",
+                "http_parse_errors_total{reason=\"413\"} 1",
+                "http_parse_errors_total{reason=\"aborted\"} 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use http::{header, StatusCode};
+
+use crate::request::parse_quality_values;
+use crate::response::HttpResponse;
+use crate::route::HTTPRequest;
+
+/// Ratings from an `Accept`-style header, best first. Equal ratings keep
+/// the order the client listed them in.
+fn sorted_by_quality(header: Option<&str>) -> Vec<(String, f32)> {
+    let mut ratings = match header {
+        Some(header) => parse_quality_values(header),
+        None => vec![],
+    };
+    ratings.sort_by(|a, b| b.1.total_cmp(&a.1));
+    return ratings;
+}
+
+/// How closely a media range such as `text/*` covers `media_type`, with
+/// higher being more specific, or `None` if it does not cover it at all.
+fn media_range_specificity(range: &str, media_type: &str) -> Option<u8> {
+    let (range_type, range_subtype) = range.split_once("/")?;
+    let (media_type, media_subtype) = media_type.split_once("/")?;
+    if range_type == "*" && range_subtype == "*" {
+        return Some(0);
+    }
+    if !range_type.eq_ignore_ascii_case(media_type) {
+        return None;
+    }
+    if range_subtype == "*" {
+        return Some(1);
+    }
+    if range_subtype.eq_ignore_ascii_case(media_subtype) {
+        return Some(2);
+    }
+    return None;
+}
+
+/// Basic filtering from RFC 4647: `en` covers `en-GB`, `*` covers everything.
+fn language_range_specificity(range: &str, language: &str) -> Option<u8> {
+    if range == "*" {
+        return Some(0);
+    }
+    if range.eq_ignore_ascii_case(language) {
+        return Some(2);
+    }
+    let is_prefix = language.len() > range.len()
+        && language.as_bytes()[range.len()] == b'-'
+        && language[..range.len()].eq_ignore_ascii_case(range);
+    if is_prefix {
+        return Some(1);
+    }
+    return None;
+}
+
+fn charset_specificity(range: &str, charset: &str) -> Option<u8> {
+    if range == "*" {
+        return Some(0);
+    }
+    if range.eq_ignore_ascii_case(charset) {
+        return Some(1);
+    }
+    return None;
+}
+
+/// Picks the entry of `available` the client rates highest, taking each
+/// entry's rating from the most specific range that covers it. Ties go to
+/// whichever comes first in `available`. A missing header accepts anything.
+fn negotiate<'a>(
+    header: Option<&str>,
+    available: &[&'a str],
+    specificity: fn(&str, &str) -> Option<u8>,
+) -> Option<&'a str> {
+    let ratings = match header {
+        Some(header) => parse_quality_values(header),
+        None => return available.first().copied(),
+    };
+
+    let mut best: Option<(&'a str, f32)> = None;
+    for &candidate in available.iter() {
+        let quality = ratings
+            .iter()
+            .filter_map(|(range, quality)| {
+                specificity(range, candidate).map(|specificity| (specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, quality)| quality)
+            .unwrap_or(0.0);
+        let is_better = match best {
+            Some((_, best_quality)) => quality > best_quality,
+            None => quality > 0.0,
+        };
+        if is_better {
+            best = Some((candidate, quality));
+        }
+    }
+    return best.map(|(candidate, _)| candidate);
+}
+
+/// The media type out of `available` that suits an `Accept` header best,
+/// for when there is no `HTTPRequest` to ask.
+pub fn negotiate_media_type<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    return negotiate(accept, available, media_range_specificity);
+}
+
+impl HTTPRequest {
+    /// Media ranges from `Accept`, best first.
+    pub fn accepted_media_types(&self) -> Vec<(String, f32)> {
+        return sorted_by_quality(self.context.get_header("accept"));
+    }
+
+    /// Language ranges from `Accept-Language`, best first.
+    pub fn accepted_languages(&self) -> Vec<(String, f32)> {
+        return sorted_by_quality(self.context.get_header("accept-language"));
+    }
+
+    /// Charsets from `Accept-Charset`, best first.
+    pub fn accepted_charsets(&self) -> Vec<(String, f32)> {
+        return sorted_by_quality(self.context.get_header("accept-charset"));
+    }
+
+    /// The media type out of `available` that suits the client's `Accept`
+    /// best, e.g. `negotiate(&["application/json", "text/html"])`.
+    /// `None` means none of them is acceptable, see `not_acceptable`.
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        return negotiate_media_type(self.context.get_header("accept"), available);
+    }
+
+    /// The language tag out of `available` that suits `Accept-Language` best.
+    pub fn negotiate_language<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        return negotiate(
+            self.context.get_header("accept-language"),
+            available,
+            language_range_specificity,
+        );
+    }
+
+    /// The charset out of `available` that suits `Accept-Charset` best.
+    pub fn negotiate_charset<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        return negotiate(
+            self.context.get_header("accept-charset"),
+            available,
+            charset_specificity,
+        );
+    }
+}
+
+/// A 406 listing the media types the handler could have produced.
+pub fn not_acceptable(available: &[&str]) -> HttpResponse {
+    let mut body = String::from("Not Acceptable. Available representations:\n");
+    for media_type in available.iter() {
+        body.push_str(&format!("{}\n", media_type));
+    }
+    let mut response = HttpResponse::new();
+    response
+        .set_status(StatusCode::NOT_ACCEPTABLE)
+        .set_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .set_header(header::VARY, "Accept")
+        .set_body(&body);
+    return response;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{context_from_parts, HTTPHeader, RequestLimits};
+
+    fn request(headers: &[(&str, &str)]) -> HTTPRequest {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| HTTPHeader(name.to_string(), value.to_string()))
+            .collect();
+        let context = context_from_parts("GET", "HTTP/1.1", "/", headers, vec![], &RequestLimits::default());
+        return HTTPRequest::new(context.unwrap());
+    }
+
+    const AVAILABLE: [&str; 2] = ["application/json", "text/html"];
+
+    #[test]
+    fn picks_the_media_type_with_the_highest_quality() {
+        let cases = [
+            (None, Some("application/json")),
+            (Some("text/html"), Some("text/html")),
+            (Some("text/html;q=0.5, application/json;q=0.9"), Some("application/json")),
+            (Some("*/*;q=0.1, text/*"), Some("text/html")),
+            // The more specific range decides, even when it rates lower.
+            (Some("*/*, application/json;q=0.2"), Some("text/html")),
+            // Ties go to the server's order.
+            (Some("text/html, application/json"), Some("application/json")),
+            (Some("TEXT/HTML;level=1;q=0.3"), Some("text/html")),
+            (Some("text/html;q=0, */*"), Some("application/json")),
+            (Some("image/png"), None),
+            (Some("*/*;q=0"), None),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(negotiate_media_type(accept, &AVAILABLE), expected, "{:?}", accept);
+        }
+    }
+
+    #[test]
+    fn sorts_ratings_best_first() {
+        let request = request(&[("Accept", "text/plain;q=0.2, text/html, application/json;q=0.2, x/y;q=bad")]);
+        assert_eq!(
+            request.accepted_media_types(),
+            vec![
+                ("text/html".to_string(), 1.0),
+                ("x/y".to_string(), 1.0),
+                ("text/plain".to_string(), 0.2),
+                ("application/json".to_string(), 0.2),
+            ]
+        );
+    }
+
+    #[test]
+    fn negotiates_languages_and_charsets() {
+        let request = request(&[("Accept-Language", "en;q=0.8, de-CH, *;q=0.1"), ("Accept-Charset", "utf-8, *;q=0")]);
+        assert_eq!(request.negotiate_language(&["fr", "en-GB", "de"]), Some("en-GB"));
+        assert_eq!(request.negotiate_language(&["fr", "de-CH"]), Some("de-CH"));
+        assert_eq!(request.negotiate_language(&["fr"]), Some("fr"));
+        assert_eq!(request.negotiate_charset(&["iso-8859-1", "UTF-8"]), Some("UTF-8"));
+        assert_eq!(request.negotiate_charset(&["iso-8859-1"]), None);
+    }
+
+    #[test]
+    fn answers_unacceptable_requests_with_406() {
+        let request = request(&[("Accept", "image/*")]);
+        assert_eq!(request.negotiate(&AVAILABLE), None);
+        let response = not_acceptable(&AVAILABLE);
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(response.get_header(&header::VARY), Some("Accept"));
+        let body = match response.body() {
+            Some(crate::response::ResponseBody::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => panic!("expected a buffered body"),
+        };
+        assert!(body.contains("application/json\ntext/html\n"));
+    }
+}
@@ -0,0 +1,335 @@
+use std::io;
+
+use http::{header, StatusCode};
+
+use crate::conditional::is_weak;
+use crate::response::{HttpResponse, ResponseBody};
+use crate::route::HTTPRequest;
+
+/// More ranges than this are answered with the full representation, so a
+/// request cannot make us repeat the same bytes over and over.
+const MAX_RANGES: usize = 16;
+
+enum ByteRange {
+    Bounded(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// The inclusive span within a representation of `total` bytes, if satisfiable.
+    fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        return match *self {
+            ByteRange::Bounded(start, end) if start < total => Some((start, end.min(total - 1))),
+            ByteRange::From(start) if start < total => Some((start, total - 1)),
+            ByteRange::Suffix(length) if length > 0 && total > 0 => {
+                Some((total - length.min(total), total - 1))
+            }
+            _ => None,
+        };
+    }
+}
+
+/// Parses `bytes=0-99, 200-, -500`. Returns `None` for anything malformed,
+/// which means the header is ignored.
+fn parse_range_header(header: &str) -> Option<Vec<ByteRange>> {
+    let (unit, raw_ranges) = header.split_once("=")?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = vec![];
+    for raw_range in raw_ranges.split(",") {
+        let raw_range = raw_range.trim();
+        if raw_range.is_empty() {
+            continue;
+        }
+        let (start, end) = raw_range.split_once("-")?;
+        let (start, end) = (start.trim(), end.trim());
+        let range = match (start.is_empty(), end.is_empty()) {
+            (true, true) => return None,
+            (true, false) => ByteRange::Suffix(end.parse().ok()?),
+            (false, true) => ByteRange::From(start.parse().ok()?),
+            (false, false) => {
+                let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+                if end < start {
+                    return None;
+                }
+                ByteRange::Bounded(start, end)
+            }
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() {
+        return None;
+    }
+    return Some(ranges);
+}
+
+/// `If-Range` only lets the range through when the representation is unchanged,
+/// judged by a strong `ETag` or an exact `Last-Modified` date.
+fn if_range_matches(request: &HTTPRequest, response: &HttpResponse) -> bool {
+    let if_range = match request.context.get_header("if-range") {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match response.get_header(&header::ETAG) {
+            Some(etag) => !is_weak(if_range) && !is_weak(etag) && etag.trim() == if_range,
+            None => false,
+        };
+    }
+    let last_modified = response
+        .get_header(&header::LAST_MODIFIED)
+        .and_then(|last_modified| httpdate::parse_http_date(last_modified).ok());
+    return match (last_modified, httpdate::parse_http_date(if_range)) {
+        (Some(last_modified), Ok(date)) => last_modified == date,
+        _ => false,
+    };
+}
+
+fn slice(body: &ResponseBody, start: u64, length: u64) -> io::Result<ResponseBody> {
+    return match body {
+        ResponseBody::Bytes(bytes) => Ok(ResponseBody::Bytes(
+            bytes[start as usize..(start + length) as usize].to_vec(),
+        )),
+        ResponseBody::File { file, offset, .. } => Ok(ResponseBody::File {
+            file: file.try_clone()?,
+            offset: offset + start,
+            length,
+        }),
+        ResponseBody::Parts(_) | ResponseBody::Encoded { .. } | ResponseBody::Events(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cannot take a range of a composite body",
+        )),
+    };
+}
+
+fn generate_boundary() -> String {
+    let mut bytes = [0u8; 12];
+    let _ = getrandom::getrandom(&mut bytes);
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn multipart_body(
+    body: &ResponseBody,
+    spans: &[(u64, u64)],
+    content_type: Option<&str>,
+    boundary: &str,
+) -> io::Result<ResponseBody> {
+    let total = body.len();
+    let mut parts = vec![];
+    for &(start, end) in spans.iter() {
+        let mut part_header = format!("--{}\r\n", boundary);
+        if let Some(content_type) = content_type {
+            part_header.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        part_header.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, total));
+        parts.push(ResponseBody::Bytes(part_header.into_bytes()));
+        parts.push(slice(body, start, end - start + 1)?);
+        parts.push(ResponseBody::Bytes(b"\r\n".to_vec()));
+    }
+    parts.push(ResponseBody::Bytes(format!("--{}--\r\n", boundary).into_bytes()));
+    return Ok(ResponseBody::Parts(parts));
+}
+
+fn range_not_satisfiable(total: u64) -> HttpResponse {
+    let mut response = HttpResponse::new();
+    response
+        .set_status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .set_header(header::CONTENT_RANGE, &format!("bytes */{}", total));
+    return response;
+}
+
+/// Ranges are only served for representations a client can resume safely:
+/// ones with a validator, or whose handler opted in with `Accept-Ranges: bytes`.
+/// A handler can opt out with `Accept-Ranges: none`.
+fn accepts_ranges(response: &mut HttpResponse) -> bool {
+    let has_validator = response.get_header(&header::ETAG).is_some()
+        || response.get_header(&header::LAST_MODIFIED).is_some();
+    return match response.get_header(&header::ACCEPT_RANGES) {
+        Some(accept_ranges) => accept_ranges.trim().eq_ignore_ascii_case("bytes"),
+        None if has_validator => {
+            response.set_header(header::ACCEPT_RANGES, "bytes");
+            true
+        }
+        None => false,
+    };
+}
+
+/// Narrows a full 200 response down to the ranges the client asked for,
+/// answering with 206, or 416 when none of them can be satisfied.
+pub fn apply_range(request: &HTTPRequest, response: HttpResponse) -> HttpResponse {
+    let mut response = response;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let total = match response.body() {
+        Some(body @ (ResponseBody::Bytes(_) | ResponseBody::File { .. })) => body.len(),
+        _ => return response,
+    };
+    if !accepts_ranges(&mut response) {
+        return response;
+    }
+
+    let ranges = match request.context.get_header("range").and_then(parse_range_header) {
+        Some(ranges) if ranges.len() <= MAX_RANGES => ranges,
+        _ => return response,
+    };
+    if !if_range_matches(request, &response) {
+        return response;
+    }
+    let spans: Vec<(u64, u64)> = ranges.iter().filter_map(|range| range.resolve(total)).collect();
+    if spans.is_empty() {
+        return range_not_satisfiable(total);
+    }
+
+    let body = response.body().unwrap();
+    if spans.len() == 1 {
+        let (start, end) = spans[0];
+        let body = match slice(body, start, end - start + 1) {
+            Ok(body) => body,
+            Err(_) => return response,
+        };
+        response
+            .set_status(StatusCode::PARTIAL_CONTENT)
+            .set_header(header::CONTENT_RANGE, &format!("bytes {}-{}/{}", start, end, total))
+            .set_response_body(body);
+        return response;
+    }
+
+    let boundary = generate_boundary();
+    let content_type = response.get_header(&header::CONTENT_TYPE);
+    let body = match multipart_body(body, &spans, content_type, &boundary) {
+        Ok(body) => body,
+        Err(_) => return response,
+    };
+    response
+        .set_status(StatusCode::PARTIAL_CONTENT)
+        .remove_header(&header::CONTENT_TYPE)
+        .set_header(
+            header::CONTENT_TYPE,
+            &format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .set_response_body(body);
+    return response;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{context_from_parts, HTTPHeader, RequestLimits};
+
+    const BODY: &str = "0123456789abcdefghij";
+    const ETAG: &str = "\"v1\"";
+
+    fn request(headers: &[(&str, &str)]) -> HTTPRequest {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| HTTPHeader(name.to_string(), value.to_string()))
+            .collect();
+        let context = context_from_parts("GET", "HTTP/1.1", "/doc", headers, vec![], &RequestLimits::default());
+        return HTTPRequest::new(context.unwrap());
+    }
+
+    fn document(etag: Option<&str>) -> HttpResponse {
+        let mut response = HttpResponse::new();
+        response.set_header(header::CONTENT_TYPE, "text/plain").set_body(BODY);
+        if let Some(etag) = etag {
+            response.set_header(header::ETAG, etag);
+        }
+        return response;
+    }
+
+    fn body(response: &HttpResponse) -> String {
+        return match response.body() {
+            Some(ResponseBody::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            Some(ResponseBody::Parts(parts)) => parts.iter().map(body_of).collect(),
+            _ => panic!("unexpected body"),
+        };
+    }
+
+    fn body_of(part: &ResponseBody) -> String {
+        return match part {
+            ResponseBody::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => panic!("unexpected part"),
+        };
+    }
+
+    #[test]
+    fn serves_a_single_range() {
+        for (range, expected, content_range) in [
+            ("bytes=0-4", "01234", "bytes 0-4/20"),
+            ("bytes=15-", "fghij", "bytes 15-19/20"),
+            ("bytes=-3", "hij", "bytes 17-19/20"),
+            ("bytes=18-99", "ij", "bytes 18-19/20"),
+        ] {
+            let response = apply_range(&request(&[("Range", range)]), document(Some(ETAG)));
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(response.get_header(&header::CONTENT_RANGE), Some(content_range));
+            assert_eq!(body(&response), expected);
+        }
+    }
+
+    #[test]
+    fn serves_multiple_ranges_as_multipart() {
+        let response = apply_range(&request(&[("Range", "bytes=0-1, -2")]), document(Some(ETAG)));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.get_header(&header::CONTENT_TYPE).unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        assert_eq!(
+            body(&response),
+            format!(
+                "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/20\r\n\r\n01\r\n\
+                 --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 18-19/20\r\n\r\nij\r\n--{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+
+    #[test]
+    fn answers_unsatisfiable_ranges_with_416() {
+        let response = apply_range(&request(&[("Range", "bytes=20-, 30-40")]), document(Some(ETAG)));
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.get_header(&header::CONTENT_RANGE), Some("bytes */20"));
+    }
+
+    #[test]
+    fn ignores_malformed_and_excessive_ranges() {
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        for range in ["bytes=5-1", "items=0-1", "bytes=-", "bytes=a-b", too_many.as_str()] {
+            let response = apply_range(&request(&[("Range", range)]), document(Some(ETAG)));
+            assert_eq!(response.status(), StatusCode::OK, "{}", range);
+            assert_eq!(body(&response), BODY);
+        }
+    }
+
+    #[test]
+    fn only_serves_ranges_of_representations_with_validators_or_opt_in() {
+        let response = apply_range(&request(&[("Range", "bytes=0-1")]), document(None));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.get_header(&header::ACCEPT_RANGES), None);
+
+        let response = apply_range(&request(&[]), document(Some(ETAG)));
+        assert_eq!(response.get_header(&header::ACCEPT_RANGES), Some("bytes"));
+
+        let mut opted_in = document(None);
+        opted_in.set_header(header::ACCEPT_RANGES, "bytes");
+        let response = apply_range(&request(&[("Range", "bytes=0-1")]), opted_in);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+        let mut opted_out = document(Some(ETAG));
+        opted_out.set_header(header::ACCEPT_RANGES, "none");
+        let response = apply_range(&request(&[("Range", "bytes=0-1")]), opted_out);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn serves_the_full_representation_when_if_range_does_not_match() {
+        let headers = [("Range", "bytes=0-1"), ("If-Range", "\"v0\"")];
+        assert_eq!(apply_range(&request(&headers), document(Some(ETAG))).status(), StatusCode::OK);
+        let headers = [("Range", "bytes=0-1"), ("If-Range", ETAG)];
+        assert_eq!(apply_range(&request(&headers), document(Some(ETAG))).status(), StatusCode::PARTIAL_CONTENT);
+        let headers = [("Range", "bytes=0-1"), ("If-Range", "W/\"v1\"")];
+        assert_eq!(apply_range(&request(&headers), document(Some("W/\"v1\""))).status(), StatusCode::OK);
+    }
+}
@@ -1,10 +1,18 @@
-use std::io::{BufRead, BufReader, Read};
-use std::net::TcpStream;
-use std::os::macos::raw;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use http::Version;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
 
-#[derive(Debug, PartialEq)]
+use crate::error::{Error, Limit, Phase};
+use crate::stream::{is_timeout, DeadlineStream};
+use crate::tls::TlsInfo;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum HTTPMethod {
     POST,
     GET,
@@ -14,16 +22,17 @@ pub enum HTTPMethod {
     OPTIONS,
 }
 
-impl ToString for HTTPMethod {
-    fn to_string(&self) -> String {
-        return match self {
-            HTTPMethod::POST => "POST".to_string(),
-            HTTPMethod::GET => "GET".to_string(),
-            HTTPMethod::PATCH => "PATCH".to_string(),
-            HTTPMethod::DELETE => "DELETE".to_string(),
-            HTTPMethod::PUT => "PUT".to_string(),
-            HTTPMethod::OPTIONS => "OPTIONS".to_string(),
+impl fmt::Display for HTTPMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HTTPMethod::POST => "POST",
+            HTTPMethod::GET => "GET",
+            HTTPMethod::PATCH => "PATCH",
+            HTTPMethod::DELETE => "DELETE",
+            HTTPMethod::PUT => "PUT",
+            HTTPMethod::OPTIONS => "OPTIONS",
         };
+        return f.write_str(name);
     }
 }
 
@@ -44,10 +53,10 @@ impl From<&str> for HTTPMethod {
 #[derive(Debug, Clone)]
 pub struct HTTPHeader(pub String, pub String);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HTTPQuery(String, String);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HTTPContext {
     pub method: HTTPMethod,
     pub http_version: String,
@@ -56,30 +65,152 @@ pub struct HTTPContext {
     pub queries: Vec<HTTPQuery>,
     pub body: Option<String>,
     pub body_bytes: Option<Vec<u8>>,
+    /// Set by the server for requests that arrived over HTTPS.
+    pub tls: Option<TlsInfo>,
+    /// Set by the server to the address of the client on the other end.
+    pub peer_addr: Option<SocketAddr>,
+    /// Set by `RequestIdMiddleware`.
+    pub request_id: Option<String>,
+}
+
+impl HTTPContext {
+    /// Looks up the first header with the given name, ignoring case.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|header| header.0.eq_ignore_ascii_case(name))
+            .map(|header| header.1.as_str());
+    }
+
+    /// The request target as sent, the path followed by any query.
+    pub fn target(&self) -> String {
+        if self.queries.is_empty() {
+            return self.path.clone();
+        }
+        let queries: Vec<String> = self
+            .queries
+            .iter()
+            .map(|query| format!("{}={}", query.0, query.1))
+            .collect();
+        return format!("{}?{}", self.path, queries.join("&"));
+    }
+
+    /// Whether a comma separated header such as `Connection` lists `token`, ignoring case.
+    pub fn header_has_token(&self, name: &str, token: &str) -> bool {
+        return self
+            .headers
+            .iter()
+            .filter(|header| header.0.eq_ignore_ascii_case(name))
+            .flat_map(|header| header.1.split(","))
+            .any(|option| option.trim().eq_ignore_ascii_case(token));
+    }
+
+    /// Whether the client asked for the connection to be closed after this request.
+    pub fn wants_close(&self) -> bool {
+        return self.header_has_token("connection", "close");
+    }
+
+    pub fn version(&self) -> Version {
+        return match self.http_version.as_str() {
+            "HTTP/1.0" => Version::HTTP_10,
+            "HTTP/2.0" => Version::HTTP_2,
+            _ => Version::HTTP_11,
+        };
+    }
+
+    /// Whether the connection may carry another request after this one.
+    /// HTTP/1.0 closes unless the client asked for `keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        if self.wants_close() {
+            return false;
+        }
+        if self.version() == Version::HTTP_10 {
+            return self.header_has_token("connection", "keep-alive");
+        }
+        return true;
+    }
+}
+
+/// Parses a list such as `gzip;q=0.8, br, *;q=0` into values and their
+/// quality, dropping any other parameters. A missing `q` counts as 1.
+pub fn parse_quality_values(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(",")
+        .filter_map(|item| {
+            let mut parts = item.split(";");
+            let value = parts.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+            let quality = parts
+                .filter_map(|parameter| parameter.split_once("="))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, quality)| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0)
+                .clamp(0.0, 1.0);
+            Some((value.to_string(), quality))
+        })
+        .collect()
+}
+
+/// Upper bounds applied while reading a request off the wire.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Maximum length of the request line in bytes, answered with 414.
+    pub max_request_line: usize,
+    /// Maximum size of all header lines combined, answered with 431.
+    pub max_header_bytes: usize,
+    /// Maximum number of header lines, answered with 431.
+    pub max_header_count: usize,
+    /// Maximum accepted `Content-Length`, answered with 413.
+    pub max_body_bytes: usize,
+    /// Maximum size of a `Content-Encoding` compressed body once decoded, answered with 413.
+    pub max_decoded_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        return RequestLimits {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_header_count: 100,
+            max_body_bytes: 10 * 1024 * 1024,
+            max_decoded_body_bytes: 10 * 1024 * 1024,
+        };
+    }
+}
+
+/// Why a request could not be understood.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("Could not read request")]
+    Malformed,
+    #[error("Unsupported request content encoding")]
+    UnsupportedEncoding,
+    #[error("Unsupported HTTP version")]
+    UnsupportedVersion,
+    #[error("Unsupported expectation")]
+    ExpectationFailed,
+    #[error("Unsupported request transfer encoding")]
+    UnsupportedTransferEncoding,
 }
 
 fn to_header(pair: Option<(&str, &str)>) -> Option<HTTPHeader> {
-    return match pair {
-        Some((key, value)) => Some(HTTPHeader(key.trim().to_string(), value.trim().to_string())),
-        None => None,
-    };
+    return pair.map(|(key, value)| HTTPHeader(key.trim().to_string(), value.trim().to_string()));
 }
 
 fn parse_headers(raw_headers: std::slice::Iter<'_, &str>) -> Vec<HTTPHeader> {
     raw_headers
         .map(|&raw_header| raw_header.split_once(":"))
-        .map(to_header)
-        .filter(|header| header.is_some())
-        .map(|header| header.unwrap())
+        .filter_map(to_header)
         .collect()
 }
 
 fn parse_queries(raw_query: Vec<&str>) -> Vec<HTTPQuery> {
     raw_query
         .iter()
-        .map(|&q| q.split_once("="))
-        .filter(|&x| x.is_some())
-        .map(|x| x.unwrap())
+        .filter_map(|&q| q.split_once("="))
         .map(|(key, value)| HTTPQuery(key.to_string(), value.to_string()))
         .collect()
 }
@@ -94,13 +225,22 @@ fn parse_path(raw_path: &str) -> (&str, Vec<&str>) {
     return (parts[0], queries);
 }
 
-fn build_request(raw_headers: &String, raw_body_bytes: Vec<u8>) -> Option<HTTPContext> {
-    let request_lines: Vec<_> = raw_headers.split("\r\n").collect();
-    let start = request_lines.first();
-    if start.is_none() {
-        return None;
+fn to_body(raw_body_bytes: Vec<u8>) -> (Option<String>, Option<Vec<u8>>) {
+    let mut body = None;
+    let raw_body = String::from_utf8_lossy(&raw_body_bytes).to_string();
+    if !raw_body.trim().is_empty() {
+        body = Some(raw_body.trim().to_string());
     }
-    let start_line = *start.unwrap();
+    let mut body_bytes = None;
+    if !raw_body_bytes.is_empty() {
+        body_bytes = Some(raw_body_bytes);
+    }
+    return (body, body_bytes);
+}
+
+fn build_request(raw_headers: &str, raw_body_bytes: Vec<u8>) -> Option<HTTPContext> {
+    let request_lines: Vec<_> = raw_headers.split("\r\n").collect();
+    let start_line = *request_lines.first()?;
     let start_parts: Vec<&str> = start_line.split(" ").collect();
     if start_parts.len() < 3 {
         return None;
@@ -111,124 +251,729 @@ fn build_request(raw_headers: &String, raw_body_bytes: Vec<u8>) -> Option<HTTPCo
     let queries = parse_queries(raw_queries);
     let http_version = start_parts[2];
     let headers = parse_headers(request_lines[1..].iter());
-    let mut body = None;
-    let raw_body = String::from_utf8_lossy(&raw_body_bytes).to_string();
-    if raw_body.trim().len() > 0 {
-        body = Some(raw_body.trim().to_string());
-    }
-    let mut body_bytes = None;
-    if raw_body_bytes.len() > 0 {
-        body_bytes = Some(raw_body_bytes);
-    }
+    let (body, body_bytes) = to_body(raw_body_bytes);
     return Some(HTTPContext {
         method: method.into(),
         http_version: http_version.to_string(),
         headers,
         queries,
         body,
-        body_bytes: body_bytes,
+        body_bytes,
         path: path.to_string(),
+        tls: None,
+        peer_addr: None,
+        request_id: None,
     });
 }
 
-fn get_content_length(header: &str) -> usize {
-    return match header.split_once(":") {
-        Some((_, content_length)) => content_length.trim().parse().unwrap_or_default(),
-        None => 0,
+/// Builds a context from a request that arrived already taken apart, such as an
+/// HTTP/2 stream, decoding the body the same way `parse_stream` does.
+pub fn context_from_parts(
+    method: &str,
+    http_version: &str,
+    raw_path: &str,
+    headers: Vec<HTTPHeader>,
+    raw_body_bytes: Vec<u8>,
+    limits: &RequestLimits,
+) -> Result<HTTPContext, Error> {
+    let (path, raw_queries) = parse_path(raw_path);
+    let (body, body_bytes) = to_body(raw_body_bytes);
+    let mut context = HTTPContext {
+        method: method.into(),
+        http_version: http_version.to_string(),
+        headers,
+        queries: parse_queries(raw_queries),
+        body,
+        body_bytes,
+        path: path.to_string(),
+        tls: None,
+        peer_addr: None,
+        request_id: None,
     };
+    decode_body(&mut context, limits)?;
+    return Ok(context);
+}
+
+/// Content codings we can undo on request bodies, as advertised on a 415.
+pub const SUPPORTED_CONTENT_ENCODINGS: &str = "gzip, deflate, br";
+
+/// zlib streams start with a two byte header whose value is a multiple of 31.
+fn is_zlib_stream(bytes: &[u8]) -> bool {
+    return bytes.len() >= 2
+        && bytes[0] & 0x0f == 8
+        && ((bytes[0] as u16) << 8 | bytes[1] as u16).is_multiple_of(31);
+}
+
+/// Undoes a single content coding. Reading stops one byte past `limit`,
+/// so a small compressed body cannot expand into unbounded memory.
+fn decode(encoding: &str, bytes: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(bytes)),
+        // "deflate" is meant to be zlib wrapped, but some clients send a raw stream.
+        "deflate" if is_zlib_stream(bytes) => Box::new(flate2::read::ZlibDecoder::new(bytes)),
+        "deflate" => Box::new(flate2::read::DeflateDecoder::new(bytes)),
+        "br" => Box::new(brotli::Decompressor::new(bytes, 4096)),
+        _ => return Err(Error::Parse(ParseError::UnsupportedEncoding)),
+    };
+    let mut decoded = vec![];
+    if decoder.take(limit as u64 + 1).read_to_end(&mut decoded).is_err() {
+        return Err(Error::Parse(ParseError::Malformed));
+    }
+    if decoded.len() > limit {
+        return Err(Error::LimitExceeded(Limit::Body));
+    }
+    return Ok(decoded);
+}
+
+/// Replaces a `Content-Encoding` compressed body with its decoded form, so
+/// handlers always see the body as the client meant it.
+fn decode_body(context: &mut HTTPContext, limits: &RequestLimits) -> Result<(), Error> {
+    let encodings: Vec<String> = match context.get_header("content-encoding") {
+        Some(value) => value
+            .split(",")
+            .map(|encoding| encoding.trim().to_lowercase())
+            .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+            .collect(),
+        None => return Ok(()),
+    };
+    if encodings
+        .iter()
+        .any(|encoding| !matches!(encoding.as_str(), "gzip" | "x-gzip" | "deflate" | "br"))
+    {
+        return Err(Error::Parse(ParseError::UnsupportedEncoding));
+    }
+
+    let mut body = context.body_bytes.take().unwrap_or_default();
+    // Codings are listed in the order they were applied, so undo them backwards.
+    for encoding in encodings.iter().rev() {
+        if body.is_empty() {
+            break;
+        }
+        body = decode(encoding, &body, limits.max_decoded_body_bytes)?;
+    }
+    context.headers.retain(|header| {
+        !header.0.eq_ignore_ascii_case("content-encoding")
+            && !header.0.eq_ignore_ascii_case("content-length")
+    });
+    context
+        .headers
+        .push(HTTPHeader("Content-Length".to_string(), body.len().to_string()));
+    (context.body, context.body_bytes) = to_body(body);
+    return Ok(());
+}
+
+/// How the body following the head is delimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+/// What `check_head` found out before the body is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Head {
+    /// Whether the client waits for `100 Continue` before sending the body.
+    pub expects_continue: bool,
+    pub body_length: BodyLength,
+}
+
+fn header_values<'a>(raw_headers: &'a str, name: &str) -> Vec<&'a str> {
+    return raw_headers
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(":"))
+        .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+        .collect();
+}
+
+fn is_token(value: &str) -> bool {
+    return !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+}
+
+/// Works out how the body is framed. Anything that could be read two ways,
+/// such as a `Content-Length` next to `Transfer-Encoding` or conflicting
+/// lengths, is refused, since that is how requests get smuggled past a proxy.
+fn find_body_length(raw_headers: &str, version: &str) -> Result<BodyLength, Error> {
+    let transfer_encodings = header_values(raw_headers, "transfer-encoding");
+    let content_lengths = header_values(raw_headers, "content-length");
+    if !transfer_encodings.is_empty() {
+        if !content_lengths.is_empty() || version == "HTTP/1.0" {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+        let codings: Vec<String> = transfer_encodings
+            .iter()
+            .flat_map(|value| value.split(","))
+            .map(|coding| coding.trim().to_lowercase())
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        if codings == ["chunked"] {
+            return Ok(BodyLength::Chunked);
+        }
+        return Err(Error::Parse(ParseError::UnsupportedTransferEncoding));
+    }
+
+    let mut length = None;
+    for value in content_lengths.iter().flat_map(|value| value.split(",")) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+        let parsed = match value.parse::<usize>() {
+            Ok(parsed) => parsed,
+            Err(_) => return Err(Error::LimitExceeded(Limit::Body)),
+        };
+        if length.is_some_and(|length| length != parsed) {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+        length = Some(parsed);
+    }
+    return Ok(BodyLength::Fixed(length.unwrap_or(0)));
+}
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Checks the request line, the header names, the `Expect` header and the
+/// body framing before the body is read.
+pub fn check_head(raw_headers: &str) -> Result<Head, Error> {
+    let mut lines = raw_headers.lines();
+    let version = match lines.next().and_then(|line| line.split(" ").nth(2)) {
+        Some(version) => version,
+        None => return Err(Error::Parse(ParseError::Malformed)),
+    };
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => (),
+        version if version.starts_with("HTTP/") => return Err(Error::Parse(ParseError::UnsupportedVersion)),
+        _ => return Err(Error::Parse(ParseError::Malformed)),
+    }
+    // A name with whitespace around it would be skipped here but honoured
+    // by other servers, so the request is refused rather than guessed at.
+    for line in lines.clone() {
+        match line.split_once(":") {
+            Some((name, _)) if is_token(name) => (),
+            _ => return Err(Error::Parse(ParseError::Malformed)),
+        }
+    }
+    let body_length = find_body_length(raw_headers, version)?;
+
+    let expect = lines
+        .filter_map(|line| line.split_once(":"))
+        .find(|(name, _)| name.eq_ignore_ascii_case("expect"))
+        .map(|(_, value)| value.trim());
+    let expects_continue = match expect {
+        None => false,
+        // HTTP/1.0 clients cannot understand an interim response, so it is ignored for them.
+        Some(expect) if expect.eq_ignore_ascii_case("100-continue") => version == "HTTP/1.1",
+        Some(_) => return Err(Error::Parse(ParseError::ExpectationFailed)),
+    };
+    let has_body = match body_length {
+        BodyLength::Fixed(length) => length > 0,
+        BodyLength::Chunked => true,
+    };
+    return Ok(Head {
+        expects_continue: expects_continue && has_body,
+        body_length,
+    });
+}
+
+/// Longest chunk size line accepted, leaving room for chunk extensions.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Reads the hex size off a chunk size line, ignoring any extensions.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line.trim_end_matches(['\r', '\n']),
+        Err(_) => return Err(Error::Parse(ParseError::Malformed)),
+    };
+    let size = match line.split_once(";") {
+        Some((size, _)) => size.trim_end(),
+        None => line,
+    };
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::Parse(ParseError::Malformed));
+    }
+    return match usize::from_str_radix(size, 16) {
+        Ok(size) => Ok(size),
+        Err(_) => Err(Error::LimitExceeded(Limit::Body)),
+    };
+}
+
+fn is_line_end(line: &[u8]) -> bool {
+    return line == b"\r\n" || line == b"\n";
+}
+
+async fn read_chunked_async<TReader>(reader: &mut TReader, limits: &RequestLimits) -> Result<Vec<u8>, Error>
+where
+    TReader: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if read_line_limited_async(reader, &mut line, MAX_CHUNK_LINE).await?.is_none() {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() {
+            return Err(Error::LimitExceeded(Limit::Body));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        if let Err(e) = reader.read_exact(&mut body[start..]).await {
+            return Err(Error::Io(e));
+        }
+        line.clear();
+        read_line_limited_async(reader, &mut line, 2).await?;
+        if !is_line_end(&line) {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+    }
+    // Trailer fields are dropped, but bounded like the header section.
+    let mut trailer_bytes = 0;
+    loop {
+        let mut line = Vec::new();
+        match read_line_limited_async(reader, &mut line, limits.max_header_bytes - trailer_bytes).await? {
+            Some(_) if is_line_end(&line) => return Ok(body),
+            Some(_) if !line.ends_with(b"\n") => return Err(Error::Parse(ParseError::Malformed)),
+            Some(size) => trailer_bytes += size,
+            None => return Err(Error::LimitExceeded(Limit::Headers)),
+        }
+    }
 }
-async fn read_stream_async(stream: &mut tokio::net::TcpStream) -> Option<(String, Vec<u8>)>
+
+fn read_chunked<TReader>(reader: &mut TReader, limits: &RequestLimits) -> Result<Vec<u8>, Error>
+where
+    TReader: BufRead,
 {
-    let mut reader = tokio::io::BufReader::new(stream);
-    let mut raw_headers = String::new();
-    let mut content_length = 0;
-    const MIN_LINE_LENGTH: usize = 3;
+    let mut body = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if read_line_limited(reader, &mut line, MAX_CHUNK_LINE)?.is_none() {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() {
+            return Err(Error::LimitExceeded(Limit::Body));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        if let Err(e) = reader.read_exact(&mut body[start..]) {
+            return Err(read_error(e));
+        }
+        line.clear();
+        read_line_limited(reader, &mut line, 2)?;
+        if !is_line_end(&line) {
+            return Err(Error::Parse(ParseError::Malformed));
+        }
+    }
+    // Trailer fields are dropped, but bounded like the header section.
+    let mut trailer_bytes = 0;
+    loop {
+        let mut line = Vec::new();
+        match read_line_limited(reader, &mut line, limits.max_header_bytes - trailer_bytes)? {
+            Some(_) if is_line_end(&line) => return Ok(body),
+            Some(_) if !line.ends_with(b"\n") => return Err(Error::Parse(ParseError::Malformed)),
+            Some(size) => trailer_bytes += size,
+            None => return Err(Error::LimitExceeded(Limit::Headers)),
+        }
+    }
+}
 
+/// Once a chunked body has been read, the handler sees it with a length
+/// like any other body.
+fn unchunk_headers(context: &mut HTTPContext) {
+    context.headers.retain(|header| !header.0.eq_ignore_ascii_case("transfer-encoding"));
+    let length = context.body_bytes.as_ref().map(|body| body.len()).unwrap_or(0);
+    context
+        .headers
+        .push(HTTPHeader("Content-Length".to_string(), length.to_string()));
+}
+
+fn to_raw_headers(raw_head: Vec<u8>) -> Result<String, Error> {
+    return match String::from_utf8(raw_head) {
+        Ok(raw_headers) => Ok(raw_headers.trim().to_string()),
+        Err(_) => Err(Error::Parse(ParseError::Malformed)),
+    };
+}
+
+fn read_error(error: std::io::Error) -> Error {
+    if is_timeout(&error) {
+        return Error::Timeout(Phase::ReadingRequest);
+    }
+    return Error::Io(error);
+}
+
+const MIN_LINE_LENGTH: usize = 3;
+
+/// Appends a single line (including its `\n`) to `line`.
+/// Returns `None` when the line would grow beyond `limit` bytes.
+async fn read_line_limited_async<TReader>(
+    reader: &mut TReader,
+    line: &mut Vec<u8>,
+    limit: usize,
+) -> Result<Option<usize>, Error>
+where
+    TReader: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut read = 0;
     loop {
-        match reader.read_line(&mut raw_headers).await {
-            Ok(size) => {
-                if size < MIN_LINE_LENGTH {
-                    break;
-                }
-            }
-            Err(_) => return None,
+        let available = match reader.fill_buf().await {
+            Ok(available) => available,
+            Err(e) => return Err(Error::Io(e)),
+        };
+        if available.is_empty() {
+            return Ok(Some(read));
+        }
+        let (size, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => (index + 1, true),
+            None => (available.len(), false),
+        };
+        if read + size > limit {
+            return Ok(None);
+        }
+        line.extend_from_slice(&available[..size]);
+        reader.consume(size);
+        read += size;
+        if done {
+            return Ok(Some(read));
         }
     }
-    for line in raw_headers.lines() {
-        if line.to_lowercase().contains("content-length")  {
-            content_length = get_content_length(line);
+}
+
+/// Appends a single line (including its `\n`) to `line`.
+/// Returns `None` when the line would grow beyond `limit` bytes.
+fn read_line_limited<TReader>(
+    reader: &mut TReader,
+    line: &mut Vec<u8>,
+    limit: usize,
+) -> Result<Option<usize>, Error>
+where
+    TReader: BufRead,
+{
+    let mut read = 0;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) => return Err(read_error(e)),
+        };
+        if available.is_empty() {
+            return Ok(Some(read));
+        }
+        let (size, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => (index + 1, true),
+            None => (available.len(), false),
+        };
+        if read + size > limit {
+            return Ok(None);
+        }
+        line.extend_from_slice(&available[..size]);
+        reader.consume(size);
+        read += size;
+        if done {
+            return Ok(Some(read));
         }
     }
+}
 
-    let mut buffer = vec![0; content_length];
-    match reader.read_exact(&mut buffer).await {
-        Ok(size) => {
-            return Some((
-                raw_headers.trim().to_string(),
-                buffer,
-            ))
+async fn read_head_async<TReader>(
+    reader: &mut TReader,
+    limits: &RequestLimits,
+) -> Result<String, Error>
+where
+    TReader: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut raw_head = Vec::new();
+    match read_line_limited_async(reader, &mut raw_head, limits.max_request_line).await? {
+        Some(size) if size < MIN_LINE_LENGTH => return to_raw_headers(raw_head),
+        Some(_) => (),
+        None => return Err(Error::LimitExceeded(Limit::RequestLine)),
+    }
+
+    let mut header_bytes = 0;
+    let mut header_count = 0;
+    loop {
+        let remaining = limits.max_header_bytes - header_bytes;
+        match read_line_limited_async(reader, &mut raw_head, remaining).await? {
+            Some(size) if size < MIN_LINE_LENGTH => break,
+            Some(size) => {
+                header_bytes += size;
+                header_count += 1;
+                if header_count > limits.max_header_count {
+                    return Err(Error::LimitExceeded(Limit::Headers));
+                }
+            }
+            None => return Err(Error::LimitExceeded(Limit::Headers)),
         }
-        Err(_) => return None,
     }
+    return to_raw_headers(raw_head);
 }
 
-fn read_stream<TStream>(reader: &mut BufReader<TStream>) -> Option<(String, Vec<u8>)>
+fn read_head<TReader>(reader: &mut TReader, limits: &RequestLimits) -> Result<String, Error>
 where
-    TStream: std::io::Read
+    TReader: BufRead,
 {
-    // let mut reader = BufReader::new(stream);
-    let mut raw_headers = String::new();
-    let mut content_length = 0;
-    const MIN_LINE_LENGTH: usize = 3;
+    let mut raw_head = Vec::new();
+    match read_line_limited(reader, &mut raw_head, limits.max_request_line)? {
+        Some(size) if size < MIN_LINE_LENGTH => return to_raw_headers(raw_head),
+        Some(_) => (),
+        None => return Err(Error::LimitExceeded(Limit::RequestLine)),
+    }
+
+    let mut header_bytes = 0;
+    let mut header_count = 0;
     loop {
-        match reader.read_line(&mut raw_headers) {
-            Ok(size) => {
-                if size < MIN_LINE_LENGTH {
-                    break;
+        let remaining = limits.max_header_bytes - header_bytes;
+        match read_line_limited(reader, &mut raw_head, remaining)? {
+            Some(size) if size < MIN_LINE_LENGTH => break,
+            Some(size) => {
+                header_bytes += size;
+                header_count += 1;
+                if header_count > limits.max_header_count {
+                    return Err(Error::LimitExceeded(Limit::Headers));
                 }
             }
-            Err(_) => return None,
+            None => return Err(Error::LimitExceeded(Limit::Headers)),
+        }
+    }
+    return to_raw_headers(raw_head);
+}
+
+async fn read_stream_async<TReader>(
+    reader: &mut TReader,
+    limits: &RequestLimits,
+    header_timeout: Duration,
+    body_timeout: Duration,
+) -> Result<(String, Vec<u8>), Error>
+where
+    TReader: tokio::io::AsyncBufRead + AsyncWrite + Unpin,
+{
+    let raw_headers = match timeout(header_timeout, read_head_async(reader, limits)).await {
+        Ok(raw_headers) => raw_headers?,
+        Err(_) => return Err(Error::Timeout(Phase::ReadingRequest)),
+    };
+    let head = check_head(&raw_headers)?;
+    if let BodyLength::Fixed(length) = head.body_length {
+        if length > limits.max_body_bytes {
+            return Err(Error::LimitExceeded(Limit::Body));
         }
     }
-    for line in raw_headers.lines() {
-        if line.to_lowercase().contains("content-length") {
-            content_length = get_content_length(line);
+    if head.expects_continue {
+        let write_continue = async {
+            reader.write_all(CONTINUE).await?;
+            reader.flush().await
+        };
+        match timeout(body_timeout, write_continue).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(Error::Io(e)),
+            Err(_) => return Err(Error::Timeout(Phase::ReadingRequest)),
         }
     }
 
+    let content_length = match head.body_length {
+        BodyLength::Fixed(length) => length,
+        BodyLength::Chunked => {
+            return match timeout(body_timeout, read_chunked_async(reader, limits)).await {
+                Ok(body) => Ok((raw_headers, body?)),
+                Err(_) => Err(Error::Timeout(Phase::ReadingRequest)),
+            };
+        }
+    };
     let mut buffer = vec![0; content_length];
+    match timeout(body_timeout, reader.read_exact(&mut buffer)).await {
+        Ok(Ok(_)) => return Ok((raw_headers, buffer)),
+        Ok(Err(e)) => return Err(Error::Io(e)),
+        Err(_) => return Err(Error::Timeout(Phase::ReadingRequest)),
+    }
+}
 
-    match reader.read_exact(&mut buffer) {
-        Ok(_) => {
-            return Some((
-                raw_headers.trim().to_string(),
-                buffer,
-            ))
+fn read_stream(
+    reader: &mut BufReader<DeadlineStream>,
+    limits: &RequestLimits,
+    header_timeout: Duration,
+    body_timeout: Duration,
+) -> Result<(String, Vec<u8>), Error> {
+    reader.get_mut().set_timeout(header_timeout);
+    let raw_headers = read_head(reader, limits)?;
+    let head = check_head(&raw_headers)?;
+    if let BodyLength::Fixed(length) = head.body_length {
+        if length > limits.max_body_bytes {
+            return Err(Error::LimitExceeded(Limit::Body));
+        }
+    }
+
+    reader.get_mut().set_timeout(body_timeout);
+    if head.expects_continue {
+        let stream = reader.get_mut();
+        if let Err(e) = stream.write_all(CONTINUE).and_then(|_| stream.flush()) {
+            return Err(read_error(e));
         }
-        Err(_) => return None,
+    }
+    let content_length = match head.body_length {
+        BodyLength::Fixed(length) => length,
+        BodyLength::Chunked => return Ok((raw_headers, read_chunked(reader, limits)?)),
+    };
+    let mut buffer = vec![0; content_length];
+    match reader.read_exact(&mut buffer) {
+        Ok(_) => return Ok((raw_headers, buffer)),
+        Err(e) => return Err(read_error(e)),
     }
 }
 
-pub async  fn parse_stream_async(stream: &mut tokio::net::TcpStream) -> Result<HTTPContext, String> {
-    let request_parts = read_stream_async(stream).await;
-    if request_parts.is_none() {
-        return Err("Failed to read lines".to_string());
+pub async fn parse_stream_async<TReader>(
+    reader: &mut TReader,
+    limits: &RequestLimits,
+    header_timeout: Duration,
+    body_timeout: Duration,
+) -> Result<HTTPContext, Error>
+where
+    TReader: tokio::io::AsyncBufRead + AsyncWrite + Unpin,
+{
+    let (raw_headers, raw_body) =
+        read_stream_async(reader, limits, header_timeout, body_timeout).await?;
+    let mut context = match build_request(&raw_headers, raw_body) {
+        Some(context) => context,
+        None => return Err(Error::Parse(ParseError::Malformed)),
+    };
+    if context.get_header("transfer-encoding").is_some() {
+        unchunk_headers(&mut context);
     }
-    let (raw_headers, raw_body) = request_parts.unwrap();
-    match build_request(&raw_headers, raw_body) {
-        Some(context) => Ok(context),
-        None => Err("Could not read request ".to_string()),
+    decode_body(&mut context, limits)?;
+    return Ok(context);
+}
+
+pub fn parse_stream(
+    reader: &mut BufReader<DeadlineStream>,
+    limits: &RequestLimits,
+    header_timeout: Duration,
+    body_timeout: Duration,
+) -> Result<HTTPContext, Error> {
+    let (raw_headers, raw_body) = read_stream(reader, limits, header_timeout, body_timeout)?;
+    let mut context = match build_request(&raw_headers, raw_body) {
+        Some(context) => context,
+        None => return Err(Error::Parse(ParseError::Malformed)),
+    };
+    if context.get_header("transfer-encoding").is_some() {
+        unchunk_headers(&mut context);
     }
+    decode_body(&mut context, limits)?;
+    return Ok(context);
 }
-pub fn parse_stream(stream: &mut TcpStream) -> Result<HTTPContext, String> {
-    let mut reader = BufReader::new(stream);
-    let request_parts = read_stream(&mut reader);
-    if request_parts.is_none() {
-        return Err("Failed to read lines".to_string());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use tokio::io::AsyncWriteExt;
+
+    async fn parse_with(raw: &[u8], limits: &RequestLimits) -> (Result<HTTPContext, Error>, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(raw).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut reader = tokio::io::BufReader::new(server);
+        let second = Duration::from_secs(1);
+        let result = parse_stream_async(&mut reader, limits, second, second).await;
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        return (result, rest);
+    }
+
+    async fn parse(raw: &[u8]) -> Result<HTTPContext, Error> {
+        return parse_with(raw, &RequestLimits::default()).await.0;
+    }
+
+    fn status(result: Result<HTTPContext, Error>) -> Option<StatusCode> {
+        return result.err().and_then(|error| error.status_code());
+    }
+
+    #[tokio::test]
+    async fn decodes_a_chunked_body_and_leaves_the_next_request() {
+        let raw = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n";
+        let (result, rest) = parse_with(raw, &RequestLimits::default()).await;
+        let context = result.unwrap();
+        assert_eq!(context.body_bytes.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!(context.get_header("transfer-encoding"), None);
+        assert_eq!(context.get_header("content-length"), Some("11"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_ambiguous_framing() {
+        let both = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(parse(both).await), Some(StatusCode::BAD_REQUEST));
+        let http10 = b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(parse(http10).await), Some(StatusCode::BAD_REQUEST));
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd";
+        assert_eq!(status(parse(conflicting).await), Some(StatusCode::BAD_REQUEST));
+        let invalid = b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc";
+        assert_eq!(status(parse(invalid).await), Some(StatusCode::BAD_REQUEST));
+        let spaced = b"POST / HTTP/1.1\r\nContent-Length : 3\r\n\r\nabc";
+        assert_eq!(status(parse(spaced).await), Some(StatusCode::BAD_REQUEST));
     }
-    let (raw_headers, raw_body) = request_parts.unwrap();
-    match build_request(&raw_headers, raw_body) {
-        Some(context) => Ok(context),
-        None => Err("Could not read request ".to_string()),
+
+    #[tokio::test]
+    async fn rejects_unknown_transfer_codings() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(parse(raw).await), Some(StatusCode::NOT_IMPLEMENTED));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunks() {
+        let size = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(status(parse(size).await), Some(StatusCode::BAD_REQUEST));
+        let missing_crlf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX0\r\n\r\n";
+        assert_eq!(status(parse(missing_crlf).await), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn accepts_repeated_equal_content_lengths() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc";
+        assert_eq!(parse(raw).await.unwrap().body.as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn enforces_limits() {
+        let limits = RequestLimits {
+            max_request_line: 32,
+            max_header_bytes: 64,
+            max_header_count: 2,
+            max_body_bytes: 4,
+            max_decoded_body_bytes: 4,
+        };
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        let (result, _) = parse_with(long_line.as_bytes(), &limits).await;
+        assert_eq!(status(result), Some(StatusCode::URI_TOO_LONG));
+
+        let many_headers = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        let (result, _) = parse_with(many_headers, &limits).await;
+        assert_eq!(status(result), Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+
+        let large_header = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(80));
+        let (result, _) = parse_with(large_header.as_bytes(), &limits).await;
+        assert_eq!(status(result), Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+
+        let large_body = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let (result, _) = parse_with(large_body, &limits).await;
+        assert_eq!(status(result), Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        let large_chunks = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+        let (result, _) = parse_with(large_chunks, &limits).await;
+        assert_eq!(status(result), Some(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn reads_chunked_bodies_synchronously() {
+        let mut reader: &[u8] = b"3\r\nabc\r\n0\r\n\r\nnext";
+        assert_eq!(read_chunked(&mut reader, &RequestLimits::default()).unwrap(), b"abc");
+        assert_eq!(reader, b"next");
     }
 }
@@ -0,0 +1,186 @@
+use http::header::HeaderName;
+
+use crate::response::HttpResponse;
+use crate::route::{
+    HTTPRequest, PostRequestMiddlewareResult, PreRequestMiddleware, PreRequestMiddlewareResult,
+    ResponseMiddleware,
+};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// A random version 4 UUID, e.g. `9b2c4f1e-6a3d-4e8f-b1c7-2d5e8f9a0b1c`.
+pub fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("operating system random source is unavailable");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    return format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    );
+}
+
+/// Incoming ids end up in logs and response headers, so only short ones
+/// made of unremarkable characters are taken over.
+fn is_valid_request_id(id: &str) -> bool {
+    return !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '/' | '+' | '='));
+}
+
+impl HTTPRequest {
+    /// The id `RequestIdMiddleware` tagged the request with.
+    pub fn request_id(&self) -> Option<&str> {
+        return self.context.request_id.as_deref();
+    }
+}
+
+/// Tags every request with the `X-Request-Id` it came with, or a new one,
+/// and echoes it on the response. Log messages about the request carry it too.
+///
+/// `router.before_all(request_ids.clone()).after_all(request_ids);`
+#[derive(Clone)]
+pub struct RequestIdMiddleware {
+    trust_incoming: bool,
+}
+
+impl Default for RequestIdMiddleware {
+    fn default() -> Self {
+        return RequestIdMiddleware::new();
+    }
+}
+
+impl RequestIdMiddleware {
+    pub fn new() -> Self {
+        return RequestIdMiddleware {
+            trust_incoming: true,
+        };
+    }
+
+    /// Whether an id sent by the client is kept, true by default. Turn it off
+    /// when clients are not trusted to pick ids, e.g. without a proxy in front.
+    pub fn trust_incoming(&mut self, trust_incoming: bool) -> &mut Self {
+        self.trust_incoming = trust_incoming;
+        return self;
+    }
+}
+
+impl PreRequestMiddleware for RequestIdMiddleware {
+    fn handle(&self, request: &mut HTTPRequest) -> PreRequestMiddlewareResult {
+        let incoming = match request.context.get_header(X_REQUEST_ID.as_str()) {
+            Some(id) if self.trust_incoming && is_valid_request_id(id) => Some(id.to_string()),
+            _ => None,
+        };
+        request.context.request_id = Some(incoming.unwrap_or_else(generate_request_id));
+        return PreRequestMiddlewareResult::Next;
+    }
+}
+
+impl ResponseMiddleware for RequestIdMiddleware {
+    fn handle(&self, request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+        let mut response = response;
+        if let Some(id) = request.request_id() {
+            response.remove_header(&X_REQUEST_ID).set_header(X_REQUEST_ID, id);
+        }
+        return PostRequestMiddlewareResult::Next(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{context_from_parts, HTTPHeader, HTTPMethod, RequestLimits};
+    use crate::route::Router;
+
+    fn is_uuid_v4(id: &str) -> bool {
+        let groups: Vec<&str> = id.split("-").collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        return lengths == [8, 4, 4, 4, 12]
+            && id.chars().all(|c| c == '-' || c.is_ascii_digit() || ('a'..='f').contains(&c))
+            && groups[2].starts_with('4')
+            && groups[3].starts_with(['8', '9', 'a', 'b']);
+    }
+
+    #[test]
+    fn generates_version_4_uuids() {
+        let ids: Vec<String> = (0..50).map(|_| generate_request_id()).collect();
+        for id in ids.iter() {
+            assert!(is_uuid_v4(id), "{}", id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn accepts_only_short_plain_ids() {
+        assert!(is_valid_request_id("abc-123_x.y:z/w+v="));
+        assert!(is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH)));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+        for id in ["", "a b", "a\r\nb", "a\"b", "<script>", "naïve", "a;b", "a\0"] {
+            assert!(!is_valid_request_id(id), "{:?}", id);
+        }
+    }
+
+    fn router(middleware: RequestIdMiddleware) -> Router {
+        let mut router = Router::new();
+        router
+            .before_all(middleware.clone())
+            .after_all(middleware)
+            .route(HTTPMethod::GET, "/", |request| {
+                let mut response = HttpResponse::new();
+                // Handlers see the id, and one they set is replaced rather than repeated.
+                response
+                    .set_header(X_REQUEST_ID, "from-handler")
+                    .set_body(request.request_id().unwrap());
+                return response;
+            });
+        return router;
+    }
+
+    fn request(router: &Router, incoming: Option<&str>) -> (Vec<String>, String) {
+        let headers = match incoming {
+            Some(id) => vec![HTTPHeader("X-Request-Id".to_string(), id.to_string())],
+            None => vec![],
+        };
+        let context = context_from_parts("GET", "HTTP/1.1", "/", headers, vec![], &RequestLimits::default());
+        let response = router.handle(context.unwrap());
+        let body = match response.body() {
+            Some(crate::response::ResponseBody::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => panic!("expected a buffered body"),
+        };
+        let ids = response.get_header_values(&X_REQUEST_ID).iter().map(|id| id.to_string()).collect();
+        return (ids, body);
+    }
+
+    #[test]
+    fn keeps_trusted_incoming_ids() {
+        let router = router(RequestIdMiddleware::new());
+        assert_eq!(request(&router, Some("upstream-7")), (vec!["upstream-7".to_string()], "upstream-7".to_string()));
+
+        let (ids, body) = request(&router, Some("bad id\u{7f}"));
+        assert!(is_uuid_v4(&body), "{}", body);
+        assert_eq!(ids, vec![body]);
+
+        let (ids, body) = request(&router, None);
+        assert!(is_uuid_v4(&body));
+        assert_eq!(ids, vec![body]);
+    }
+
+    #[test]
+    fn ignores_incoming_ids_unless_trusted() {
+        let mut middleware = RequestIdMiddleware::new();
+        middleware.trust_incoming(false);
+        let router = router(middleware);
+        let (ids, body) = request(&router, Some("upstream-7"));
+        assert!(is_uuid_v4(&body), "{}", body);
+        assert_eq!(ids, vec![body]);
+    }
+}
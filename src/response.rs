@@ -1,21 +1,91 @@
-use std::io::Write;
-use std::net::TcpStream;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
 
-use http::{header::HeaderName, StatusCode};
-use tokio::io::AsyncWriteExt;
+use http::{header, header::HeaderName, StatusCode, Version};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::compression::{ContentEncoding, Encoder};
+use crate::cookie::Cookie;
+use crate::error::Error;
+use crate::logging::{self, Span};
+use crate::sse::{EventStream, HEARTBEAT};
+use crate::stream::Connection;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    /// Read from disk while the response is written rather than loaded up front.
+    File { file: File, offset: u64, length: u64 },
+    /// Written one after another, e.g. the parts of a `multipart/byteranges` body.
+    Parts(Vec<ResponseBody>),
+    /// Compressed while written. The encoded length is not known up front,
+    /// so it is sent with chunked transfer encoding, or delimited by closing
+    /// the connection for HTTP/1.0 clients.
+    Encoded {
+        body: Box<ResponseBody>,
+        encoding: ContentEncoding,
+    },
+    /// Server-sent events, written as they arrive with the same framing as `Encoded`.
+    Events(EventStream),
+}
+
+impl ResponseBody {
+    pub fn len(&self) -> u64 {
+        return match self {
+            ResponseBody::Bytes(bytes) => bytes.len() as u64,
+            ResponseBody::File { length, .. } => *length,
+            ResponseBody::Parts(parts) => parts.iter().map(|part| part.len()).sum(),
+            // The length before encoding, the only one known ahead of time.
+            ResponseBody::Encoded { body, .. } => body.len(),
+            ResponseBody::Events(_) => 0,
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Writes just the payload, compressing `Encoded` bodies but without any
+    /// HTTP/1.1 framing, for transports that delimit the body themselves.
+    pub async fn write_async<TStream: AsyncWrite + Unpin>(self, stream: &mut TStream) -> Result<(), std::io::Error> {
+        return write_payload_async(self, false, stream).await;
+    }
+}
+
+/// Takes over the connection once a `101 Switching Protocols` response is written.
+pub type Upgrade = Box<dyn FnOnce(Box<dyn Connection>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// A header value that would end the header line early: it holds `\r`, `\n` or NUL.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("invalid header value {0:?}")]
+pub struct InvalidHeaderValue(pub String);
 
 pub struct HttpResponse {
     status_code: StatusCode,
+    version: Version,
     headers: Vec<(HeaderName, String)>,
-    body: Option<String>,
+    body: Option<ResponseBody>,
+    upgrade: Option<Upgrade>,
+}
+
+impl Default for HttpResponse {
+    fn default() -> Self {
+        return HttpResponse::new();
+    }
 }
 
 impl HttpResponse {
     pub fn new() -> Self {
         return HttpResponse {
             status_code: StatusCode::OK,
+            version: Version::HTTP_11,
             headers: vec![],
             body: None,
+            upgrade: None,
         };
     }
     pub fn set_status(&mut self, status_code: StatusCode) -> &mut Self {
@@ -23,21 +93,133 @@ impl HttpResponse {
         return self;
     }
 
+    /// The protocol version of the status line, which should match the request's.
+    pub fn set_version(&mut self, version: Version) -> &mut Self {
+        self.version = version;
+        return self;
+    }
+
     pub fn set_body(&mut self, body: &str) -> &mut Self {
-        self.body = Some(body.to_string());
+        self.body = Some(ResponseBody::Bytes(body.as_bytes().to_vec()));
+        return self;
+    }
+
+    pub fn set_body_bytes(&mut self, body: Vec<u8>) -> &mut Self {
+        self.body = Some(ResponseBody::Bytes(body));
+        return self;
+    }
+
+    /// Streams the first `length` bytes of the file as the body.
+    pub fn set_file(&mut self, file: File, length: u64) -> &mut Self {
+        self.body = Some(ResponseBody::File {
+            file,
+            offset: 0,
+            length,
+        });
         return self;
     }
 
+    pub fn set_response_body(&mut self, body: ResponseBody) -> &mut Self {
+        self.body = Some(body);
+        return self;
+    }
+
+    /// Leaves the header out and logs a warning if `value` holds `\r`, `\n`
+    /// or NUL, which would let it inject headers. `try_set_header` reports
+    /// that to the caller instead.
     pub fn set_header(&mut self, key: HeaderName, value: &str) -> &mut Self {
+        if let Err(e) = self.try_set_header(key.clone(), value) {
+            logging::warn(&Span::default(), &format!("dropped {} header: {}", key, e));
+        }
+        return self;
+    }
+
+    pub fn try_set_header(&mut self, key: HeaderName, value: &str) -> Result<&mut Self, InvalidHeaderValue> {
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(InvalidHeaderValue(value.to_string()));
+        }
         self.headers.push((key, value.to_string()));
+        return Ok(self);
+    }
+
+    pub fn status(&self) -> StatusCode {
+        return self.status_code;
+    }
+
+    /// The first value set for `key`, if any.
+    pub fn get_header(&self, key: &HeaderName) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str());
+    }
+
+    /// Every value set for `key`, in the order they were set.
+    pub fn get_header_values(&self, key: &HeaderName) -> Vec<&str> {
+        return self
+            .headers
+            .iter()
+            .filter(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+            .collect();
+    }
+
+    pub fn remove_header(&mut self, key: &HeaderName) -> &mut Self {
+        self.headers.retain(|(name, _)| name != key);
+        return self;
+    }
+
+    pub fn body(&self) -> Option<&ResponseBody> {
+        return self.body.as_ref();
+    }
+
+    pub fn take_body(&mut self) -> Option<ResponseBody> {
+        return self.body.take();
+    }
+
+    /// Appends a `Set-Cookie` header, keeping any cookies set before it.
+    pub fn set_cookie(&mut self, cookie: &Cookie) -> &mut Self {
+        return self.set_header(header::SET_COOKIE, &cookie.to_header_value());
+    }
+
+    /// Hands the connection to `upgrade` after this response has been written,
+    /// for protocols such as WebSocket. Only HTTP/1.1 connections can be taken over.
+    pub fn set_upgrade<T, F>(&mut self, upgrade: T) -> &mut Self
+    where
+        T: FnOnce(Box<dyn Connection>) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(move |connection| Box::pin(upgrade(connection))));
         return self;
     }
 
-    fn write_headers(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        return self.upgrade.take();
+    }
+
+    /// Splits the response into its status, headers and body.
+    pub fn into_parts(self) -> (StatusCode, Vec<(HeaderName, String)>, Option<ResponseBody>) {
+        return (self.status_code, self.headers, self.body);
+    }
+
+    /// Whether the body is an event stream that stays open for as long as it produces events.
+    pub fn is_streaming(&self) -> bool {
+        return matches!(self.body, Some(ResponseBody::Events(_)));
+    }
+
+    /// Whether a `Connection: close` header has been set on this response.
+    pub fn closes_connection(&self) -> bool {
+        return self.headers.iter().any(|(key, value)| {
+            key == header::CONNECTION && value.eq_ignore_ascii_case("close")
+        });
+    }
+
+    fn write_headers<TStream: Write>(&self, stream: &mut TStream) -> Result<(), std::io::Error> {
         for header in self.headers.iter() {
-            let header_line = format!("{}: {}\r\n\r\n", header.0.as_str(), header.1);
-            match stream.write(header_line.as_bytes()) {
-                Ok(_) => {}
+            let header_line = format!("{}: {}\r\n", header.0.as_str(), header.1);
+            match stream.write_all(header_line.as_bytes()) {
+                Ok(()) => {}
                 Err(e) => return Err(e),
             }
         }
@@ -46,11 +228,11 @@ impl HttpResponse {
     }
 
 
-    async fn write_headers_async(&self, stream: &mut tokio::net::TcpStream) -> Result<(), std::io::Error> {
+    async fn write_headers_async<TStream: AsyncWrite + Unpin>(&self, stream: &mut TStream) -> Result<(), std::io::Error> {
         for header in self.headers.iter() {
-            let header_line = format!("{}: {}\r\n\r\n", header.0.as_str(), header.1);
-            match stream.write(header_line.as_bytes()).await {
-                Ok(_) => {}
+            let header_line = format!("{}: {}\r\n", header.0.as_str(), header.1);
+            match stream.write_all(header_line.as_bytes()).await {
+                Ok(()) => {}
                 Err(e) => return Err(e),
             }
         }
@@ -59,8 +241,13 @@ impl HttpResponse {
     }
 
     fn status_line(&self) -> String {
+        let version = match self.version {
+            Version::HTTP_10 => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        };
         format!(
-            "HTTP/1.1 {} {}\r\n",
+            "{} {} {}\r\n",
+            version,
             self.status_code.as_str(),
             self.status_code
                 .canonical_reason()
@@ -68,57 +255,350 @@ impl HttpResponse {
         )
     }
 
-    fn write_status(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    fn write_status<TStream: Write>(&self, stream: &mut TStream) -> Result<(), std::io::Error> {
         let status_line = self.status_line();
-        return match stream.write(status_line.as_bytes()) {
+        return match stream.write_all(status_line.as_bytes()) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
         };
     }
 
-    async fn write_status_async(&self, stream: &mut tokio::net::TcpStream) -> Result<(), std::io::Error> {
+    async fn write_status_async<TStream: AsyncWrite + Unpin>(&self, stream: &mut TStream) -> Result<(), std::io::Error> {
         let status_line = self.status_line();
-        return match stream.write(status_line.as_bytes()).await {
+        return match stream.write_all(status_line.as_bytes()).await {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
         };
     }
 
-    fn write_body(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
-        let body = match &self.body {
-            Some(body) => body,
-            None => "",
-        };
+    /// These statuses never carry a body, and a 304 must not advertise a length
+    /// other than the one of the representation it stands for.
+    pub fn allows_body(&self) -> bool {
+        return !(self.status_code.is_informational()
+            || self.status_code == StatusCode::NO_CONTENT
+            || self.status_code == StatusCode::NOT_MODIFIED);
+    }
 
-        let response = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
-        return match stream.write(response.as_bytes()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    /// HTTP/1.0 has no chunked encoding, so a body of unknown length can only
+    /// be ended by closing the connection.
+    pub fn is_close_delimited(&self) -> bool {
+        return self.version == Version::HTTP_10
+            && self.allows_body()
+            && matches!(self.body, Some(ResponseBody::Encoded { .. } | ResponseBody::Events(_)));
+    }
+
+    fn is_chunked(&self) -> bool {
+        return self.version != Version::HTTP_10;
+    }
+
+    fn content_length_line(&self) -> String {
+        if !self.allows_body() || self.is_close_delimited() {
+            return "\r\n".to_string();
+        }
+        if let Some(ResponseBody::Encoded { .. } | ResponseBody::Events(_)) = &self.body {
+            return "Transfer-Encoding: chunked\r\n\r\n".to_string();
+        }
+        let length = match &self.body {
+            Some(body) => body.len(),
+            None => 0,
         };
+        return format!("Content-Length: {}\r\n\r\n", length);
     }
 
-    async fn write_body_async(&self, stream: &mut tokio::net::TcpStream) -> Result<(), std::io::Error> {
-        let body = match &self.body {
-            Some(body) => body,
-            None => "",
+    fn write_body<TStream: Write>(self, stream: &mut TStream) -> Result<(), std::io::Error> {
+        stream.write_all(self.content_length_line().as_bytes())?;
+        if !self.allows_body() {
+            return Ok(());
+        }
+        let chunked = self.is_chunked();
+        return match self.body {
+            Some(body) => write_payload(body, chunked, stream),
+            None => Ok(()),
         };
+    }
 
-        let response = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
-        return match stream.write(response.as_bytes()).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    async fn write_body_async<TStream: AsyncWrite + Unpin>(self, stream: &mut TStream) -> Result<(), std::io::Error> {
+        stream.write_all(self.content_length_line().as_bytes()).await?;
+        if !self.allows_body() {
+            return Ok(());
+        }
+        let chunked = self.is_chunked();
+        return match self.body {
+            Some(body) => write_payload_async(body, chunked, stream).await,
+            None => Ok(()),
         };
     }
 
-    pub fn write(&self, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    pub fn write<TStream: Write>(self, stream: &mut TStream) -> Result<(), Error> {
         self.write_status(stream)?;
         self.write_headers(stream)?;
-        self.write_body(stream)
+        return self.write_body(stream).map_err(Error::from);
     }
 
-    pub async fn write_async(&self, stream: &mut tokio::net::TcpStream) -> Result<(), std::io::Error> {
+    pub async fn write_async<TStream: AsyncWrite + Unpin>(self, stream: &mut TStream) -> Result<(), Error> {
         self.write_status_async(stream).await?;
         self.write_headers_async(stream).await?;
-        self.write_body_async(stream).await
+        return self.write_body_async(stream).await.map_err(Error::from);
+    }
+}
+
+/// A file that shrank after its length was taken would otherwise leave the
+/// client waiting for bytes promised by `Content-Length`.
+fn check_copied(copied: u64, length: u64) -> Result<(), std::io::Error> {
+    if copied < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ended before its length"));
+    }
+    return Ok(());
+}
+
+fn nested_encoding_error() -> std::io::Error {
+    return io::Error::new(io::ErrorKind::Unsupported, "cannot encode a streamed or already encoded body");
+}
+
+fn write_chunk<TStream: Write>(stream: &mut TStream, data: &[u8], chunked: bool) -> Result<(), std::io::Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if !chunked {
+        return stream.write_all(data);
+    }
+    stream.write_all(format!("{:x}\r\n", data.len()).as_bytes())?;
+    stream.write_all(data)?;
+    return stream.write_all(b"\r\n");
+}
+
+async fn write_chunk_async<TStream: AsyncWrite + Unpin>(
+    stream: &mut TStream,
+    data: &[u8],
+    chunked: bool,
+) -> Result<(), std::io::Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if !chunked {
+        return stream.write_all(data).await;
+    }
+    stream.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+    stream.write_all(data).await?;
+    return stream.write_all(b"\r\n").await;
+}
+
+/// Writes the body, with `chunked` framing the output of `Encoded` bodies
+/// for HTTP/1.1 since its length is not known up front.
+fn write_payload<TStream: Write>(body: ResponseBody, chunked: bool, stream: &mut TStream) -> Result<(), std::io::Error> {
+    let mut pending = vec![body];
+    while let Some(part) = pending.pop() {
+        match part {
+            ResponseBody::Bytes(bytes) => stream.write_all(&bytes)?,
+            ResponseBody::File {
+                mut file,
+                offset,
+                length,
+            } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut file.take(length), stream)?;
+                check_copied(copied, length)?;
+            }
+            ResponseBody::Parts(parts) => pending.extend(parts.into_iter().rev()),
+            ResponseBody::Encoded { body, encoding } => write_encoded(*body, encoding, chunked, stream)?,
+            ResponseBody::Events(events) => write_events(events, chunked, stream)?,
+        }
+    }
+    return Ok(());
+}
+
+fn write_encoded<TStream: Write>(
+    body: ResponseBody,
+    encoding: ContentEncoding,
+    chunked: bool,
+    stream: &mut TStream,
+) -> Result<(), std::io::Error> {
+    let mut encoder = Encoder::new(encoding);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut pending = vec![body];
+    while let Some(part) = pending.pop() {
+        match part {
+            ResponseBody::Bytes(bytes) => write_chunk(stream, &encoder.compress(&bytes)?, chunked)?,
+            ResponseBody::File {
+                mut file,
+                offset,
+                length,
+            } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut reader = file.take(length);
+                let mut copied = 0;
+                loop {
+                    let read = reader.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    copied += read as u64;
+                    write_chunk(stream, &encoder.compress(&buffer[..read])?, chunked)?;
+                }
+                check_copied(copied, length)?;
+            }
+            ResponseBody::Parts(parts) => pending.extend(parts.into_iter().rev()),
+            ResponseBody::Encoded { .. } | ResponseBody::Events(_) => return Err(nested_encoding_error()),
+        }
+    }
+    write_chunk(stream, &encoder.finish()?, chunked)?;
+    if !chunked {
+        return Ok(());
+    }
+    return stream.write_all(b"0\r\n\r\n");
+}
+
+/// Writes the body, with `chunked` framing the output of `Encoded` bodies
+/// for HTTP/1.1 since its length is not known up front.
+async fn write_payload_async<TStream: AsyncWrite + Unpin>(
+    body: ResponseBody,
+    chunked: bool,
+    stream: &mut TStream,
+) -> Result<(), std::io::Error> {
+    let mut pending = vec![body];
+    while let Some(part) = pending.pop() {
+        match part {
+            ResponseBody::Bytes(bytes) => stream.write_all(&bytes).await?,
+            ResponseBody::File {
+                file,
+                offset,
+                length,
+            } => {
+                let mut file = tokio::fs::File::from_std(file);
+                file.seek(SeekFrom::Start(offset)).await?;
+                let copied = tokio::io::copy(&mut file.take(length), stream).await?;
+                check_copied(copied, length)?;
+            }
+            ResponseBody::Parts(parts) => pending.extend(parts.into_iter().rev()),
+            ResponseBody::Encoded { body, encoding } => {
+                write_encoded_async(*body, encoding, chunked, stream).await?
+            }
+            ResponseBody::Events(events) => write_events_async(events, chunked, stream).await?,
+        }
+    }
+    return Ok(());
+}
+
+async fn write_encoded_async<TStream: AsyncWrite + Unpin>(
+    body: ResponseBody,
+    encoding: ContentEncoding,
+    chunked: bool,
+    stream: &mut TStream,
+) -> Result<(), std::io::Error> {
+    let mut encoder = Encoder::new(encoding);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut pending = vec![body];
+    while let Some(part) = pending.pop() {
+        match part {
+            ResponseBody::Bytes(bytes) => {
+                write_chunk_async(stream, &encoder.compress(&bytes)?, chunked).await?
+            }
+            ResponseBody::File {
+                file,
+                offset,
+                length,
+            } => {
+                let mut file = tokio::fs::File::from_std(file);
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut reader = file.take(length);
+                let mut copied = 0;
+                loop {
+                    let read = reader.read(&mut buffer).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    copied += read as u64;
+                    write_chunk_async(stream, &encoder.compress(&buffer[..read])?, chunked).await?;
+                }
+                check_copied(copied, length)?;
+            }
+            ResponseBody::Parts(parts) => pending.extend(parts.into_iter().rev()),
+            ResponseBody::Encoded { .. } | ResponseBody::Events(_) => return Err(nested_encoding_error()),
+        }
+    }
+    write_chunk_async(stream, &encoder.finish()?, chunked).await?;
+    if !chunked {
+        return Ok(());
+    }
+    return stream.write_all(b"0\r\n\r\n").await;
+}
+
+/// The next event to write, or a heartbeat once the stream has been quiet
+/// for too long, or `None` when the source has ended.
+async fn next_event(events: &mut EventStream) -> Option<Vec<u8>> {
+    let event = match events.heartbeat_interval() {
+        Some(interval) => tokio::time::timeout(interval, events.next()).await,
+        None => Ok(events.next().await),
+    };
+    return match event {
+        Ok(Some(event)) => Some(event.to_bytes()),
+        Ok(None) => None,
+        Err(_) => Some(HEARTBEAT.to_vec()),
+    };
+}
+
+/// Pulls events on a runtime of its own, since sync mode has none to lend.
+fn write_events<TStream: Write>(events: EventStream, chunked: bool, stream: &mut TStream) -> Result<(), std::io::Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+    let mut events = events;
+    stream.flush()?;
+    while let Some(event) = runtime.block_on(next_event(&mut events)) {
+        write_chunk(stream, &event, chunked)?;
+        stream.flush()?;
+    }
+    if !chunked {
+        return Ok(());
+    }
+    return stream.write_all(b"0\r\n\r\n");
+}
+
+async fn write_events_async<TStream: AsyncWrite + Unpin>(
+    events: EventStream,
+    chunked: bool,
+    stream: &mut TStream,
+) -> Result<(), std::io::Error> {
+    let mut events = events;
+    stream.flush().await?;
+    while let Some(event) = next_event(&mut events).await {
+        write_chunk_async(stream, &event, chunked).await?;
+        stream.flush().await?;
+    }
+    if !chunked {
+        return Ok(());
+    }
+    return stream.write_all(b"0\r\n\r\n").await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_header_values_with_line_breaks() {
+        let mut response = HttpResponse::new();
+        for value in ["a\r\nSet-Cookie: x=1", "a\nb", "a\rb", "a\0b"] {
+            assert_eq!(
+                response.try_set_header(header::LOCATION, value).err(),
+                Some(InvalidHeaderValue(value.to_string()))
+            );
+        }
+        response.try_set_header(header::LOCATION, "/next").unwrap();
+        assert_eq!(response.get_header(&header::LOCATION), Some("/next"));
+
+        let mut written = Vec::new();
+        response.write(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("location: /next\r\n"));
+        assert!(!written.contains("set-cookie"));
+    }
+
+    #[test]
+    fn set_header_drops_values_with_line_breaks() {
+        let mut response = HttpResponse::new();
+        response
+            .set_header(header::LOCATION, "a\r\nSet-Cookie: x=1")
+            .set_header(header::CONTENT_TYPE, "text/plain");
+        assert_eq!(response.get_header(&header::LOCATION), None);
+        assert_eq!(response.get_header(&header::CONTENT_TYPE), Some("text/plain"));
     }
 }
@@ -1,19 +1,84 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Instant;
 use std::vec;
 
-use http::StatusCode;
+use http::{header, Extensions, StatusCode};
 
 use crate::{
+    access_log::{AccessLog, AccessLogEntry},
+    metrics::Metrics,
+    conditional::{apply_preconditions, check_preconditions, Validators},
+    cookie::{parse_cookie_header, verify_value},
+    error_page::{empty_error_response, error_page, HttpError},
+    logging::{self, Span},
     request::{HTTPContext, HTTPMethod},
     response::HttpResponse,
+    range::apply_range,
+    static_files::StaticFiles,
+    websocket::{handshake, WebSocket},
 };
 
+#[derive(Clone)]
 pub struct HTTPRequest {
     pub context: HTTPContext,
+    /// Values captured by `:name` and `*` segments of the matched route.
+    pub params: HashMap<String, String>,
+    /// Per-request state attached by middleware, such as the session.
+    pub extensions: Extensions,
+}
+
+impl HTTPRequest {
+    pub fn new(context: HTTPContext) -> Self {
+        return HTTPRequest {
+            context,
+            params: HashMap::new(),
+            extensions: Extensions::new(),
+        };
+    }
+
+    /// A segment captured by the route, e.g. `param("id")` for `/users/:id`
+    /// or `param("*")` for the remainder matched by `/static/*`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        return self.params.get(name).map(|value| value.as_str());
+    }
+
+    /// All cookies sent by the client, across every `Cookie` header.
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        return self
+            .context
+            .headers
+            .iter()
+            .filter(|header| header.0.eq_ignore_ascii_case("cookie"))
+            .flat_map(|header| parse_cookie_header(&header.1))
+            .collect();
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        return self
+            .cookies()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value);
+    }
+
+    /// The value of a cookie set with `Cookie::sign`, or `None` if it was tampered with.
+    pub fn signed_cookie(&self, name: &str, key: &[u8]) -> Option<String> {
+        return match self.cookie(name) {
+            Some(signed) => verify_value(key, name, &signed),
+            None => None,
+        };
+    }
 }
 
 enum HTTPPath {
     Parameterized(String),
     Plain(String),
+    /// Matches the rest of the path, only meaningful as the last segment.
+    Wildcard,
 }
 
 pub enum PreRequestMiddlewareResult {
@@ -34,6 +99,19 @@ pub trait PostRequestMiddleware {
         -> PostRequestMiddlewareResult;
 }
 
+/// Post-request middleware that takes the response by value, so it can add
+/// headers or swap the body without cloning it. `after` and `after_all`
+/// accept either kind.
+pub trait ResponseMiddleware {
+    fn handle(&self, request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult;
+}
+
+impl<T: PostRequestMiddleware> ResponseMiddleware for T {
+    fn handle(&self, request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+        return PostRequestMiddleware::handle(self, request, &response);
+    }
+}
+
 enum MatchedPath {
     Plain,
     Parameterized { key: String, value: String },
@@ -52,6 +130,7 @@ impl HTTPPath {
                 }
                 return None;
             }
+            HTTPPath::Wildcard => Some(MatchedPath::Plain),
         };
     }
 }
@@ -60,6 +139,10 @@ fn is_parameterized_path(path: &str) -> bool {
     return path.starts_with(":");
 }
 
+fn is_wildcard_path(path: &str) -> bool {
+    return path == "*";
+}
+
 fn split_path(path: &str) -> Vec<&str> {
     let mut split_paths: Vec<&str> = path
         .split("/")
@@ -83,6 +166,9 @@ fn build_paths(path: String) -> Vec<HTTPPath> {
             if is_parameterized_path(segment) {
                 return HTTPPath::Parameterized(segment.to_string());
             }
+            if is_wildcard_path(segment) {
+                return HTTPPath::Wildcard;
+            }
             return HTTPPath::Plain(segment.to_string());
         })
         .collect()
@@ -90,10 +176,17 @@ fn build_paths(path: String) -> Vec<HTTPPath> {
 
 struct RouteMiddleware {
     pre_request_handlers: Vec<Box<dyn PreRequestMiddleware + Sync + Send>>,
-    post_request_handlers: Vec<Box<dyn PostRequestMiddleware + Sync + Send>>,
+    post_request_handlers: Vec<Box<dyn ResponseMiddleware + Sync + Send>>,
 }
 
 impl RouteMiddleware {
+    fn new() -> Self {
+        return RouteMiddleware {
+            pre_request_handlers: vec![],
+            post_request_handlers: vec![],
+        };
+    }
+
     fn pre_request_hook(&self, request: &mut HTTPRequest) -> Option<HttpResponse> {
         for handler in self.pre_request_handlers.iter() {
             let res = handler.handle(request);
@@ -108,7 +201,7 @@ impl RouteMiddleware {
     fn post_request_hook(&self, request: &HTTPRequest, response: HttpResponse) -> HttpResponse {
         let mut response_copy = response;
         for handler in self.post_request_handlers.iter() {
-            let res = handler.handle(request, &response_copy);
+            let res = handler.handle(request, response_copy);
             match res {
                 PostRequestMiddlewareResult::Next(response) => {
                     response_copy = response;
@@ -120,14 +213,26 @@ impl RouteMiddleware {
     }
 }
 
+type HandlerFn = Box<dyn Fn(&mut HTTPRequest) -> Option<HttpResponse> + Sync + Send>;
+type ValidatorsFn = Box<dyn Fn(&HTTPRequest) -> Option<Validators> + Sync + Send>;
+
 struct RouteHandler {
-    inner_handler: Option<Box<dyn Fn(&mut HTTPRequest) -> HttpResponse + Sync + Send>>,
+    inner_handler: Option<HandlerFn>,
 }
 
 impl RouteHandler {
     fn new<T>(inner_handler: T) -> Self
     where
         T: Fn(&mut HTTPRequest) -> HttpResponse + 'static + Sync + Send,
+    {
+        return RouteHandler::optional(move |request| Some(inner_handler(request)));
+    }
+
+    /// A handler that may pass on a request by returning `None`, which is
+    /// then answered like one no route matched.
+    fn optional<T>(inner_handler: T) -> Self
+    where
+        T: Fn(&mut HTTPRequest) -> Option<HttpResponse> + 'static + Sync + Send,
     {
         return RouteHandler {
             inner_handler: Some(Box::new(inner_handler)),
@@ -135,15 +240,13 @@ impl RouteHandler {
     }
 
     fn call(&self, request: &mut HTTPRequest) -> Option<HttpResponse> {
-        return match &self.inner_handler {
-            Some(inner_handler) => Some((inner_handler)(request)),
-            None => None,
-        };
+        return self.inner_handler.as_ref().and_then(|inner_handler| (inner_handler)(request));
     }
 }
 
 pub struct RouteMapping {
     method: Option<HTTPMethod>,
+    path: String,
     segments: Vec<HTTPPath>,
     handler: Option<RouteHandler>,
     middleware: Option<RouteMiddleware>,
@@ -156,7 +259,8 @@ impl RouteMapping {
     {
         return RouteMapping {
             method,
-            segments: build_paths(path),
+            segments: build_paths(path.clone()),
+            path,
             handler: Some(RouteHandler::new(handler)),
             middleware: None,
         };
@@ -166,6 +270,7 @@ impl RouteMapping {
         return RouteMapping {
             method: None,
             segments: build_paths(path.to_string()),
+            path: path.to_string(),
             handler: None,
             middleware: None,
         };
@@ -173,16 +278,16 @@ impl RouteMapping {
 
     fn add_post_request_middleware(
         &mut self,
-        post_request: Box<dyn PostRequestMiddleware + Sync + Send>,
+        post_request: Box<dyn ResponseMiddleware + Sync + Send>,
     ) {
-        if self.middleware.is_none() {
-            self.middleware = Some(RouteMiddleware {
-                pre_request_handlers: vec![],
-                post_request_handlers: vec![post_request],
-            })
-        } else {
-            let middleware = self.middleware.as_mut().unwrap();
-            middleware.post_request_handlers.push(post_request)
+        match self.middleware.as_mut() {
+            Some(middleware) => middleware.post_request_handlers.push(post_request),
+            None => {
+                self.middleware = Some(RouteMiddleware {
+                    pre_request_handlers: vec![],
+                    post_request_handlers: vec![post_request],
+                })
+            }
         }
     }
 
@@ -190,14 +295,14 @@ impl RouteMapping {
         &mut self,
         pre_request: Box<dyn PreRequestMiddleware + Sync + Send>,
     ) {
-        if self.middleware.is_none() {
-            self.middleware = Some(RouteMiddleware {
-                pre_request_handlers: vec![pre_request],
-                post_request_handlers: vec![],
-            })
-        } else {
-            let middleware = self.middleware.as_mut().unwrap();
-            middleware.pre_request_handlers.push(pre_request)
+        match self.middleware.as_mut() {
+            Some(middleware) => middleware.pre_request_handlers.push(pre_request),
+            None => {
+                self.middleware = Some(RouteMiddleware {
+                    pre_request_handlers: vec![pre_request],
+                    post_request_handlers: vec![],
+                })
+            }
         }
     }
 
@@ -222,15 +327,27 @@ impl RouteMapping {
         }
     }
 
-    fn handle(&self, request: &mut HTTPRequest) -> Option<HttpResponse> {
+    /// Runs the route's middleware and handler. `preconditions` is checked
+    /// after the middleware, so a 304 or 412 never skips e.g. authentication.
+    fn handle<P>(&self, request: &mut HTTPRequest, preconditions: P) -> Option<HttpResponse>
+    where
+        P: Fn(&HTTPRequest) -> Option<HttpResponse>,
+    {
+        if let Some(params) = self.match_params(&request.context.path) {
+            request.params = params;
+        }
         let response = self.pre_request_hook(request);
         if response.is_some() {
             return response;
         };
-        return match self.request_handler(request) {
-            Some(response) => Some(self.post_request_hook(request, response)),
-            None => None,
-        };
+        if self.handler.is_some() {
+            if let Some(response) = preconditions(request) {
+                return Some(self.post_request_hook(request, response));
+            }
+        }
+        return self
+            .request_handler(request)
+            .map(|response| self.post_request_hook(request, response));
     }
 
     fn match_method(&self, method: &HTTPMethod) -> bool {
@@ -241,14 +358,36 @@ impl RouteMapping {
     }
 
     fn match_path(&self, path: &str) -> bool {
+        return self.match_params(path).is_some();
+    }
+
+    fn match_params(&self, path: &str) -> Option<HashMap<String, String>> {
         let split_paths = split_path(path);
+        let mut params = HashMap::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            if let HTTPPath::Wildcard = segment {
+                if split_paths.len() <= index {
+                    return None;
+                }
+                let rest: Vec<&str> = split_paths[index..]
+                    .iter()
+                    .filter(|&&segment| segment != "/")
+                    .copied()
+                    .collect();
+                params.insert("*".to_string(), rest.join("/"));
+                return Some(params);
+            }
+            match segment.match_segment(split_paths.get(index)?)? {
+                MatchedPath::Plain => (),
+                MatchedPath::Parameterized { key, value } => {
+                    params.insert(key.trim_start_matches(":").to_string(), value);
+                }
+            }
+        }
         if split_paths.len() != self.segments.len() {
-            return false;
+            return None;
         }
-        split_paths
-            .iter()
-            .zip(self.segments.iter())
-            .all(|(&path, segment)| segment.match_segment(path).is_some())
+        return Some(params);
     }
 
     pub fn match_full(&self, path: &str, method: &HTTPMethod) -> bool {
@@ -259,21 +398,50 @@ impl RouteMapping {
     }
 }
 
+type PanicHandler = Box<dyn Fn(&HTTPRequest, &str) -> HttpResponse + Sync + Send>;
+type ErrorHandler = Box<dyn Fn(&HttpError) -> HttpResponse + Sync + Send>;
+
 pub struct Router {
     routes: Vec<RouteMapping>,
+    middleware: RouteMiddleware,
+    access_log: Option<AccessLog>,
+    metrics: Option<Metrics>,
+    panic_handler: Option<PanicHandler>,
+    fallback: Option<RouteHandler>,
+    error_handlers: HashMap<StatusCode, ErrorHandler>,
+    default_error_handler: ErrorHandler,
+    validators: Vec<(RouteMapping, ValidatorsFn)>,
 }
 
 impl Default for Router {
     fn default() -> Self {
         Self {
             routes: Default::default(),
+            middleware: RouteMiddleware::new(),
+            access_log: None,
+            metrics: None,
+            panic_handler: None,
+            fallback: None,
+            error_handlers: HashMap::new(),
+            default_error_handler: Box::new(error_page),
+            validators: vec![],
         }
     }
 }
 
 impl Router {
     pub fn new() -> Self {
-        return Router { routes: vec![] };
+        return Router {
+            routes: vec![],
+            middleware: RouteMiddleware::new(),
+            access_log: None,
+            metrics: None,
+            panic_handler: None,
+            fallback: None,
+            error_handlers: HashMap::new(),
+            default_error_handler: Box::new(error_page),
+            validators: vec![],
+        };
     }
 
     pub fn route<T>(&mut self, method: HTTPMethod, path: &str, handler: T) -> &mut Self
@@ -285,8 +453,62 @@ impl Router {
         return self;
     }
 
+    /// Serves `files` for GET requests on a wildcard path such as `/static/*`.
+    /// Paths without a file get the router's 404.
+    pub fn serve_static(&mut self, path: &str, files: StaticFiles) -> &mut Self {
+        let files = Arc::new(files);
+        let validators = files.clone();
+        self.validators(path, move |request| validators.validators(request));
+        let mut mapping = RouteMapping::from_path(path);
+        mapping.method = Some(HTTPMethod::GET);
+        mapping.handler = Some(RouteHandler::optional(move |request| files.serve(request)));
+        self.routes.push(mapping);
+        return self;
+    }
+
+    /// Looks up the validators of what `path` currently serves, so that
+    /// conditional requests are answered with a 304 or 412 without running
+    /// the handler. Return `None` when nothing exists at the path yet.
+    pub fn validators<T>(&mut self, path: &str, validators: T) -> &mut Self
+    where
+        T: Fn(&HTTPRequest) -> Option<Validators> + 'static + Sync + Send,
+    {
+        self.validators.push((RouteMapping::from_path(path), Box::new(validators)));
+        return self;
+    }
+
+    fn check_preconditions(&self, request: &HTTPRequest) -> Option<HttpResponse> {
+        let (mapping, validators) = self
+            .validators
+            .iter()
+            .find(|(mapping, _)| mapping.match_path(&request.context.path))?;
+        let mut request = request.clone();
+        request.params = mapping.match_params(&request.context.path).unwrap_or_default();
+        return check_preconditions(&request, validators(&request).as_ref());
+    }
+
+    /// Accepts WebSocket upgrades on GET `path` and hands each connection to
+    /// `handler`, along with the request that opened it.
+    pub fn websocket<T, F>(&mut self, path: &str, handler: T) -> &mut Self
+    where
+        T: Fn(HTTPRequest, WebSocket) -> F + 'static + Sync + Send,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        return self.route(HTTPMethod::GET, path, move |request| {
+            let mut response = match handshake(request) {
+                Ok(response) => response,
+                Err(response) => return response,
+            };
+            let handler = handler.clone();
+            let request = request.clone();
+            response.set_upgrade(move |connection| handler(request, WebSocket::new(connection)));
+            return response;
+        });
+    }
+
     pub fn nest(&mut self, router: Router) -> &mut Self {
-        self.routes.extend(router.routes.into_iter());
+        self.routes.extend(router.routes);
         return self;
     }
 
@@ -298,46 +520,216 @@ impl Router {
             .routes
             .iter_mut()
             .find(|mapping| mapping.match_path(path));
-        if mapping.is_some() {
-            mapping
-                .unwrap()
-                .add_pre_request_middleware(Box::new(middleware))
-        } else {
-            let mut mapping = RouteMapping::from_path(path);
-            mapping.add_pre_request_middleware(Box::new(middleware));
-            self.routes.push(mapping);
+        match mapping {
+            Some(mapping) => mapping.add_pre_request_middleware(Box::new(middleware)),
+            None => {
+                let mut mapping = RouteMapping::from_path(path);
+                mapping.add_pre_request_middleware(Box::new(middleware));
+                self.routes.push(mapping);
+            }
         }
         return self;
     }
 
     pub fn after<T>(&mut self, path: &str, middleware: T) -> &mut Self
     where
-        T: PostRequestMiddleware + 'static + Sync + Send,
+        T: ResponseMiddleware + 'static + Sync + Send,
     {
         let mapping = self
             .routes
             .iter_mut()
             .find(|mapping| mapping.match_path(path));
-        if mapping.is_some() {
-            mapping
-                .unwrap()
-                .add_post_request_middleware(Box::new(middleware))
-        } else {
-            let mut mapping = RouteMapping::from_path(path);
-            mapping.add_post_request_middleware(Box::new(middleware));
-            self.routes.push(mapping);
+        match mapping {
+            Some(mapping) => mapping.add_post_request_middleware(Box::new(middleware)),
+            None => {
+                let mut mapping = RouteMapping::from_path(path);
+                mapping.add_post_request_middleware(Box::new(middleware));
+                self.routes.push(mapping);
+            }
+        }
+        return self;
+    }
+
+    /// Runs `middleware` before every request, ahead of route matching.
+    pub fn before_all<T>(&mut self, middleware: T) -> &mut Self
+    where
+        T: PreRequestMiddleware + 'static + Sync + Send,
+    {
+        self.middleware.pre_request_handlers.push(Box::new(middleware));
+        return self;
+    }
+
+    /// Runs `middleware` on every response, including the 404 or 405 for unmatched paths.
+    pub fn after_all<T>(&mut self, middleware: T) -> &mut Self
+    where
+        T: ResponseMiddleware + 'static + Sync + Send,
+    {
+        self.middleware.post_request_handlers.push(Box::new(middleware));
+        return self;
+    }
+
+    /// Logs every request once its response has been written, including the
+    /// ones answered by the server itself such as handler timeouts.
+    pub fn access_log(&mut self, access_log: AccessLog) -> &mut Self {
+        self.access_log = Some(access_log);
+        return self;
+    }
+
+    /// Starts an access log entry for the request, or `None` when it is not logged.
+    pub fn access_log_entry(&self, context: &HTTPContext, started: Instant) -> Option<AccessLogEntry> {
+        return match &self.access_log {
+            Some(access_log) if !access_log.is_excluded(&context.path) => {
+                Some(AccessLogEntry::new(context, started))
+            }
+            _ => None,
+        };
+    }
+
+    pub fn log_access(&self, entry: &AccessLogEntry) {
+        if let Some(access_log) = &self.access_log {
+            access_log.record(entry);
         }
+    }
+
+    /// Counts requests and connections into `metrics` and serves them on GET `path`.
+    pub fn serve_metrics(&mut self, path: &str, metrics: Metrics) -> &mut Self {
+        self.metrics = Some(metrics.clone());
+        return self.route(HTTPMethod::GET, path, move |_| {
+            let mut response = HttpResponse::new();
+            response
+                .set_header(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+                .set_body(&metrics.render());
+            response
+        });
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        return self.metrics.as_ref();
+    }
+
+    /// The pattern of the route that handles `path`, such as `/users/:id`.
+    pub fn route_pattern(&self, path: &str, method: &HTTPMethod) -> Option<&str> {
+        return self
+            .get_handlers(path, method)
+            .into_iter()
+            .find(|route| route.handler.is_some())
+            .map(|route| route.path.as_str());
+    }
+
+    /// Answers requests no route matched, instead of the 404 or 405 the
+    /// router would send. Middleware runs around it as around any route.
+    pub fn fallback<T>(&mut self, handler: T) -> &mut Self
+    where
+        T: Fn(&mut HTTPRequest) -> HttpResponse + 'static + Sync + Send,
+    {
+        self.fallback = Some(RouteHandler::new(handler));
+        return self;
+    }
+
+    /// Builds the response for errors with `status` that the router or the
+    /// server answers on its own, such as 404, 405, 413 or 503.
+    pub fn error_handler<T>(&mut self, status: StatusCode, handler: T) -> &mut Self
+    where
+        T: Fn(&HttpError) -> HttpResponse + 'static + Sync + Send,
+    {
+        self.error_handlers.insert(status, Box::new(handler));
         return self;
     }
 
+    /// Builds the response for errors without a handler of their own.
+    /// `error_page` by default, which answers with HTML or JSON as the client
+    /// prefers; `default_error_handler(empty_error_response)` sends just the status.
+    pub fn default_error_handler<T>(&mut self, handler: T) -> &mut Self
+    where
+        T: Fn(&HttpError) -> HttpResponse + 'static + Sync + Send,
+    {
+        self.default_error_handler = Box::new(handler);
+        return self;
+    }
+
+    /// The response for an error the router or server answers on its own.
+    pub fn error_response(&self, error: &HttpError) -> HttpResponse {
+        let handler = match self.error_handlers.get(&error.status) {
+            Some(handler) => handler,
+            None => &self.default_error_handler,
+        };
+        return match panic::catch_unwind(AssertUnwindSafe(|| handler(error))) {
+            Ok(response) => response,
+            Err(payload) => {
+                logging::error(
+                    &Span::default(),
+                    &format!("error handler for {} panicked: {}", error.status, panic_message(&*payload)),
+                );
+                empty_error_response(error)
+            }
+        };
+    }
+
+    /// Builds the response for a request whose handler or middleware panicked,
+    /// from the request as far as it got and the panic message. Without one
+    /// the 500 goes through the error handlers.
+    pub fn panic_handler<T>(&mut self, handler: T) -> &mut Self
+    where
+        T: Fn(&HTTPRequest, &str) -> HttpResponse + 'static + Sync + Send,
+    {
+        self.panic_handler = Some(Box::new(handler));
+        return self;
+    }
+
+    /// A panic anywhere in the middleware or handler is answered with a 500
+    /// instead of taking the connection down with it.
     pub fn handle(&self, context: HTTPContext) -> HttpResponse {
-        let handlers = self.get_handlers(&context.path, &context.method);
+        let request = &mut HTTPRequest::new(context);
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| self.handle_request(request))) {
+            Ok(response) => return response,
+            Err(payload) => payload,
+        };
+        let message = panic_message(&*payload);
+        let route = self
+            .route_pattern(&request.context.path, &request.context.method)
+            .unwrap_or(&request.context.path);
+        logging::error(
+            &Span::request(&request.context),
+            &format!("handler for {} {} panicked: {}", request.context.method, route, message),
+        );
+        let error = HttpError::for_request(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "The server failed to handle the request",
+            &request.context,
+        );
+        // The panic handler and middleware are user code too, so if they
+        // panic as well the plain 500 goes out.
+        let response = panic::catch_unwind(AssertUnwindSafe(|| {
+            let response = match &self.panic_handler {
+                Some(panic_handler) => panic_handler(request, &message),
+                None => self.error_response(&error),
+            };
+            self.middleware.post_request_hook(request, response)
+        }));
+        return response.unwrap_or_else(|_| empty_error_response(&error));
+    }
 
-        let request = &mut HTTPRequest { context };
+    fn handle_request(&self, request: &mut HTTPRequest) -> HttpResponse {
+        return match self.middleware.pre_request_hook(request) {
+            Some(response) => response,
+            None => {
+                let response = self.dispatch(request);
+                let response = self.middleware.post_request_hook(request, response);
+                if request.context.method == HTTPMethod::GET {
+                    let response = apply_preconditions(request, response);
+                    return apply_range(request, response);
+                }
+                response
+            }
+        };
+    }
+
+    fn dispatch(&self, request: &mut HTTPRequest) -> HttpResponse {
+        let handlers = self.get_handlers(&request.context.path, &request.context.method);
 
         let mut response: Option<HttpResponse> = None;
         for &handler in handlers.iter() {
-            let handler_response = handler.handle(request);
+            let handler_response = handler.handle(request, |request| self.check_preconditions(request));
             if handler_response.is_some() {
                 response = handler_response;
                 break;
@@ -346,20 +738,101 @@ impl Router {
 
         return match response {
             Some(response) => response,
-            None => {
-                let mut response = HttpResponse::new();
-                response.set_status(StatusCode::NOT_FOUND);
-                return response;
-            }
+            None => self.unmatched(request),
         };
     }
 
+    /// The fallback's response, or a 405 when the path is routed for other
+    /// methods only, or a 404.
+    fn unmatched(&self, request: &mut HTTPRequest) -> HttpResponse {
+        if let Some(response) = self.fallback.as_ref().and_then(|fallback| fallback.call(request)) {
+            return response;
+        }
+        let allowed = self.allowed_methods(&request.context.path);
+        if allowed.is_empty() || allowed.contains(&request.context.method.to_string()) {
+            let message = format!("Nothing is served at {}", request.context.path);
+            let error = HttpError::for_request(StatusCode::NOT_FOUND, &message, &request.context);
+            return self.error_response(&error);
+        }
+        let message = format!("{} is not allowed on {}", request.context.method, request.context.path);
+        let error = HttpError::for_request(StatusCode::METHOD_NOT_ALLOWED, &message, &request.context);
+        let mut response = self.error_response(&error);
+        response.set_header(header::ALLOW, &allowed.join(", "));
+        return response;
+    }
+
+    fn allowed_methods(&self, path: &str) -> Vec<String> {
+        let mut allowed: Vec<String> = vec![];
+        for route in self.routes.iter() {
+            if let (Some(method), Some(_)) = (&route.method, &route.handler) {
+                let method = method.to_string();
+                if route.match_path(path) && !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+        }
+        return allowed;
+    }
+
     fn get_handlers(&self, path: &str, method: &HTTPMethod) -> Vec<&RouteMapping> {
         return self
             .routes
             .iter()
             .filter(|&route| route.match_full(path, method))
-            .map(|route| route)
             .collect::<Vec<_>>();
     }
 }
+
+/// What was passed to `panic!`, which is a `&str` or a `String` unless the
+/// handler used `panic_any`.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    return match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => "unknown panic payload".to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{context_from_parts, RequestLimits};
+
+    /// Written against the borrowing `PostRequestMiddleware`, as middleware
+    /// outside this crate is.
+    struct PoweredBy;
+
+    impl PostRequestMiddleware for PoweredBy {
+        fn handle(&self, _request: &HTTPRequest, response: &HttpResponse) -> PostRequestMiddlewareResult {
+            let mut replacement = HttpResponse::new();
+            replacement.set_status(response.status()).set_header(header::SERVER, "test");
+            return PostRequestMiddlewareResult::Next(replacement);
+        }
+    }
+
+    struct Teapot;
+
+    impl ResponseMiddleware for Teapot {
+        fn handle(&self, _request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+            let mut response = response;
+            response.set_status(StatusCode::IM_A_TEAPOT);
+            return PostRequestMiddlewareResult::End(response);
+        }
+    }
+
+    #[test]
+    fn runs_both_kinds_of_post_request_middleware() {
+        let mut router = Router::new();
+        router
+            .route(HTTPMethod::GET, "/", |_| HttpResponse::new())
+            .after("/", PoweredBy)
+            .after_all(Teapot)
+            .after_all(PoweredBy);
+        let context = context_from_parts("GET", "HTTP/1.1", "/", vec![], vec![], &RequestLimits::default());
+        let response = router.handle(context.unwrap());
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(response.get_header(&header::SERVER), Some("test"));
+    }
+}
@@ -1,127 +1,893 @@
-use std::io::{BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}};
+use http::{header, Version};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
 
-use nom::ParseTo;
 use threadpool::ThreadPool;
 
-use crate::response::HttpResponse;
+use crate::access_log::AccessLogEntry;
+use crate::error::{Error, Phase};
+use crate::error_page::HttpError;
+use crate::handler_pool::HandlerPool;
+use crate::http2;
+use crate::logging::{self, Span};
+use crate::metrics::RequestTimer;
+use crate::request::HTTPContext;
+use crate::response::{HttpResponse, Upgrade};
 use crate::route::Router;
+use crate::stream::{CountingWriter, DeadlineStream, Rewind, WriteTimeout};
+use crate::tls::{TlsConfig, TlsInfo};
 
-use crate::request::{parse_stream, parse_stream_async};
+use crate::request::{
+    parse_stream, parse_stream_async, ParseError, RequestLimits, SUPPORTED_CONTENT_ENCODINGS,
+};
 
-pub struct Server {
-    port: u32,
-    host: String,
-    router: Arc<Router>,
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// How long a kept-alive connection may wait for its next request.
+    pub idle: Duration,
+    /// How long the request line and headers may take to arrive, answered with 408.
+    pub header_read: Duration,
+    /// How long the body may take to arrive after the headers, answered with 408.
+    pub body_read: Duration,
+    /// How long a handler may run before the client is answered with 504.
+    pub handler: Duration,
+    /// How long writing a response may take before the connection is dropped.
+    pub write: Duration,
 }
 
-impl Server {
-    pub fn new(port: u32, host: String) -> Self {
-        return Server {
-            port,
-            host,
-            router: Arc::new(Router::new()),
+impl Default for Timeouts {
+    fn default() -> Self {
+        return Timeouts {
+            idle: Duration::from_secs(5),
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            handler: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// Whether HTTP/1 connections stay open for another request, true by default.
+    pub enabled: bool,
+    /// How many requests one connection may carry before it is closed, unlimited when `None`.
+    pub max_requests: Option<usize>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        return KeepAlive {
+            enabled: true,
+            max_requests: None,
+        };
+    }
+}
+
+impl KeepAlive {
+    /// Whether a connection that has carried `requests` requests may carry another.
+    pub fn allows(&self, requests: usize) -> bool {
+        return match self.max_requests {
+            Some(max_requests) => self.enabled && requests < max_requests,
+            None => self.enabled,
+        };
+    }
+}
+
+/// Collects the settings of a `Server` and binds its listeners.
+///
+/// `ServerBuilder::new().bind("127.0.0.1:4221").bind("[::1]:4221").workers(16).build()?`
+pub struct ServerBuilder {
+    addresses: Vec<String>,
+    workers: usize,
+    limits: RequestLimits,
+    timeouts: Timeouts,
+    keep_alive: KeepAlive,
+    handler_threads: usize,
+    max_queued_handlers: usize,
+    tls: Option<TlsConfig>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        return ServerBuilder::new();
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        return ServerBuilder {
+            addresses: vec![],
+            workers: 8,
+            limits: RequestLimits::default(),
+            timeouts: Timeouts::default(),
+            keep_alive: KeepAlive::default(),
+            handler_threads: 16,
+            max_queued_handlers: 64,
+            tls: None,
         };
     }
 
-    pub fn from_address(address: &str) -> Option<Self> {
-        match address.split_once(":") {
-            Some((host, port)) => Some(Self::new(port.parse_to().unwrap(), host.to_string())),
+    /// Listens on `address`, such as `127.0.0.1:4221`, `[::1]:4221` or
+    /// `localhost:4221`. Every address bound gets a listener of its own.
+    pub fn bind(&mut self, address: &str) -> &mut Self {
+        self.addresses.push(address.to_string());
+        return self;
+    }
+
+    /// How many connections `run` serves at once, 8 by default. `run_async`
+    /// serves on the tokio runtime instead.
+    pub fn workers(&mut self, workers: usize) -> &mut Self {
+        self.workers = workers;
+        return self;
+    }
+
+    pub fn limits(&mut self, limits: RequestLimits) -> &mut Self {
+        self.limits = limits;
+        return self;
+    }
+
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        return self;
+    }
+
+    pub fn keep_alive(&mut self, keep_alive: KeepAlive) -> &mut Self {
+        self.keep_alive = keep_alive;
+        return self;
+    }
+
+    /// How many handlers run at once across all connections, 16 by default.
+    pub fn handler_threads(&mut self, threads: usize) -> &mut Self {
+        self.handler_threads = threads;
+        return self;
+    }
+
+    /// How many requests may wait for a handler thread before the rest are
+    /// answered with 503, 64 by default.
+    pub fn max_queued_handlers(&mut self, max_queued: usize) -> &mut Self {
+        self.max_queued_handlers = max_queued;
+        return self;
+    }
+
+    /// Serves HTTPS instead of plain HTTP, see `Server::use_tls`.
+    pub fn tls(&mut self, tls: TlsConfig) -> &mut Self {
+        self.tls = Some(tls);
+        return self;
+    }
+
+    /// Loads the TLS certificates, if any, and binds every address. Fails when
+    /// there is no address, one cannot be bound, a certificate or key cannot
+    /// be loaded or there are no workers or handler threads.
+    pub fn build(&self) -> Result<Server, Error> {
+        if self.addresses.is_empty() {
+            return Err(invalid_input("no address to bind"));
+        }
+        if self.workers == 0 {
+            return Err(invalid_input("at least one worker is needed"));
+        }
+        if self.handler_threads == 0 {
+            return Err(invalid_input("at least one handler thread is needed"));
+        }
+        let tls = match &self.tls {
+            Some(tls) => Some(tls.server_config(&[b"h2", b"http/1.1"])?),
             None => None,
+        };
+        let mut listeners = vec![];
+        for address in self.addresses.iter() {
+            match TcpListener::bind(address.as_str()) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => return Err(Error::Io(io::Error::new(e.kind(), format!("cannot bind {}: {}", address, e)))),
+            }
         }
+        return Ok(Server {
+            listeners,
+            workers: self.workers,
+            router: Arc::new(Router::new()),
+            limits: self.limits,
+            timeouts: self.timeouts,
+            keep_alive: self.keep_alive,
+            handlers: HandlerPool::new(self.handler_threads, self.max_queued_handlers),
+            tls,
+        });
+    }
+}
+
+/// What every connection of a server shares, cloned into each of them.
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    pub router: Arc<Router>,
+    pub limits: RequestLimits,
+    pub timeouts: Timeouts,
+    pub keep_alive: KeepAlive,
+    pub handlers: HandlerPool,
+}
+
+fn invalid_input(message: &str) -> Error {
+    return Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message.to_string()));
+}
+
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    workers: usize,
+    router: Arc<Router>,
+    limits: RequestLimits,
+    timeouts: Timeouts,
+    keep_alive: KeepAlive,
+    handlers: HandlerPool,
+    tls: Option<Arc<ServerConfig>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        return ServerBuilder::new();
+    }
+
+    /// A server listening on `address` with default settings. Fails when it cannot be bound.
+    pub fn from_address(address: &str) -> Result<Self, Error> {
+        return ServerBuilder::new().bind(address).build();
     }
 
     pub fn use_router(&mut self, router: Router) {
         self.router = Arc::new(router);
     }
 
-    fn address(&self) -> String {
-        return format!("{}:{}", self.host, self.port);
+    pub fn set_limits(&mut self, limits: RequestLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Serves HTTPS instead of plain HTTP. Fails if a certificate or key cannot be loaded.
+    /// Only `run_async` can terminate TLS. Clients may pick HTTP/2 through ALPN.
+    pub fn use_tls(&mut self, tls: &TlsConfig) -> Result<(), Error> {
+        self.tls = Some(tls.server_config(&[b"h2", b"http/1.1"])?);
+        return Ok(());
     }
-    pub fn run<TCallback>(&self, cb: TCallback)
+
+    fn connection_settings(&self) -> ConnectionSettings {
+        return ConnectionSettings {
+            router: self.router.clone(),
+            limits: self.limits,
+            timeouts: self.timeouts,
+            keep_alive: self.keep_alive,
+            handlers: self.handlers.clone(),
+        };
+    }
+
+    /// The addresses actually bound, which tells the port picked for `:0`.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        return self
+            .listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+    }
+
+    /// Serves every listener on a shared pool of worker threads.
+    pub fn run<TCallback>(&self, cb: TCallback) -> Result<(), Error>
     where
         TCallback: Fn() + 'static,
     {
-        let listener = TcpListener::bind(self.address()).unwrap();
+        if self.tls.is_some() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS is only supported by run_async",
+            )));
+        }
+        let pool = ThreadPool::new(self.workers);
+        let mut accepting = vec![];
+        for listener in self.listeners.iter() {
+            let listener = listener.try_clone()?;
+            logging::info(&Span::default(), &format!("listening on {}", listener.local_addr()?));
+            let pool = pool.clone();
+            let settings = self.connection_settings();
+            accepting.push(thread::spawn(move || accept_connections(listener, pool, settings)));
+        }
         cb();
-        let pool = ThreadPool::new(8);
-        for raw_stream in listener.incoming() {
-            let router = self.router.clone();
-            match raw_stream {
-                Ok(mut stream) => pool.execute(move || process_stream(router, &mut stream)),
-                Err(e) => {
-                    println!("error: {}", e);
-                }
-            }
+        for accept in accepting {
+            let _ = accept.join();
         }
+        return Ok(());
     }
 
-    pub async   fn run_async<TCallback>(&self, cb: TCallback)
+    /// Serves every listener on the tokio runtime.
+    pub async   fn run_async<TCallback>(&self, cb: TCallback) -> Result<(), Error>
         where
         TCallback: Fn() + 'static,
     {
-        let listener = tokio::net::TcpListener::bind(self.address()).await.unwrap();
+        let acceptor = self.tls.clone().map(TlsAcceptor::from);
+        let mut accepting = vec![];
+        for listener in self.listeners.iter() {
+            let listener = listener.try_clone()?;
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            logging::info(&Span::default(), &format!("listening on {}", listener.local_addr()?));
+            let settings = self.connection_settings();
+            let acceptor = acceptor.clone();
+            accepting.push(tokio::spawn(accept_connections_async(listener, acceptor, settings)));
+        }
         cb();
-        loop {
-            let conn  = listener.accept().await;
-            let router = self.router.clone();
-            match  conn {
-                Ok((mut stream, _)) => {
-                    tokio::spawn(async move {
-                        process_stream_async(router, &mut stream).await;
-                    });
-                },
-                Err(e) => println!("error: {}", e),
+        for accept in accepting {
+            let _ = accept.await;
+        }
+        return Ok(());
+    }
+}
+
+fn accept_connections(
+    listener: TcpListener,
+    pool: ThreadPool,
+    settings: ConnectionSettings,
+) {
+    for raw_stream in listener.incoming() {
+        let settings = settings.clone();
+        match raw_stream {
+            Ok(stream) => pool.execute(move || {
+                let _connection = settings.router.metrics().map(|metrics| metrics.connection_opened());
+                process_stream(settings, DeadlineStream::new(stream))
+            }),
+            Err(e) => {
+                logging::warn(&Span::default(), &format!("accept failed: {}", e));
             }
+        }
+    }
+}
+
+async fn accept_connections_async(
+    listener: tokio::net::TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    settings: ConnectionSettings,
+) {
+    loop {
+        let conn  = listener.accept().await;
+        let settings = settings.clone();
+        let acceptor = acceptor.clone();
+        match  conn {
+            Ok((stream, peer_addr)) => {
+                let peer_addr = Some(peer_addr);
+                tokio::spawn(async move {
+                    let _connection = settings.router.metrics().map(|metrics| metrics.connection_opened());
+                    match acceptor {
+                        Some(acceptor) => {
+                            let (stream, tls) = match accept_tls(acceptor, stream, settings.timeouts, peer_addr).await {
+                                Some(accepted) => accepted,
+                                None => return,
+                            };
+                            if tls.alpn_protocol.as_deref() == Some(b"h2".as_slice()) {
+                                http2::serve(settings, stream, Some(tls), peer_addr).await;
+                            } else {
+                                process_stream_async(settings, stream, Some(tls), peer_addr).await;
+                            }
+                        }
+                        None => process_stream_async(settings, stream, None, peer_addr).await,
+                    }
+                });
+            },
+            Err(e) => logging::warn(&Span::default(), &format!("accept failed: {}", e)),
+        }
+    }
+}
+
+/// The response for a request the server could not read or handle, from the
+/// router's error handlers, or `None` when the client is past answering.
+/// `accept` is the request's `Accept` header, if it was read.
+pub fn error_response(router: &Router, error: &Error, accept: Option<&str>) -> Option<HttpResponse> {
+    let status_code = error.status_code()?;
+    // What a handler died of is for the log, not the client.
+    let message = match error {
+        Error::Handler(_) => "The server failed to handle the request".to_string(),
+        _ => error.to_string(),
+    };
+    let mut http_error = HttpError::new(status_code, &message);
+    http_error.accept = accept.map(|accept| accept.to_string());
+    let mut response = router.error_response(&http_error);
+    response.remove_header(&header::CONNECTION).set_header(header::CONNECTION, "close");
+    if let Error::Parse(ParseError::UnsupportedEncoding) = error {
+        response.set_header(header::ACCEPT_ENCODING, SUPPORTED_CONTENT_ENCODINGS);
     }
+    return Some(response);
+}
+
+/// Failed handlers are errors and requests the client got an error status
+/// for are worth a warning, while a client that went away mid-request is routine.
+pub fn log_error(span: &Span, error: &Error) {
+    match error {
+        Error::Handler(_) => logging::error(span, &error.to_string()),
+        _ if error.status_code().is_some() => logging::warn(span, &format!("request failed: {}", error)),
+        _ => logging::debug(span, &format!("request not read: {}", error)),
     }
 }
 
-fn write_response(response: HttpResponse, stream: &mut TcpStream) {
-    match response.write(stream) {
-        Ok(_) => (),
-        Err(e) => eprintln!("error: {}", e),
+/// Upgraded protocols are async, so the worker thread drives the connection
+/// on a runtime of its own for as long as the upgrade runs.
+fn run_upgrade(upgrade: Upgrade, reader: BufReader<DeadlineStream>, span: &Span) {
+    let buffered = reader.buffer().to_vec();
+    let stream = reader.into_inner().into_inner();
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            logging::error(span, &format!("upgrade failed: {}", e));
+            return;
+        }
+    };
+    runtime.block_on(async move {
+        let stream = match stream.set_nonblocking(true).and_then(|_| tokio::net::TcpStream::from_std(stream)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                logging::error(span, &format!("upgrade failed: {}", e));
+                return;
+            }
+        };
+        upgrade(Box::new(Rewind::new(buffered, stream))).await;
+    });
+}
+
+/// Answers in the request's version and settles whether the connection
+/// stays open afterwards, which is returned.
+fn prepare_response(response: &mut HttpResponse, version: Version, keep_alive: bool) -> bool {
+    response.set_version(version);
+    let keep_alive = keep_alive && !response.closes_connection() && !response.is_close_delimited();
+    if !keep_alive {
+        response.remove_header(&header::CONNECTION).set_header(header::CONNECTION, "close");
+    } else if version == Version::HTTP_10 {
+        response.set_header(header::CONNECTION, "keep-alive");
+    }
+    return keep_alive;
+}
+
+/// What the access log and metrics track about one request, from when it
+/// began to arrive until its response has been written.
+pub struct RequestRecord {
+    entry: Option<AccessLogEntry>,
+    timer: Option<RequestTimer>,
+}
+
+impl RequestRecord {
+    pub fn start(router: &Router, context: &HTTPContext, started: Instant) -> Self {
+        let timer = router.metrics().map(|metrics| {
+            let route = router.route_pattern(&context.path, &context.method);
+            metrics.request_started(route, context, started)
+        });
+        return RequestRecord {
+            entry: router.access_log_entry(context, started),
+            timer,
+        };
+    }
+
+    pub fn set_response(&mut self, response: &HttpResponse) {
+        if let Some(entry) = self.entry.as_mut() {
+            entry.set_response(response);
+        }
+        if let Some(timer) = self.timer.as_mut() {
+            timer.set_response(response);
+        }
+    }
+
+    /// Records the request once its response is out, with the bytes the
+    /// connection actually took, which may be fewer if writing failed.
+    pub fn finish(self, router: &Router, bytes_sent: u64) {
+        if let Some(mut entry) = self.entry {
+            entry.set_bytes_sent(bytes_sent);
+            router.log_access(&entry);
+        }
+        if let Some(mut timer) = self.timer {
+            timer.set_bytes_sent(bytes_sent);
+            timer.finish();
+        }
     }
 }
 
-async fn write_response_async(response: HttpResponse, stream: &mut tokio::net::TcpStream) {
-    match response.write_async(stream).await {
-        Ok(_) => (),
-        Err(e) => eprintln!("error: {}", e),
+/// Returns whether the response made it to the client in time, and how
+/// many bytes were written either way.
+fn write_response(response: HttpResponse, stream: &mut DeadlineStream, write_timeout: Duration, span: &Span) -> (bool, u64) {
+    if response.is_streaming() {
+        stream.set_write_timeout(write_timeout);
+    } else {
+        stream.set_timeout(write_timeout);
     }
+    let mut bytes_sent = 0;
+    let mut stream = CountingWriter::new(stream, &mut bytes_sent);
+    let written = response.write(&mut stream).and_then(|_| stream.flush().map_err(Error::from));
+    return match written {
+        Ok(_) => (true, bytes_sent),
+        Err(e) => {
+            logging::warn(span, &format!("writing response failed: {}", e));
+            (false, bytes_sent)
+        }
+    };
 }
-async fn process_stream_async(router: Arc<Router>, stream: &mut tokio::net::TcpStream) {
-    match  parse_stream_async(stream).await {
-        Ok(context) => {
-            let response = router.handle(context);
-            write_response_async(response, stream).await;
-            match stream.flush().await {
-                Ok(_) => (),
-                Err(_) => eprintln!("An error occured when flushing stream"),
+
+async fn write_and_flush_async(response: HttpResponse, stream: &mut (impl AsyncWrite + Unpin)) -> Result<(), Error> {
+    response.write_async(stream).await?;
+    stream.flush().await?;
+    return Ok(());
+}
+
+/// Returns whether the response made it to the client in time, and how
+/// many bytes were written either way.
+async fn write_response_async(
+    response: HttpResponse,
+    stream: &mut (impl AsyncWrite + Unpin),
+    write_timeout: Duration,
+    span: &Span,
+) -> (bool, u64) {
+    let mut bytes_sent = 0;
+    let mut stream = CountingWriter::new(stream, &mut bytes_sent);
+    let written = match response.is_streaming() {
+        true => write_and_flush_async(response, &mut WriteTimeout::new(&mut stream, write_timeout)).await,
+        false => match timeout(write_timeout, write_and_flush_async(response, &mut stream)).await {
+            Ok(written) => written,
+            Err(_) => Err(Error::Timeout(Phase::WritingResponse)),
+        },
+    };
+    return match written {
+        Ok(_) => (true, bytes_sent),
+        Err(e) => {
+            logging::warn(span, &format!("writing response failed: {}", e));
+            (false, bytes_sent)
+        }
+    };
+}
+
+/// Runs the handler on the handler pool, waiting at most `handler_timeout` for it.
+fn handle(
+    handlers: &HandlerPool,
+    router: Arc<Router>,
+    context: HTTPContext,
+    handler_timeout: Duration,
+) -> Result<HttpResponse, Error> {
+    let (sender, receiver) = mpsc::channel();
+    handlers.execute(move || {
+        let _ = sender.send(router.handle(context));
+    })?;
+    return match receiver.recv_timeout(handler_timeout) {
+        Ok(response) => Ok(response),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout(Phase::Handling)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(Error::Handler("handler thread ended without a response".to_string()))
+        }
+    };
+}
+
+/// Runs the handler on the handler pool so a slow handler cannot stall the runtime.
+pub async fn handle_async(
+    handlers: &HandlerPool,
+    router: Arc<Router>,
+    context: HTTPContext,
+    handler_timeout: Duration,
+) -> Result<HttpResponse, Error> {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    handlers.execute(move || {
+        let _ = sender.send(router.handle(context));
+    })?;
+    return match timeout(handler_timeout, receiver).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(_)) => Err(Error::Handler("handler thread ended without a response".to_string())),
+        Err(_) => Err(Error::Timeout(Phase::Handling)),
+    };
+}
+
+/// Runs the TLS handshake, bounded by the header read timeout.
+async fn accept_tls(
+    acceptor: TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    timeouts: Timeouts,
+    peer_addr: Option<SocketAddr>,
+) -> Option<(tokio_rustls::server::TlsStream<tokio::net::TcpStream>, TlsInfo)> {
+    let stream = match timeout(timeouts.header_read, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            logging::warn(&Span::connection(peer_addr), &format!("tls handshake failed: {}", e));
+            return None;
+        }
+        Err(_) => {
+            logging::warn(&Span::connection(peer_addr), "timed out during tls handshake");
+            return None;
+        }
+    };
+    let connection = stream.get_ref().1;
+    let tls = TlsInfo {
+        server_name: connection.server_name().map(|name| name.to_string()),
+        alpn_protocol: connection.alpn_protocol().map(|protocol| protocol.to_vec()),
+        client_certificates: connection.peer_certificates().unwrap_or(&[]).to_vec(),
+    };
+    return Some((stream, tls));
+}
+
+async fn process_stream_async<S>(
+    settings: ConnectionSettings,
+    stream: S,
+    tls: Option<TlsInfo>,
+    peer_addr: Option<SocketAddr>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ConnectionSettings { router, limits, timeouts, keep_alive, handlers } = settings.clone();
+    let span = Span::connection(peer_addr);
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut requests = 0;
+    loop {
+        match timeout(timeouts.idle, reader.fill_buf()).await {
+            // Clients with prior knowledge of HTTP/2 (h2c) open with its preface.
+            Ok(Ok(buffer)) if requests == 0 && http2::is_preface(buffer) => {
+                return http2::serve(settings, reader, tls, peer_addr).await;
+            }
+            Ok(Ok(buffer)) if !buffer.is_empty() => (),
+            _ => break,
+        }
+        requests += 1;
+        let started = Instant::now();
+
+        let keep_alive = match parse_stream_async(
+            &mut reader,
+            &limits,
+            timeouts.header_read,
+            timeouts.body_read,
+        )
+        .await
+        {
+            Ok(mut context) => {
+                context.tls = tls.clone();
+                context.peer_addr = peer_addr;
+                if let Some(frames) = http2::upgrade_frames(&context) {
+                    return http2::serve_upgrade(settings, reader, frames, tls, peer_addr).await;
+                }
+                let keep_alive = keep_alive.allows(requests) && context.keep_alive();
+                let version = context.version();
+                let span = Span::request(&context);
+                let mut record = RequestRecord::start(&router, &context, started);
+                let accept = context.get_header("accept").map(|accept| accept.to_string());
+                let response = match handle_async(&handlers, router.clone(), context, timeouts.handler).await {
+                    Ok(response) => Some(response),
+                    Err(e) => {
+                        log_error(&span, &e);
+                        error_response(&router, &e, accept.as_deref())
+                    }
+                };
+                match response {
+                    Some(mut response) => {
+                        let keep_alive = prepare_response(&mut response, version, keep_alive);
+                        let span = span.response(&response);
+                        record.set_response(&response);
+                        let upgrade = response.take_upgrade();
+                        let (written, bytes_sent) =
+                            write_response_async(response, reader.get_mut(), timeouts.write, &span).await;
+                        record.finish(&router, bytes_sent);
+                        if let (true, Some(upgrade)) = (written, upgrade) {
+                            return upgrade(Box::new(reader)).await;
+                        }
+                        written && keep_alive
+                    }
+                    None => false,
+                }
             }
+            Err(e) => {
+                log_error(&span, &e);
+                if let Some(metrics) = router.metrics() {
+                    metrics.parse_error(&e);
+                }
+                if let Some(response) = error_response(&router, &e, None) {
+                    write_response_async(response, reader.get_mut(), timeouts.write, &span).await;
+                }
+                false
+            }
+        };
+        if !keep_alive {
+            break;
         }
-        Err(e) => eprintln!("stream error: {}", e),
     }
+
+    let _ = reader.get_mut().shutdown().await;
 }
-fn process_stream(router: Arc<Router>, stream: &mut TcpStream) {
-    match parse_stream(stream) {
-        Ok(context) => {
-            let response = router.handle(context);
-            write_response(response, stream);
-            flush_stream(stream);
+
+fn process_stream(settings: ConnectionSettings, stream: DeadlineStream) {
+    let ConnectionSettings { router, limits, timeouts, keep_alive, handlers } = settings;
+    let peer_addr = stream.get_ref().peer_addr().ok();
+    let span = Span::connection(peer_addr);
+    let mut reader = BufReader::new(stream);
+    let mut requests = 0;
+    loop {
+        reader.get_mut().set_timeout(timeouts.idle);
+        match reader.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => (),
+            _ => break,
+        }
+        requests += 1;
+        let started = Instant::now();
+
+        let keep_alive = match parse_stream(&mut reader, &limits, timeouts.header_read, timeouts.body_read) {
+            Ok(mut context) => {
+                context.peer_addr = peer_addr;
+                let keep_alive = keep_alive.allows(requests) && context.keep_alive();
+                let version = context.version();
+                let span = Span::request(&context);
+                let mut record = RequestRecord::start(&router, &context, started);
+                let accept = context.get_header("accept").map(|accept| accept.to_string());
+                let response = match handle(&handlers, router.clone(), context, timeouts.handler) {
+                    Ok(response) => Some(response),
+                    Err(e) => {
+                        log_error(&span, &e);
+                        error_response(&router, &e, accept.as_deref())
+                    }
+                };
+                match response {
+                    Some(mut response) => {
+                        let keep_alive = prepare_response(&mut response, version, keep_alive);
+                        let span = span.response(&response);
+                        record.set_response(&response);
+                        let upgrade = response.take_upgrade();
+                        let (written, bytes_sent) = write_response(response, reader.get_mut(), timeouts.write, &span);
+                        record.finish(&router, bytes_sent);
+                        if let (true, Some(upgrade)) = (written, upgrade) {
+                            return run_upgrade(upgrade, reader, &span);
+                        }
+                        written && keep_alive
+                    }
+                    None => false,
+                }
+            }
+            Err(e) => {
+                log_error(&span, &e);
+                if let Some(metrics) = router.metrics() {
+                    metrics.parse_error(&e);
+                }
+                if let Some(response) = error_response(&router, &e, None) {
+                    write_response(response, reader.get_mut(), timeouts.write, &span);
+                }
+                false
+            }
+        };
+        if !keep_alive {
+            break;
         }
-        Err(e) => eprintln!("stream error: {}", e),
     }
 
-    let _ = stream.shutdown(std::net::Shutdown::Both);
+    let _ = reader.get_ref().get_ref().shutdown(std::net::Shutdown::Both);
 }
 
-fn flush_stream(stream: &mut TcpStream) {
-    match stream.flush() {
-        Ok(_) => (),
-        Err(_) => eprintln!("An error occured when flushing stream"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_log::{AccessLog, AccessLogFormat};
+    use crate::logging::LogSink;
+    use crate::metrics::Metrics;
+    use crate::request::HTTPMethod;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+
+    fn slow_server() -> Server {
+        let timeouts = Timeouts {
+            handler: Duration::from_millis(300),
+            ..Timeouts::default()
+        };
+        let mut server = Server::builder()
+            .bind("127.0.0.1:0")
+            .handler_threads(1)
+            .max_queued_handlers(0)
+            .timeouts(timeouts)
+            .build()
+            .unwrap();
+        let mut router = Router::new();
+        router.route(HTTPMethod::GET, "/slow", |_request| {
+            thread::sleep(Duration::from_secs(1));
+            return HttpResponse::new();
+        });
+        server.use_router(router);
+        return server;
+    }
+
+    fn status_of(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        return response.lines().next().unwrap_or_default().to_string();
+    }
+
+    /// The first request holds the only handler thread past its timeout, so
+    /// it gets 504 while the one behind it finds the pool full and gets 503.
+    fn assert_timeout_and_overload(address: SocketAddr) {
+        let first = thread::spawn(move || status_of(address, "/slow"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(status_of(address, "/slow"), "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(first.join().unwrap(), "HTTP/1.1 504 Gateway Timeout");
+    }
+
+    #[test]
+    fn fails_to_build_with_a_bad_tls_config() {
+        let mut builder = Server::builder();
+        builder.bind("127.0.0.1:0").tls(TlsConfig::new("missing/chain.pem", "missing/key.pem"));
+        assert!(matches!(builder.build(), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn bounds_handlers_when_running_sync() {
+        let server = slow_server();
+        let address = server.local_addrs()[0];
+        thread::spawn(move || server.run(|| ()));
+        assert_timeout_and_overload(address);
+    }
+
+    /// Collects the access log lines.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>);
+
+    impl LogSink for Lines {
+        fn write_line(&self, line: &str) -> io::Result<()> {
+            self.0.lock().unwrap().push(line.to_string());
+            return Ok(());
+        }
+    }
+
+    fn logged_server(lines: Lines, metrics: Metrics) -> Server {
+        let mut server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+        let mut router = Router::new();
+        router
+            .route(HTTPMethod::GET, "/hello", |_request| {
+                let mut response = HttpResponse::new();
+                response.set_body("hello world");
+                return response;
+            })
+            .access_log(AccessLog::new(AccessLogFormat::Common, lines))
+            .serve_metrics("/metrics", metrics);
+        server.use_router(router);
+        return server;
+    }
+
+    /// The access log and metrics report what went over the wire, headers included.
+    fn assert_counts_bytes_sent(address: SocketAddr, lines: &Lines, metrics: &Metrics) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).unwrap();
+        let line = lines.0.lock().unwrap().last().cloned().unwrap();
+        assert!(line.ends_with(&format!(" 200 {}", received.len())), "{}", line);
+        let total = format!("http_response_bytes_total {}\n", received.len());
+        assert!(metrics.render().contains(&total), "{}", metrics.render());
+    }
+
+    #[test]
+    fn counts_bytes_sent_when_running_sync() {
+        let (lines, metrics) = (Lines::default(), Metrics::new());
+        let server = logged_server(lines.clone(), metrics.clone());
+        let address = server.local_addrs()[0];
+        thread::spawn(move || server.run(|| ()));
+        assert_counts_bytes_sent(address, &lines, &metrics);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn counts_bytes_sent_when_running_async() {
+        let (lines, metrics) = (Lines::default(), Metrics::new());
+        let server = logged_server(lines.clone(), metrics.clone());
+        let address = server.local_addrs()[0];
+        tokio::spawn(async move { server.run_async(|| ()).await });
+        tokio::task::spawn_blocking(move || assert_counts_bytes_sent(address, &lines, &metrics))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bounds_handlers_when_running_async() {
+        let server = slow_server();
+        let address = server.local_addrs()[0];
+        tokio::spawn(async move { server.run_async(|| ()).await });
+        tokio::task::spawn_blocking(move || assert_timeout_and_overload(address))
+            .await
+            .unwrap();
     }
 }
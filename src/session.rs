@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::cookie::{self, Cookie, CookieError, SameSite};
+use crate::logging::{self, Span};
+use crate::response::HttpResponse;
+use crate::route::{
+    HTTPRequest, PostRequestMiddlewareResult, PreRequestMiddleware, PreRequestMiddlewareResult,
+    ResponseMiddleware,
+};
+
+pub type SessionData = HashMap<String, String>;
+
+/// Backing storage for session data, keyed by session id.
+pub trait SessionStore {
+    /// Returns `None` for unknown or expired sessions.
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()>;
+    fn destroy(&self, id: &str) -> io::Result<()>;
+}
+
+const SESSION_ID_BYTES: usize = 32;
+
+fn generate_session_id() -> String {
+    let mut bytes = [0u8; SESSION_ID_BYTES];
+    getrandom::getrandom(&mut bytes).expect("operating system random source is unavailable");
+    return URL_SAFE_NO_PAD.encode(bytes);
+}
+
+/// Ids come straight from a client cookie, so anything we did not generate is rejected
+/// before it reaches a store (and, for `FileSessionStore`, the filesystem).
+fn is_valid_session_id(id: &str) -> bool {
+    return id.len() == URL_SAFE_NO_PAD.encode([0u8; SESSION_ID_BYTES]).len()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    id: String,
+    data: SessionData,
+    is_new: bool,
+    changed: bool,
+    destroyed: bool,
+    previous_id: Option<String>,
+}
+
+impl Session {
+    fn new() -> Self {
+        return Session {
+            id: generate_session_id(),
+            data: HashMap::new(),
+            is_new: true,
+            changed: false,
+            destroyed: false,
+            previous_id: None,
+        };
+    }
+
+    fn existing(id: String, data: SessionData) -> Self {
+        return Session {
+            id,
+            data,
+            is_new: false,
+            changed: false,
+            destroyed: false,
+            previous_id: None,
+        };
+    }
+
+    pub fn id(&self) -> &str {
+        return &self.id;
+    }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        return self.data.get(key);
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> &mut Self {
+        self.data.insert(key.to_string(), value.to_string());
+        self.changed = true;
+        return self;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.changed = true;
+        return self.data.remove(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.changed = true;
+    }
+
+    /// Drops the session from the store and tells the client to forget its cookie.
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+
+    /// Moves the data to a fresh id, e.g. after login, to prevent session fixation.
+    pub fn regenerate(&mut self) {
+        if self.previous_id.is_none() && !self.is_new {
+            self.previous_id = Some(self.id.clone());
+        }
+        self.id = generate_session_id();
+        self.changed = true;
+    }
+}
+
+impl HTTPRequest {
+    /// The session loaded by `SessionMiddleware`, if it is installed on the router.
+    pub fn session(&mut self) -> Option<&mut Session> {
+        return self.extensions.get_mut::<Session>();
+    }
+}
+
+/// Loads the session before each request and persists it afterwards.
+///
+/// Install the same instance on both sides of the router:
+/// `router.before_all(sessions.clone()).after_all(sessions);`
+#[derive(Clone)]
+pub struct SessionMiddleware {
+    store: Arc<dyn SessionStore + Send + Sync>,
+    cookie_name: String,
+    cookie_path: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl SessionMiddleware {
+    pub fn new<T>(store: T) -> Self
+    where
+        T: SessionStore + 'static + Send + Sync,
+    {
+        return SessionMiddleware {
+            store: Arc::new(store),
+            cookie_name: "session_id".to_string(),
+            cookie_path: "/".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            same_site: SameSite::Lax,
+        };
+    }
+
+    pub fn cookie_name(&mut self, cookie_name: &str) -> Result<&mut Self, CookieError> {
+        if !cookie::is_valid_name(cookie_name) {
+            return Err(CookieError::InvalidName(cookie_name.to_string()));
+        }
+        self.cookie_name = cookie_name.to_string();
+        return Ok(self);
+    }
+
+    pub fn cookie_path(&mut self, cookie_path: &str) -> Result<&mut Self, CookieError> {
+        if !cookie::is_valid_attribute(cookie_path) {
+            return Err(CookieError::InvalidAttribute {
+                attribute: "Path",
+                value: cookie_path.to_string(),
+            });
+        }
+        self.cookie_path = cookie_path.to_string();
+        return Ok(self);
+    }
+
+    pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = ttl;
+        return self;
+    }
+
+    pub fn secure(&mut self, secure: bool) -> &mut Self {
+        self.secure = secure;
+        return self;
+    }
+
+    pub fn same_site(&mut self, same_site: SameSite) -> &mut Self {
+        self.same_site = same_site;
+        return self;
+    }
+
+    fn load_session(&self, request: &HTTPRequest) -> Session {
+        let id = match request.cookie(&self.cookie_name) {
+            Some(id) if is_valid_session_id(&id) => id,
+            _ => return Session::new(),
+        };
+        return match self.store.load(&id) {
+            Ok(Some(data)) => Session::existing(id, data),
+            Ok(None) => Session::new(),
+            Err(e) => {
+                logging::error(&Span::request(&request.context), &format!("loading session failed: {}", e));
+                Session::new()
+            }
+        };
+    }
+
+    fn session_cookie(&self, id: &str) -> Cookie {
+        // The name and path were checked by the setters and ids are base64url.
+        let mut cookie = Cookie::new(&self.cookie_name, id).expect("session cookie is valid");
+        cookie
+            .path(&self.cookie_path)
+            .expect("session cookie path is valid")
+            .max_age(self.ttl)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        return cookie;
+    }
+
+    fn persist(&self, session: &Session, response: &mut HttpResponse) -> io::Result<()> {
+        if let Some(previous_id) = &session.previous_id {
+            self.store.destroy(previous_id)?;
+        }
+        if session.destroyed {
+            if !session.is_new {
+                self.store.destroy(&session.id)?;
+            }
+            let mut cookie = Cookie::removal(&self.cookie_name).expect("session cookie is valid");
+            cookie.path(&self.cookie_path).expect("session cookie path is valid");
+            response.set_cookie(&cookie);
+            return Ok(());
+        }
+        // Untouched new sessions are not stored, so anonymous traffic does not fill the store.
+        if session.is_new && !session.changed {
+            return Ok(());
+        }
+        self.store.save(&session.id, &session.data, self.ttl)?;
+        response.set_cookie(&self.session_cookie(&session.id));
+        return Ok(());
+    }
+}
+
+impl PreRequestMiddleware for SessionMiddleware {
+    fn handle(&self, request: &mut HTTPRequest) -> PreRequestMiddlewareResult {
+        let session = self.load_session(request);
+        request.extensions.insert(session);
+        return PreRequestMiddlewareResult::Next;
+    }
+}
+
+impl ResponseMiddleware for SessionMiddleware {
+    fn handle(&self, request: &HTTPRequest, response: HttpResponse) -> PostRequestMiddlewareResult {
+        let mut response = response;
+        if let Some(session) = request.extensions.get::<Session>() {
+            if let Err(e) = self.persist(session, &mut response) {
+                logging::error(&Span::request(&request.context), &format!("saving session failed: {}", e));
+            }
+        }
+        return PostRequestMiddlewareResult::Next(response);
+    }
+}
+
+/// Keeps sessions in process memory; they are lost on restart.
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl Default for MemorySessionStore {
+    fn default() -> Self {
+        return MemorySessionStore::new();
+    }
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        return MemorySessionStore {
+            sessions: Mutex::new(HashMap::new()),
+        };
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        return match sessions.get(id) {
+            Some((data, expires)) if *expires > SystemTime::now() => Ok(Some(data.clone())),
+            Some(_) => {
+                sessions.remove(id);
+                Ok(None)
+            }
+            None => Ok(None),
+        };
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(id.to_string(), (data.clone(), now + ttl));
+        return Ok(());
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        return Ok(());
+    }
+}
+
+/// Keeps one file per session in `directory`.
+///
+/// The first line holds the expiry as unix seconds, every following line a
+/// base64 encoded key and value separated by a space. An expired file is
+/// removed when it is next loaded; sessions nobody comes back for stay on
+/// disk until `remove_expired` runs, so call it every now and then.
+pub struct FileSessionStore {
+    directory: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(directory: &str) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        return Ok(FileSessionStore {
+            directory: PathBuf::from(directory),
+        });
+    }
+
+    fn session_path(&self, id: &str) -> io::Result<PathBuf> {
+        if !is_valid_session_id(id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid session id"));
+        }
+        return Ok(self.directory.join(id));
+    }
+
+    /// Deletes every expired session file and returns how many there were.
+    /// Files that are not sessions, such as in-flight writes, are left alone.
+    pub fn remove_expired(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let is_session = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => is_valid_session_id(name),
+                None => false,
+            };
+            if !is_session {
+                continue;
+            }
+            let file = match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if is_expired(read_expiry(&mut BufReader::new(file).lines())?) {
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => removed += 1,
+                }
+            }
+        }
+        return Ok(removed);
+    }
+}
+
+fn read_expiry<T: BufRead>(lines: &mut io::Lines<T>) -> io::Result<u64> {
+    return match lines.next() {
+        Some(line) => line?.trim().parse().map_err(|_| invalid_data("invalid session expiry")),
+        None => Err(invalid_data("empty session file")),
+    };
+}
+
+fn is_expired(expires: u64) -> bool {
+    return UNIX_EPOCH + Duration::from_secs(expires) <= SystemTime::now();
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+fn decode_field(field: &str) -> io::Result<String> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(field)
+        .map_err(|_| invalid_data("invalid session field"))?;
+    return String::from_utf8(bytes).map_err(|_| invalid_data("invalid session field"));
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let path = self.session_path(id)?;
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut lines = BufReader::new(file).lines();
+        if is_expired(read_expiry(&mut lines)?) {
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+
+        let mut data = HashMap::new();
+        for line in lines {
+            let line = line?;
+            let (key, value) = match line.split_once(" ") {
+                Some(pair) => pair,
+                None => return Err(invalid_data("invalid session entry")),
+            };
+            data.insert(decode_field(key)?, decode_field(value)?);
+        }
+        return Ok(Some(data));
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let path = self.session_path(id)?;
+        let expires = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut contents = format!("{}\n", expires);
+        for (key, value) in data.iter() {
+            contents.push_str(&format!(
+                "{} {}\n",
+                URL_SAFE_NO_PAD.encode(key),
+                URL_SAFE_NO_PAD.encode(value)
+            ));
+        }
+
+        // Write to a temporary file first so a concurrent load never sees a
+        // partial session. Each write gets its own, so concurrent saves of one
+        // session cannot interleave; the last rename wins.
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix).expect("operating system random source is unavailable");
+        let temporary_path = self
+            .directory
+            .join(format!("{}.{}.tmp", id, URL_SAFE_NO_PAD.encode(suffix)));
+        let written = fs::File::create(&temporary_path).and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            return file.sync_all();
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temporary_path, path)) {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        return match fs::remove_file(self.session_path(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header;
+
+    use crate::request::{context_from_parts, HTTPHeader, HTTPMethod, RequestLimits};
+    use crate::response::ResponseBody;
+    use crate::route::Router;
+
+    fn scratch_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("sessions-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        return directory;
+    }
+
+    fn data(pairs: &[(&str, &str)]) -> SessionData {
+        return pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+    }
+
+    /// Save, load, expire and destroy, which every store must get right.
+    fn check_store(store: &dyn SessionStore) {
+        let id = generate_session_id();
+        let stored = data(&[("user", "ada lovelace"), ("note", "line one\nline two")]);
+        assert_eq!(store.load(&id).unwrap(), None);
+        store.save(&id, &stored, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load(&id).unwrap(), Some(stored.clone()));
+
+        store.destroy(&id).unwrap();
+        assert_eq!(store.load(&id).unwrap(), None);
+        store.destroy(&id).unwrap();
+
+        store.save(&id, &stored, Duration::ZERO).unwrap();
+        assert_eq!(store.load(&id).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_store_keeps_sessions_until_they_expire() {
+        check_store(&MemorySessionStore::new());
+    }
+
+    #[test]
+    fn file_store_keeps_sessions_until_they_expire() {
+        let directory = scratch_directory("round-trip");
+        let store = FileSessionStore::new(directory.to_str().unwrap()).unwrap();
+        check_store(&store);
+
+        let id = generate_session_id();
+        store.save(&id, &data(&[]), Duration::ZERO).unwrap();
+        assert!(directory.join(&id).exists());
+        assert_eq!(store.load(&id).unwrap(), None);
+        assert!(!directory.join(&id).exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_rejects_ids_it_did_not_generate() {
+        let directory = scratch_directory("ids");
+        let store = FileSessionStore::new(directory.to_str().unwrap()).unwrap();
+        let padding = "a".repeat(generate_session_id().len() - 3);
+        for id in ["", "short", "../../etc/passwd", &format!("../{}", padding), &format!("{}.tm", padding)] {
+            assert_eq!(store.load(id).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", id);
+            assert!(store.save(id, &data(&[]), Duration::from_secs(60)).is_err());
+            assert!(store.destroy(id).is_err());
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_saves_concurrently_without_tearing() {
+        let directory = scratch_directory("concurrent");
+        let store = Arc::new(FileSessionStore::new(directory.to_str().unwrap()).unwrap());
+        let id = generate_session_id();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let store = store.clone();
+                let id = id.clone();
+                std::thread::spawn(move || {
+                    let value = writer.to_string().repeat(4096);
+                    for _ in 0..20 {
+                        store.save(&id, &data(&[("a", &value), ("b", &value)]), Duration::from_secs(60)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let loaded = store.load(&id).unwrap().unwrap();
+        assert_eq!(loaded.get("a"), loaded.get("b"));
+        assert_eq!(loaded["a"].len(), 4096);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_removes_expired_sessions() {
+        let directory = scratch_directory("sweep");
+        let store = FileSessionStore::new(directory.to_str().unwrap()).unwrap();
+        let (expired, live) = (generate_session_id(), generate_session_id());
+        store.save(&expired, &data(&[]), Duration::ZERO).unwrap();
+        store.save(&live, &data(&[("user", "ada")]), Duration::from_secs(60)).unwrap();
+        fs::write(directory.join("notes.txt"), "not a session").unwrap();
+
+        assert_eq!(store.remove_expired().unwrap(), 1);
+        assert!(!directory.join(&expired).exists());
+        assert_eq!(store.load(&live).unwrap(), Some(data(&[("user", "ada")])));
+        assert!(directory.join("notes.txt").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    fn session_router() -> Router {
+        let sessions = SessionMiddleware::new(MemorySessionStore::new());
+        let mut router = Router::new();
+        router
+            .before_all(sessions.clone())
+            .after_all(sessions)
+            .route(HTTPMethod::GET, "/peek", |_| HttpResponse::new())
+            .route(HTTPMethod::GET, "/visit", |request| {
+                let session = request.session().unwrap();
+                let visits = session.get("visits").map_or(0, |visits| visits.parse().unwrap()) + 1;
+                session.insert("visits", &visits.to_string());
+                let mut response = HttpResponse::new();
+                response.set_body(&visits.to_string());
+                return response;
+            })
+            .route(HTTPMethod::GET, "/login", |request| {
+                request.session().unwrap().regenerate();
+                return HttpResponse::new();
+            })
+            .route(HTTPMethod::GET, "/logout", |request| {
+                request.session().unwrap().destroy();
+                return HttpResponse::new();
+            });
+        return router;
+    }
+
+    /// The `Set-Cookie` header and body of a GET to `path` sending `id`.
+    fn get(router: &Router, path: &str, id: Option<&str>) -> (Option<String>, String) {
+        let headers = match id {
+            Some(id) => vec![HTTPHeader("Cookie".to_string(), format!("theme=dark; session_id={}", id))],
+            None => vec![],
+        };
+        let context = context_from_parts("GET", "HTTP/1.1", path, headers, vec![], &RequestLimits::default());
+        let response = router.handle(context.unwrap());
+        let body = match response.body() {
+            Some(ResponseBody::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => String::new(),
+        };
+        return (response.get_header(&header::SET_COOKIE).map(|value| value.to_string()), body);
+    }
+
+    fn session_id(set_cookie: &str) -> String {
+        let value = set_cookie.strip_prefix("session_id=").unwrap();
+        return value.split(";").next().unwrap().to_string();
+    }
+
+    #[test]
+    fn middleware_issues_and_reissues_the_session_cookie() {
+        let router = session_router();
+        assert_eq!(get(&router, "/peek", None), (None, String::new()));
+
+        let (set_cookie, body) = get(&router, "/visit", None);
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.ends_with("; Path=/; Max-Age=86400; HttpOnly; SameSite=Lax"), "{}", set_cookie);
+        assert_eq!(body, "1");
+        let id = session_id(&set_cookie);
+
+        let (set_cookie, body) = get(&router, "/visit", Some(&id));
+        assert_eq!(session_id(&set_cookie.unwrap()), id);
+        assert_eq!(body, "2");
+
+        // An id the store does not know, or one we could never have issued, starts over.
+        let (set_cookie, body) = get(&router, "/visit", Some(&generate_session_id()));
+        assert_ne!(session_id(&set_cookie.unwrap()), id);
+        assert_eq!(body, "1");
+        let (_, body) = get(&router, "/visit", Some("../../etc/passwd"));
+        assert_eq!(body, "1");
+    }
+
+    #[test]
+    fn middleware_moves_regenerated_sessions_and_forgets_destroyed_ones() {
+        let router = session_router();
+        let id = session_id(&get(&router, "/visit", None).0.unwrap());
+
+        let new_id = session_id(&get(&router, "/login", Some(&id)).0.unwrap());
+        assert_ne!(new_id, id);
+        assert_eq!(get(&router, "/visit", Some(&new_id)).1, "2");
+        assert_eq!(get(&router, "/visit", Some(&id)).1, "1");
+
+        let (set_cookie, _) = get(&router, "/logout", Some(&new_id));
+        assert!(set_cookie.unwrap().starts_with("session_id=; Path=/; Max-Age=0"));
+        assert_eq!(get(&router, "/visit", Some(&new_id)).1, "1");
+    }
+}
@@ -0,0 +1,275 @@
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use http::header;
+use tokio::sync::mpsc::Receiver;
+
+use crate::response::{HttpResponse, ResponseBody};
+use crate::route::HTTPRequest;
+
+/// One message of a `text/event-stream` response.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    /// An unnamed event, delivered to the client's `onmessage`. Line breaks
+    /// in `data` are kept and arrive as `\n`.
+    pub fn new(data: &str) -> Self {
+        return Event {
+            data: Some(data.to_string()),
+            ..Default::default()
+        };
+    }
+
+    /// A comment line, which clients ignore.
+    pub fn comment(comment: &str) -> Self {
+        return Event {
+            comment: Some(comment.to_string()),
+            ..Default::default()
+        };
+    }
+
+    /// Remembered by the client and sent back as `Last-Event-ID` when it reconnects.
+    pub fn id(&mut self, id: &str) -> &mut Self {
+        self.id = Some(id.replace(['\r', '\n', '\0'], ""));
+        return self;
+    }
+
+    /// The event type, dispatched to listeners registered for it instead of `onmessage`.
+    pub fn event(&mut self, event: &str) -> &mut Self {
+        self.event = Some(event.replace(['\r', '\n', '\0'], ""));
+        return self;
+    }
+
+    /// How long the client waits before reconnecting after the stream ends.
+    pub fn retry(&mut self, retry: Duration) -> &mut Self {
+        self.retry = Some(retry);
+        return self;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = String::new();
+        if let Some(comment) = &self.comment {
+            for line in split_lines(comment) {
+                message.push_str(&format!(":{}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            message.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            message.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = &self.retry {
+            message.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            for line in split_lines(data) {
+                message.push_str(&format!("data: {}\n", line));
+            }
+        }
+        message.push('\n');
+        return message.into_bytes();
+    }
+}
+
+/// Splits on every line ending the client does. `lines` would drop a trailing
+/// empty line and treat a lone `\r` as text, letting it start a new field.
+fn split_lines(text: &str) -> Vec<String> {
+    return text.replace("\r\n", "\n").split(['\n', '\r']).map(|line| line.to_string()).collect();
+}
+
+struct ChannelStream(Receiver<Event>);
+
+impl Stream for ChannelStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        return self.get_mut().0.poll_recv(cx);
+    }
+}
+
+/// Events written to the client as they are produced, until the source ends
+/// or the client goes away. Each event has the server's write timeout to
+/// itself rather than sharing one with the whole response.
+pub struct EventStream {
+    source: Pin<Box<dyn Stream<Item = Event> + Send + Sync>>,
+    heartbeat: Option<Duration>,
+}
+
+impl EventStream {
+    /// Sends every event pushed into the channel. Sync handlers can feed it
+    /// from any thread with `Sender::blocking_send`.
+    pub fn from_channel(receiver: Receiver<Event>) -> Self {
+        return EventStream::from_stream(ChannelStream(receiver));
+    }
+
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Event> + Send + Sync + 'static,
+    {
+        return EventStream {
+            source: Box::pin(stream),
+            heartbeat: Some(Duration::from_secs(15)),
+        };
+    }
+
+    /// How long the stream may stay quiet before a comment is sent to keep
+    /// proxies from closing it and to notice clients that left, 15 seconds
+    /// by default. `None` sends no heartbeats.
+    pub fn heartbeat(&mut self, heartbeat: Option<Duration>) -> &mut Self {
+        self.heartbeat = heartbeat;
+        return self;
+    }
+
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        return self.heartbeat;
+    }
+
+    /// The next event, or `None` once the source has ended.
+    pub async fn next(&mut self) -> Option<Event> {
+        return poll_fn(|cx| self.source.as_mut().poll_next(cx)).await;
+    }
+}
+
+/// The comment sent when the stream has been quiet for a heartbeat interval.
+pub const HEARTBEAT: &[u8] = b":\n\n";
+
+impl HttpResponse {
+    /// Turns the response into a `text/event-stream` that stays open while
+    /// `events` produces events.
+    pub fn set_event_stream(&mut self, events: EventStream) -> &mut Self {
+        return self
+            .set_header(header::CONTENT_TYPE, "text/event-stream")
+            .set_header(header::CACHE_CONTROL, "no-cache")
+            .set_response_body(ResponseBody::Events(events));
+    }
+}
+
+impl HTTPRequest {
+    /// The id of the last event a reconnecting client saw, to resume after it.
+    pub fn last_event_id(&self) -> Option<&str> {
+        return self.context.get_header("last-event-id");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use tokio::sync::mpsc;
+
+    use crate::request::{context_from_parts, HTTPHeader, HTTPMethod, RequestLimits};
+    use crate::route::Router;
+
+    fn text(event: &Event) -> String {
+        return String::from_utf8(event.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn frames_events() {
+        assert_eq!(text(&Event::new("hello")), "data: hello\n\n");
+
+        let mut event = Event::new("{\"price\": 3}");
+        event.event("quote").id("42").retry(Duration::from_secs(3));
+        assert_eq!(text(&event), "event: quote\nid: 42\nretry: 3000\ndata: {\"price\": 3}\n\n");
+
+        let mut event = Event::new("");
+        event.retry(Duration::from_millis(1500));
+        assert_eq!(text(&event), "retry: 1500\ndata: \n\n");
+    }
+
+    #[test]
+    fn splits_multi_line_data_on_every_line_ending() {
+        assert_eq!(text(&Event::new("a\nb\r\nc\rd")), "data: a\ndata: b\ndata: c\ndata: d\n\n");
+        assert_eq!(text(&Event::new("trailing\n")), "data: trailing\ndata: \n\n");
+    }
+
+    #[test]
+    fn keeps_comments_and_fields_from_injecting_lines() {
+        assert_eq!(text(&Event::comment("keep-alive")), ":keep-alive\n\n");
+        assert_eq!(text(&Event::comment("a\rdata: x")), ":a\n:data: x\n\n");
+        assert_eq!(text(&Event::comment("a\r\nb\nc")), ":a\n:b\n:c\n\n");
+
+        let mut event = Event::new("x");
+        event.event("quote\rdata: injected\0").id("1\n\0data: injected");
+        assert_eq!(text(&event), "event: quotedata: injected\nid: 1data: injected\ndata: x\n\n");
+    }
+
+    async fn written(events: EventStream) -> Vec<u8> {
+        let mut written = vec![];
+        ResponseBody::Events(events).write_async(&mut written).await.unwrap();
+        return written;
+    }
+
+    #[tokio::test]
+    async fn sends_heartbeats_while_the_stream_is_quiet() {
+        let (sender, receiver) = mpsc::channel(1);
+        let mut events = EventStream::from_channel(receiver);
+        events.heartbeat(Some(Duration::from_millis(20)));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            sender.send(Event::new("late")).await.unwrap();
+        });
+
+        let written = String::from_utf8(written(events).await).unwrap();
+        let (heartbeats, rest) = written.split_at(written.find("data:").unwrap());
+        assert!(heartbeats.len() >= HEARTBEAT.len() * 2, "{:?}", written);
+        assert_eq!(heartbeats, ":\n\n".repeat(heartbeats.len() / HEARTBEAT.len()));
+        assert_eq!(rest, "data: late\n\n");
+    }
+
+    #[tokio::test]
+    async fn sends_no_heartbeats_when_disabled() {
+        let (sender, receiver) = mpsc::channel(2);
+        let mut events = EventStream::from_channel(receiver);
+        events.heartbeat(None);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            sender.send(Event::new("one")).await.unwrap();
+            sender.send(Event::comment("two")).await.unwrap();
+        });
+
+        let started = Instant::now();
+        assert_eq!(written(events).await, b"data: one\n\n:two\n\n");
+        assert!(started.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn passes_last_event_id_to_the_handler() {
+        let mut router = Router::new();
+        router.route(HTTPMethod::GET, "/events", |request| {
+            let mut response = HttpResponse::new();
+            response.set_body(request.last_event_id().unwrap_or("none"));
+            return response;
+        });
+        for (headers, expected) in [(vec![HTTPHeader("Last-Event-ID".to_string(), "17".to_string())], "17"), (vec![], "none")] {
+            let context = context_from_parts("GET", "HTTP/1.1", "/events", headers, vec![], &RequestLimits::default());
+            let response = router.handle(context.unwrap());
+            match response.body() {
+                Some(ResponseBody::Bytes(bytes)) => assert_eq!(bytes, expected.as_bytes()),
+                _ => panic!("expected a buffered body"),
+            }
+        }
+    }
+
+    #[test]
+    fn sets_event_stream_headers() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut response = HttpResponse::new();
+        response.set_event_stream(EventStream::from_channel(receiver));
+        assert_eq!(response.get_header(&header::CONTENT_TYPE), Some("text/event-stream"));
+        assert_eq!(response.get_header(&header::CACHE_CONTROL), Some("no-cache"));
+        assert!(response.is_streaming());
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use http::header;
+
+use crate::conditional::{file_etag, Validators};
+use crate::response::HttpResponse;
+use crate::route::HTTPRequest;
+
+/// Serves files below a directory, mounted on a wildcard route such as `/static/*`.
+pub struct StaticFiles {
+    root: PathBuf,
+    index_file: String,
+}
+
+impl StaticFiles {
+    pub fn new(directory: &str) -> io::Result<Self> {
+        return Ok(StaticFiles {
+            root: fs::canonicalize(directory)?,
+            index_file: "index.html".to_string(),
+        });
+    }
+
+    /// The file served when a directory is requested, `index.html` by default.
+    pub fn index_file(&mut self, index_file: &str) -> &mut Self {
+        self.index_file = index_file.to_string();
+        return self;
+    }
+
+    /// The file the request's `*` parameter names, or `None` when there is
+    /// no such file or the path leaves the root.
+    pub fn serve(&self, request: &HTTPRequest) -> Option<HttpResponse> {
+        let relative_path = request.param("*").unwrap_or("");
+        let path = self.resolve(relative_path)?;
+        let file = File::open(&path).ok()?;
+        let metadata = file.metadata().ok().filter(|metadata| metadata.is_file())?;
+
+        let mut response = HttpResponse::new();
+        response
+            .set_header(header::CONTENT_TYPE, content_type(&path))
+            .set_header(header::ACCEPT_RANGES, "bytes");
+        if let Ok(modified) = metadata.modified() {
+            response
+                .set_header(header::LAST_MODIFIED, &httpdate::fmt_http_date(modified))
+                .set_header(header::ETAG, &file_etag(metadata.len(), modified));
+        }
+        response.set_file(file, metadata.len());
+        return Some(response);
+    }
+
+    /// The validators `serve` would send for the request, from the file's
+    /// metadata alone, or `None` if there is no such file.
+    pub fn validators(&self, request: &HTTPRequest) -> Option<Validators> {
+        let path = self.resolve(request.param("*").unwrap_or(""))?;
+        let metadata = fs::metadata(path).ok().filter(|metadata| metadata.is_file())?;
+        return Some(match metadata.modified() {
+            Ok(modified) => Validators {
+                etag: Some(file_etag(metadata.len(), modified)),
+                last_modified: Some(httpdate::fmt_http_date(modified)),
+            },
+            Err(_) => Validators::default(),
+        });
+    }
+
+    /// Maps a request path onto a file below the root, or `None` if it would escape it.
+    fn resolve(&self, relative_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(relative_path)?;
+        let mut path = self.root.clone();
+        for segment in decoded.split("/") {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                segment if segment.contains('\\') || segment.contains('\0') => return None,
+                segment => path.push(segment),
+            }
+        }
+
+        let mut path = self.canonicalize(&path)?;
+        if path.is_dir() {
+            path = self.canonicalize(&path.join(&self.index_file))?;
+        }
+        return Some(path);
+    }
+
+    /// Resolves symlinks so a link pointing outside the root is refused as well.
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        let path = fs::canonicalize(path).ok()?;
+        if !path.starts_with(&self.root) {
+            return None;
+        }
+        return Some(path);
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+/// Guesses the `Content-Type` from the file extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    return match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use http::StatusCode;
+
+    use crate::error_page::HttpError;
+    use crate::request::{context_from_parts, RequestLimits};
+    use crate::response::ResponseBody;
+    use crate::route::Router;
+
+    /// A fresh directory holding `root/` with a few files and, next to it,
+    /// `outside/secret.txt` that nothing below `root/` may reach.
+    fn fixture(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("static-files-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("root/docs")).unwrap();
+        fs::create_dir_all(directory.join("outside")).unwrap();
+        fs::write(directory.join("root/docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(directory.join("root/style.css"), "body {}").unwrap();
+        fs::write(directory.join("root/app.js"), "run()").unwrap();
+        fs::write(directory.join("root/data.unknown"), "data").unwrap();
+        fs::write(directory.join("outside/secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(directory.join("outside/secret.txt"), directory.join("root/link.txt")).unwrap();
+        return directory;
+    }
+
+    fn request(path: &str) -> HTTPRequest {
+        let context = context_from_parts("GET", "HTTP/1.1", "/", vec![], vec![], &RequestLimits::default());
+        let mut request = HTTPRequest::new(context.unwrap());
+        request.params.insert("*".to_string(), path.to_string());
+        return request;
+    }
+
+    fn body(response: &HttpResponse) -> String {
+        let mut text = String::new();
+        match response.body() {
+            Some(ResponseBody::File { file, .. }) => (&*file).read_to_string(&mut text).unwrap(),
+            _ => panic!("expected a file body"),
+        };
+        return text;
+    }
+
+    #[test]
+    fn streams_files_with_their_content_type() {
+        let directory = fixture("types");
+        let files = StaticFiles::new(directory.join("root").to_str().unwrap()).unwrap();
+        let cases = [
+            ("style.css", "text/css; charset=utf-8", "body {}"),
+            ("app.js", "text/javascript; charset=utf-8", "run()"),
+            ("data.unknown", "application/octet-stream", "data"),
+        ];
+        for (path, content_type, text) in cases {
+            let response = files.serve(&request(path)).unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.get_header(&header::CONTENT_TYPE), Some(content_type));
+            assert!(response.get_header(&header::ETAG).is_some());
+            assert_eq!(body(&response), text);
+        }
+        assert_eq!(content_type(Path::new("LOGO.PNG")), "image/png");
+        assert_eq!(content_type(Path::new("Makefile")), "application/octet-stream");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn serves_the_index_file_for_directories() {
+        let directory = fixture("index");
+        let mut files = StaticFiles::new(directory.join("root").to_str().unwrap()).unwrap();
+        for path in ["docs", "docs/", "./docs"] {
+            let response = files.serve(&request(path)).unwrap();
+            assert_eq!(response.get_header(&header::CONTENT_TYPE), Some("text/html; charset=utf-8"));
+            assert_eq!(body(&response), "<h1>docs</h1>");
+        }
+        assert!(files.serve(&request("")).is_none());
+        files.index_file("missing.html");
+        assert!(files.serve(&request("docs")).is_none());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_root() {
+        let directory = fixture("traversal");
+        let files = StaticFiles::new(directory.join("root").to_str().unwrap()).unwrap();
+        let paths = [
+            "../outside/secret.txt",
+            "docs/../../outside/secret.txt",
+            "%2e%2e/outside/secret.txt",
+            "%2E%2E%2Foutside%2Fsecret.txt",
+            "..\\outside\\secret.txt",
+            "style.css%00",
+            "bad%zz",
+            "missing.txt",
+        ];
+        for path in paths {
+            assert!(files.serve(&request(path)).is_none(), "{}", path);
+            assert!(files.validators(&request(path)).is_none(), "{}", path);
+        }
+        #[cfg(unix)]
+        assert!(files.serve(&request("link.txt")).is_none());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn answers_missing_files_through_the_error_handlers() {
+        let directory = fixture("router");
+        let mut router = Router::new();
+        router
+            .serve_static("/static/*", StaticFiles::new(directory.join("root").to_str().unwrap()).unwrap())
+            .error_handler(StatusCode::NOT_FOUND, |error: &HttpError| {
+                let mut response = HttpResponse::new();
+                response.set_status(error.status).set_body("custom not found");
+                return response;
+            });
+        let handle = |method: &str, path: &str| {
+            let context = context_from_parts(method, "HTTP/1.1", path, vec![], vec![], &RequestLimits::default());
+            return router.handle(context.unwrap());
+        };
+
+        assert_eq!(handle("GET", "/static/style.css").status(), StatusCode::OK);
+        let response = handle("GET", "/static/%2e%2e/outside/secret.txt");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        match response.body() {
+            Some(ResponseBody::Bytes(bytes)) => assert_eq!(bytes, b"custom not found"),
+            _ => panic!("expected the custom error page"),
+        }
+        assert_eq!(handle("GET", "/static/missing.txt").status(), StatusCode::NOT_FOUND);
+        assert_eq!(handle("POST", "/static/style.css").status(), StatusCode::METHOD_NOT_ALLOWED);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
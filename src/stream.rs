@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// A blocking stream whose reads and writes fail once a deadline has passed.
+///
+/// `TcpStream::set_read_timeout` only bounds a single syscall, so a client
+/// trickling one byte at a time could otherwise hold a worker forever.
+pub struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+    write_timeout: Option<Duration>,
+}
+
+impl DeadlineStream {
+    pub fn new(stream: TcpStream) -> Self {
+        return DeadlineStream {
+            stream,
+            deadline: None,
+            write_timeout: None,
+        };
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+        self.write_timeout = None;
+    }
+
+    /// Gives every write `timeout` of its own instead of a shared deadline,
+    /// for responses that stay open as long as they have something to send.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.deadline = None;
+        self.write_timeout = Some(timeout);
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        return &self.stream;
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        return self.stream;
+    }
+
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(None),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline elapsed"));
+        }
+        return Ok(Some(deadline - now));
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining()?;
+        self.stream.set_read_timeout(remaining)?;
+        return self.stream.read(buf);
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = match self.write_timeout {
+            Some(write_timeout) => Some(write_timeout),
+            None => self.remaining()?,
+        };
+        self.stream.set_write_timeout(remaining)?;
+        return self.stream.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.stream.flush();
+    }
+}
+
+pub fn is_timeout(error: &io::Error) -> bool {
+    return matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    );
+}
+
+/// A connection taken over from the server after an upgrade, whatever transport it runs on.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Replays `prefix` before reading on from `inner`.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        return Rewind {
+            prefix,
+            position: 0,
+            inner,
+        };
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position < this.prefix.len() {
+            let length = buffer.remaining().min(this.prefix.len() - this.position);
+            buffer.put_slice(&this.prefix[this.position..this.position + length]);
+            this.position += length;
+            return Poll::Ready(Ok(()));
+        }
+        return Pin::new(&mut this.inner).poll_read(cx, buffer);
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        return Pin::new(&mut self.get_mut().inner).poll_write(cx, buffer);
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.get_mut().inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.get_mut().inner).poll_shutdown(cx);
+    }
+}
+
+/// The async counterpart of `DeadlineStream::set_write_timeout`: fails a
+/// write that makes no progress for `timeout`, however long they add up to.
+pub struct WriteTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> WriteTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        return WriteTimeout {
+            inner,
+            timeout,
+            sleep: None,
+        };
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        return &mut self.inner;
+    }
+
+    /// Starts the clock on a write that could not complete right away, and
+    /// stops it again once one does.
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.sleep = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep(timeout)));
+        return match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"))),
+            Poll::Pending => Poll::Pending,
+        };
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buffer);
+        return this.check(cx, poll);
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        return this.check(cx, poll);
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        return this.check(cx, poll);
+    }
+}
+
+/// Adds up the bytes written through it into `written`, which outlives the
+/// writer so the count survives a write that was cut short, e.g. by a timeout.
+pub struct CountingWriter<'a, S> {
+    inner: S,
+    written: &'a mut u64,
+}
+
+impl<'a, S> CountingWriter<'a, S> {
+    pub fn new(inner: S, written: &'a mut u64) -> Self {
+        return CountingWriter { inner, written };
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        return &mut self.inner;
+    }
+}
+
+impl<S: Write> Write for CountingWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        *self.written += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buffer);
+        if let Poll::Ready(Ok(written)) = poll {
+            *this.written += written as u64;
+        }
+        return poll;
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.get_mut().inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.get_mut().inner).poll_shutdown(cx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn counts_bytes_written() {
+        let mut written = 0;
+        let mut output = vec![];
+        let mut writer = CountingWriter::new(&mut output, &mut written);
+        Write::write_all(&mut writer, b"hello ").unwrap();
+        Write::write_all(&mut writer, b"world").unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(output, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_written_async() {
+        let mut written = 0;
+        let (client, mut server) = tokio::io::duplex(4);
+        let mut writer = CountingWriter::new(client, &mut written);
+        // Only 4 bytes fit, so the count stops at what the other side took.
+        let write = tokio::time::timeout(Duration::from_millis(50), writer.write_all(b"hello world")).await;
+        assert!(write.is_err());
+        drop(writer);
+        assert_eq!(written, 4);
+        let mut received = [0; 4];
+        tokio::io::AsyncReadExt::read_exact(&mut server, &mut received).await.unwrap();
+        assert_eq!(&received, b"hell");
+    }
+}
@@ -0,0 +1,536 @@
+use std::io;
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, StatusCode, Version};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+
+use crate::request::HTTPMethod;
+use crate::response::HttpResponse;
+use crate::route::HTTPRequest;
+use crate::stream::Connection;
+
+/// Appended to the client's key before hashing it into `Sec-WebSocket-Accept` (RFC 6455, 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const SUPPORTED_VERSION: &str = "13";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const MAX_CONTROL_PAYLOAD: usize = 125;
+/// How long `close` waits for the client to answer the close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a pong automatically, surfaced for handlers that care.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The close code and reason, if the peer sent them.
+    Close(Option<(u16, String)>),
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    return STANDARD.encode(hasher.finalize());
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    let mut response = HttpResponse::new();
+    response.set_status(StatusCode::BAD_REQUEST).set_body(message);
+    return response;
+}
+
+/// Checks an opening handshake (RFC 6455, 4.2.1) and builds the `101` answer,
+/// or the error response explaining why the upgrade is refused.
+pub fn handshake(request: &HTTPRequest) -> Result<HttpResponse, HttpResponse> {
+    let context = &request.context;
+    if context.method != HTTPMethod::GET || context.version() != Version::HTTP_11 {
+        return Err(bad_request("WebSocket upgrades need an HTTP/1.1 GET request"));
+    }
+    if !context.header_has_token("upgrade", "websocket") || !context.header_has_token("connection", "upgrade") {
+        let mut response = HttpResponse::new();
+        response
+            .set_status(StatusCode::UPGRADE_REQUIRED)
+            .set_header(header::UPGRADE, "websocket")
+            .set_header(header::CONNECTION, "Upgrade");
+        return Err(response);
+    }
+    if context.get_header("sec-websocket-version").map(|version| version.trim()) != Some(SUPPORTED_VERSION) {
+        let mut response = HttpResponse::new();
+        response
+            .set_status(StatusCode::UPGRADE_REQUIRED)
+            .set_header(header::SEC_WEBSOCKET_VERSION, SUPPORTED_VERSION);
+        return Err(response);
+    }
+    let key = match context.get_header("sec-websocket-key") {
+        Some(key) if matches!(STANDARD.decode(key.trim()), Ok(nonce) if nonce.len() == 16) => key,
+        _ => return Err(bad_request("Missing or invalid Sec-WebSocket-Key")),
+    };
+
+    let mut response = HttpResponse::new();
+    response
+        .set_status(StatusCode::SWITCHING_PROTOCOLS)
+        .set_header(header::UPGRADE, "websocket")
+        .set_header(header::CONNECTION, "Upgrade")
+        .set_header(header::SEC_WEBSOCKET_ACCEPT, &accept_key(key));
+    return Ok(response);
+}
+
+/// Why reading a frame failed: the connection broke, or the peer broke the
+/// protocol and is sent a close frame with the given code.
+enum ReadError {
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        return ReadError::Io(error);
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+fn is_valid_close_code(code: u16) -> bool {
+    return matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+}
+
+/// A WebSocket connection after the handshake, exchanging whole messages.
+///
+/// Fragmented messages are reassembled, pings are answered and the close
+/// handshake is completed on either side. Dropping the socket closes the
+/// connection without a close frame, so prefer `close`.
+pub struct WebSocket {
+    stream: BufReader<Box<dyn Connection>>,
+    max_message_size: usize,
+    fragment_size: usize,
+    /// The opcode and payload of a fragmented message still being received.
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    pub fn new(connection: Box<dyn Connection>) -> Self {
+        return WebSocket {
+            stream: BufReader::new(connection),
+            max_message_size: 16 * 1024 * 1024,
+            fragment_size: 64 * 1024,
+            fragments: None,
+            close_sent: false,
+            closed: false,
+        };
+    }
+
+    /// Larger incoming messages are refused with close code 1009. 16 MiB by default.
+    pub fn max_message_size(&mut self, max_message_size: usize) -> &mut Self {
+        self.max_message_size = max_message_size;
+        return self;
+    }
+
+    /// Outgoing messages are split into frames of at most this many bytes. 64 KiB by default.
+    pub fn fragment_size(&mut self, fragment_size: usize) -> &mut Self {
+        self.fragment_size = fragment_size.max(1);
+        return self;
+    }
+
+    /// The next message, or `None` once the connection is closed. A `Close`
+    /// message is returned once, after it has been answered.
+    pub async fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.closed {
+            return Ok(None);
+        }
+        loop {
+            let frame = match self.read_frame().await {
+                Ok(frame) => frame,
+                Err(ReadError::Io(e)) => {
+                    self.closed = true;
+                    return Err(e);
+                }
+                Err(ReadError::Protocol(code, reason)) => return Err(self.fail(code, reason).await),
+            };
+            match self.handle_frame(frame).await {
+                Ok(Some(message)) => return Ok(Some(message)),
+                Ok(None) => continue,
+                Err(ReadError::Io(e)) => {
+                    self.closed = true;
+                    return Err(e);
+                }
+                Err(ReadError::Protocol(code, reason)) => return Err(self.fail(code, reason).await),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "close frame already sent"));
+        }
+        return match message {
+            Message::Text(text) => self.write_message(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(bytes) => self.write_message(OPCODE_BINARY, &bytes).await,
+            Message::Ping(payload) => self.write_control(OPCODE_PING, &payload).await,
+            Message::Pong(payload) => self.write_control(OPCODE_PONG, &payload).await,
+            Message::Close(close) => {
+                let (code, reason) = close.unwrap_or((CLOSE_NORMAL, String::new()));
+                self.close(code, &reason).await
+            }
+        };
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        return self.write_message(OPCODE_TEXT, text.as_bytes()).await;
+    }
+
+    pub async fn send_binary(&mut self, bytes: &[u8]) -> io::Result<()> {
+        return self.write_message(OPCODE_BINARY, bytes).await;
+    }
+
+    /// Starts the close handshake and waits briefly for the client to answer it.
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.close_sent {
+            self.write_close(code, reason).await?;
+        }
+        let stream = &mut self.stream;
+        let closed = &mut self.closed;
+        // Whatever the client still sends before its close frame is dropped.
+        let drain = async {
+            while !*closed {
+                let mut header = [0u8; 2];
+                stream.read_exact(&mut header).await?;
+                let masked = header[1] & 0x80 != 0;
+                let length = match header[1] & 0x7f {
+                    126 => stream.read_u16().await? as u64,
+                    127 => stream.read_u64().await?,
+                    length => length as u64,
+                };
+                let skip = length + if masked { 4 } else { 0 };
+                tokio::io::copy(&mut (&mut *stream).take(skip), &mut tokio::io::sink()).await?;
+                *closed = header[0] & 0x0f == OPCODE_CLOSE;
+            }
+            return Ok::<(), io::Error>(());
+        };
+        let _ = timeout(CLOSE_TIMEOUT, drain).await;
+        self.closed = true;
+        return self.stream.get_mut().shutdown().await;
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, ReadError> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).await?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        if header[0] & 0x70 != 0 {
+            return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if header[1] & 0x80 == 0 {
+            return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+        }
+        let length = match header[1] & 0x7f {
+            126 => self.stream.read_u16().await? as u64,
+            127 => self.stream.read_u64().await?,
+            length => length as u64,
+        };
+        if opcode >= OPCODE_CLOSE && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+        }
+        let buffered = self.fragments.as_ref().map(|(_, payload)| payload.len()).unwrap_or(0);
+        if length > (self.max_message_size - buffered.min(self.max_message_size)) as u64 {
+            return Err(ReadError::Protocol(CLOSE_TOO_BIG, "message too big"));
+        }
+
+        let mut mask = [0u8; 4];
+        self.stream.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; length as usize];
+        self.stream.read_exact(&mut payload).await?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        return Ok(Frame { fin, opcode, payload });
+    }
+
+    /// Turns a frame into a message, or `None` while a fragmented message is incomplete.
+    async fn handle_frame(&mut self, frame: Frame) -> Result<Option<Message>, ReadError> {
+        let (opcode, payload) = match frame.opcode {
+            OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => {
+                return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "expected a continuation frame"));
+            }
+            OPCODE_TEXT | OPCODE_BINARY if !frame.fin => {
+                self.fragments = Some((frame.opcode, frame.payload));
+                return Ok(None);
+            }
+            OPCODE_TEXT | OPCODE_BINARY => (frame.opcode, frame.payload),
+            OPCODE_CONTINUATION => match self.fragments.as_mut() {
+                Some((_, payload)) => {
+                    payload.extend(frame.payload);
+                    if !frame.fin {
+                        return Ok(None);
+                    }
+                    self.fragments.take().unwrap()
+                }
+                None => return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "unexpected continuation frame")),
+            },
+            OPCODE_PING => {
+                self.write_control(OPCODE_PONG, &frame.payload).await?;
+                return Ok(Some(Message::Ping(frame.payload)));
+            }
+            OPCODE_PONG => return Ok(Some(Message::Pong(frame.payload))),
+            OPCODE_CLOSE => return self.handle_close(frame.payload).await.map(Some),
+            _ => return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+        };
+
+        if opcode == OPCODE_BINARY {
+            return Ok(Some(Message::Binary(payload)));
+        }
+        return match String::from_utf8(payload) {
+            Ok(text) => Ok(Some(Message::Text(text))),
+            Err(_) => Err(ReadError::Protocol(CLOSE_INVALID_DATA, "text is not valid UTF-8")),
+        };
+    }
+
+    async fn handle_close(&mut self, payload: Vec<u8>) -> Result<Message, ReadError> {
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "truncated close code")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "invalid close code"));
+                }
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some((code, reason)),
+                    Err(_) => return Err(ReadError::Protocol(CLOSE_INVALID_DATA, "close reason is not valid UTF-8")),
+                }
+            }
+        };
+        if !self.close_sent {
+            let code = close.as_ref().map(|(code, _)| *code).unwrap_or(CLOSE_NORMAL);
+            self.write_close(code, "").await?;
+        }
+        self.closed = true;
+        let _ = self.stream.get_mut().shutdown().await;
+        return Ok(Message::Close(close));
+    }
+
+    /// Answers a protocol violation with a close frame and gives up on the connection.
+    async fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        if !self.close_sent {
+            let _ = self.write_close(code, reason).await;
+        }
+        self.closed = true;
+        let _ = self.stream.get_mut().shutdown().await;
+        return io::Error::new(io::ErrorKind::InvalidData, reason);
+    }
+
+    async fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(if fin { 0x80 } else { 0 } | opcode);
+        // Server frames are never masked.
+        match payload.len() {
+            length if length <= 125 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).await?;
+        return self.stream.flush().await;
+    }
+
+    async fn write_message(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "close frame already sent"));
+        }
+        if payload.len() <= self.fragment_size {
+            return self.write_frame(true, opcode, payload).await;
+        }
+        let fragments: Vec<&[u8]> = payload.chunks(self.fragment_size).collect();
+        for (index, fragment) in fragments.iter().enumerate() {
+            let opcode = if index == 0 { opcode } else { OPCODE_CONTINUATION };
+            self.write_frame(index == fragments.len() - 1, opcode, fragment).await?;
+        }
+        return Ok(());
+    }
+
+    async fn write_control(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "control frame payload too long"));
+        }
+        return self.write_frame(true, opcode, payload).await;
+    }
+
+    async fn write_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.close_sent = true;
+        return self.write_frame(true, OPCODE_CLOSE, &payload).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            length if length <= 125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ MASK[index % 4]));
+        return frame;
+    }
+
+    fn connect() -> (WebSocket, DuplexStream) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        return (WebSocket::new(Box::new(server)), client);
+    }
+
+    /// Reads one unmasked server frame: fin, opcode and payload.
+    async fn server_frame(client: &mut DuplexStream) -> (bool, u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[1] & 0x80, 0, "server frames must not be masked");
+        let length = match header[1] & 0x7f {
+            126 => client.read_u16().await.unwrap() as usize,
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        client.read_exact(&mut payload).await.unwrap();
+        return (header[0] & 0x80 != 0, header[0] & 0x0f, payload);
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // The example from RFC 6455, section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn unmasks_client_frames() {
+        let (mut socket, mut client) = connect();
+        client.write_all(&client_frame(true, OPCODE_TEXT, b"Hello")).await.unwrap();
+        let long = vec![7u8; 300];
+        client.write_all(&client_frame(true, OPCODE_BINARY, &long)).await.unwrap();
+        assert_eq!(socket.recv().await.unwrap(), Some(Message::Text("Hello".to_string())));
+        assert_eq!(socket.recv().await.unwrap(), Some(Message::Binary(long)));
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragmented_messages_around_control_frames() {
+        let (mut socket, mut client) = connect();
+        client.write_all(&client_frame(false, OPCODE_TEXT, b"Hel")).await.unwrap();
+        client.write_all(&client_frame(true, OPCODE_PING, b"hi")).await.unwrap();
+        client.write_all(&client_frame(false, OPCODE_CONTINUATION, b"lo, ")).await.unwrap();
+        client.write_all(&client_frame(true, OPCODE_CONTINUATION, "wörld".as_bytes())).await.unwrap();
+
+        assert_eq!(socket.recv().await.unwrap(), Some(Message::Ping(b"hi".to_vec())));
+        assert_eq!(server_frame(&mut client).await, (true, OPCODE_PONG, b"hi".to_vec()));
+        assert_eq!(socket.recv().await.unwrap(), Some(Message::Text("Hello, wörld".to_string())));
+    }
+
+    #[tokio::test]
+    async fn fragments_outgoing_messages_without_masking() {
+        let (mut socket, mut client) = connect();
+        socket.fragment_size(2);
+        socket.send_text("abcde").await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (false, OPCODE_TEXT, b"ab".to_vec()));
+        assert_eq!(server_frame(&mut client).await, (false, OPCODE_CONTINUATION, b"cd".to_vec()));
+        assert_eq!(server_frame(&mut client).await, (true, OPCODE_CONTINUATION, b"e".to_vec()));
+        socket.send_binary(b"xy").await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (true, OPCODE_BINARY, b"xy".to_vec()));
+    }
+
+    async fn assert_fails_with(frames: &[Vec<u8>], code: u16, configure: fn(&mut WebSocket)) {
+        let (mut socket, mut client) = connect();
+        configure(&mut socket);
+        for frame in frames.iter() {
+            client.write_all(frame).await.unwrap();
+        }
+        let mut result = socket.recv().await;
+        while let Ok(Some(_)) = result {
+            result = socket.recv().await;
+        }
+        assert!(result.is_err());
+        let (fin, opcode, payload) = server_frame(&mut client).await;
+        assert!(fin);
+        assert_eq!(opcode, OPCODE_CLOSE);
+        assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), code);
+        assert_eq!(socket.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn closes_on_protocol_violations() {
+        let unmasked = vec![0x81, 0x02, b'h', b'i'];
+        assert_fails_with(&[unmasked], CLOSE_PROTOCOL_ERROR, |_| ()).await;
+        let orphan = client_frame(true, OPCODE_CONTINUATION, b"x");
+        assert_fails_with(&[orphan], CLOSE_PROTOCOL_ERROR, |_| ()).await;
+        let interleaved = vec![client_frame(false, OPCODE_TEXT, b"a"), client_frame(true, OPCODE_TEXT, b"b")];
+        assert_fails_with(&interleaved, CLOSE_PROTOCOL_ERROR, |_| ()).await;
+        let fragmented_ping = client_frame(false, OPCODE_PING, b"");
+        assert_fails_with(&[fragmented_ping], CLOSE_PROTOCOL_ERROR, |_| ()).await;
+        let invalid_utf8 = client_frame(true, OPCODE_TEXT, &[0xff, 0xfe]);
+        assert_fails_with(&[invalid_utf8], CLOSE_INVALID_DATA, |_| ()).await;
+        let too_big = vec![client_frame(false, OPCODE_BINARY, &[0; 6]), client_frame(true, OPCODE_CONTINUATION, &[0; 6])];
+        assert_fails_with(&too_big, CLOSE_TOO_BIG, |socket| {
+            socket.max_message_size(10);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn answers_the_close_handshake() {
+        let (mut socket, mut client) = connect();
+        let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &payload)).await.unwrap();
+        assert_eq!(
+            socket.recv().await.unwrap(),
+            Some(Message::Close(Some((CLOSE_GOING_AWAY, "bye".to_string()))))
+        );
+        let (_, opcode, payload) = server_frame(&mut client).await;
+        assert_eq!(opcode, OPCODE_CLOSE);
+        assert_eq!(payload, CLOSE_GOING_AWAY.to_be_bytes());
+        assert_eq!(socket.recv().await.unwrap(), None);
+        assert!(socket.send_text("late").await.is_err());
+    }
+}